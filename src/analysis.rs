@@ -0,0 +1,1081 @@
+//! Register and control flow analysis.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+
+use super::bytecode::{Opcode, RK, extra_arg_value, get_opcode, get_a, get_b, get_c, get_bx, get_sbx};
+use super::builder::ENV;
+use super::{Constant, Function, Instruction, Upvalue};
+
+/// The registers read and written by a single instruction.
+///
+/// Writes which happen on only some paths, such as `TestSet`'s assignment,
+/// are included. Variable-length register ranges which extend to the stack
+/// top are represented by their first register.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RegEffects {
+	/// Registers whose values the instruction reads.
+	pub reads: Vec<u8>,
+	/// Registers the instruction assigns to.
+	pub writes: Vec<u8>,
+}
+
+impl RegEffects {
+	fn read(&mut self, reg: u32) {
+		if reg <= 0xff && !self.reads.contains(&(reg as u8)) {
+			self.reads.push(reg as u8);
+		}
+	}
+
+	fn read_rk(&mut self, value: u32) {
+		if let RK::R(reg) = RK::decode(value) {
+			self.read(reg as u32);
+		}
+	}
+
+	fn read_range(&mut self, first: u32, last: u32) {
+		for reg in first..last + 1 {
+			self.read(reg);
+		}
+	}
+
+	fn write(&mut self, reg: u32) {
+		if reg <= 0xff && !self.writes.contains(&(reg as u8)) {
+			self.writes.push(reg as u8);
+		}
+	}
+
+	fn write_range(&mut self, first: u32, last: u32) {
+		for reg in first..last + 1 {
+			self.write(reg);
+		}
+	}
+}
+
+/// Find the first register of the variable number of values left on the
+/// stack by the "open" instruction preceding `pc`, if there is one.
+pub(crate) fn open_top(code: &[Instruction], pc: usize) -> Option<u32> {
+	let prev = *code.get(pc.checked_sub(1)?)?;
+	match get_opcode(prev) {
+		Some(Opcode::Call) if get_c(prev) == 0 => Some(get_a(prev) as u32),
+		Some(Opcode::VarArg) if get_b(prev) == 0 => Some(get_a(prev) as u32),
+		_ => None,
+	}
+}
+
+/// Determine which registers the instruction at `pc` reads and writes.
+///
+/// `Closure` is considered to read the registers captured by the new
+/// closure's `Upvalue::Stack` entries, so the containing function is needed.
+pub fn reg_effects(function: &Function, pc: usize) -> RegEffects {
+	let mut fx = RegEffects::default();
+	let instr = function.code[pc];
+	let op = match get_opcode(instr) {
+		Some(op) => op,
+		None => return fx,
+	};
+	let a = get_a(instr) as u32;
+	let b = get_b(instr);
+	let c = get_c(instr);
+	match op {
+		Opcode::Move | Opcode::UnMinus | Opcode::BinNot | Opcode::Not | Opcode::Len => {
+			fx.read(b);
+			fx.write(a);
+		}
+		Opcode::LoadK | Opcode::LoadKX | Opcode::LoadBool | Opcode::NewTable | Opcode::GetUpval => {
+			fx.write(a);
+		}
+		Opcode::LoadNil => fx.write_range(a, a + b),
+		Opcode::GetTabUp => {
+			fx.read_rk(c);
+			fx.write(a);
+		}
+		Opcode::GetTable => {
+			fx.read(b);
+			fx.read_rk(c);
+			fx.write(a);
+		}
+		Opcode::SetTabUp | Opcode::Eq | Opcode::Less | Opcode::LessEq => {
+			fx.read_rk(b);
+			fx.read_rk(c);
+		}
+		Opcode::SetUpval | Opcode::Test => fx.read(a),
+		Opcode::SetTable => {
+			fx.read(a);
+			fx.read_rk(b);
+			fx.read_rk(c);
+		}
+		Opcode::Self_ => {
+			fx.read(b);
+			fx.read_rk(c);
+			fx.write_range(a, a + 1);
+		}
+		Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Mod | Opcode::Pow | Opcode::Div |
+		Opcode::IntDiv | Opcode::BinAnd | Opcode::BinOr | Opcode::BinXor | Opcode::ShLeft |
+		Opcode::ShRight => {
+			fx.read_rk(b);
+			fx.read_rk(c);
+			fx.write(a);
+		}
+		Opcode::Concat => {
+			fx.read_range(b, c);
+			fx.write(a);
+		}
+		Opcode::Jump | Opcode::ExtraArg => {}
+		Opcode::TestSet => {
+			fx.read(b);
+			fx.write(a);
+		}
+		Opcode::Call | Opcode::TailCall => {
+			if b == 0 {
+				fx.read_range(a, open_top(&function.code, pc).unwrap_or(a));
+			} else {
+				fx.read_range(a, a + b - 1);
+			}
+			if op == Opcode::Call {
+				if c == 0 {
+					fx.write(a);
+				} else if c > 1 {
+					fx.write_range(a, a + c - 2);
+				}
+			}
+		}
+		Opcode::Return => {
+			if b == 0 {
+				fx.read_range(a, open_top(&function.code, pc).unwrap_or(a));
+			} else if b > 1 {
+				fx.read_range(a, a + b - 2);
+			}
+		}
+		Opcode::ForLoop => {
+			fx.read_range(a, a + 2);
+			fx.write(a);
+			fx.write(a + 3);
+		}
+		Opcode::ForPrep => {
+			fx.read_range(a, a + 2);
+			fx.write(a);
+		}
+		Opcode::TForCall => {
+			fx.read_range(a, a + 2);
+			if c > 0 {
+				fx.write_range(a + 3, a + 2 + c);
+			}
+		}
+		Opcode::TForLoop => {
+			fx.read(a + 1);
+			fx.write(a);
+		}
+		Opcode::SetList => {
+			fx.read(a);
+			if b == 0 {
+				if let Some(top) = open_top(&function.code, pc) {
+					fx.read_range(a + 1, top);
+				}
+			} else {
+				fx.read_range(a + 1, a + b);
+			}
+		}
+		Opcode::Closure => {
+			if let Some(proto) = function.closure_target(instr) {
+				for upval in &proto.upvalues {
+					if let Upvalue::Stack(reg) = *upval {
+						fx.read(reg as u32);
+					}
+				}
+			}
+			fx.write(a);
+		}
+		Opcode::VarArg => {
+			if b == 0 {
+				fx.write(a);
+			} else if b > 1 {
+				fx.write_range(a, a + b - 2);
+			}
+		}
+	}
+	fx
+}
+
+fn jump_dest(pc: usize, instr: Instruction) -> usize {
+	(pc as i64 + 1 + get_sbx(instr) as i64) as usize
+}
+
+/// Determine the pcs to which control may pass after the instruction at `pc`.
+///
+/// Targets are not checked against the length of `code`. The `ExtraArg`
+/// following `LoadKX` or a `SetList` with `C == 0` is skipped over.
+pub fn successors(code: &[Instruction], pc: usize) -> Vec<usize> {
+	let instr = code[pc];
+	let jump = || jump_dest(pc, instr);
+	match get_opcode(instr) {
+		Some(Opcode::Jump) | Some(Opcode::ForPrep) => vec![jump()],
+		Some(Opcode::ForLoop) | Some(Opcode::TForLoop) => vec![pc + 1, jump()],
+		Some(Opcode::Eq) | Some(Opcode::Less) | Some(Opcode::LessEq) |
+		Some(Opcode::Test) | Some(Opcode::TestSet) => vec![pc + 1, pc + 2],
+		Some(Opcode::LoadBool) if get_c(instr) != 0 => vec![pc + 2],
+		Some(Opcode::LoadKX) => vec![pc + 2],
+		Some(Opcode::SetList) if get_c(instr) == 0 => vec![pc + 2],
+		Some(Opcode::Return) => vec![],
+		_ => vec![pc + 1],
+	}
+}
+
+/// Find where the instruction at `pc` may transfer control other than to
+/// the next instruction.
+///
+/// This is the destination of a jump or loop instruction, or `pc + 2` for
+/// comparisons and tests, which conditionally skip the next instruction,
+/// and for a `LoadBool` with `C != 0`, which always does.
+pub fn jump_target(code: &[Instruction], pc: usize) -> Option<usize> {
+	let instr = code[pc];
+	match get_opcode(instr) {
+		Some(Opcode::Jump) | Some(Opcode::ForPrep) |
+		Some(Opcode::ForLoop) | Some(Opcode::TForLoop) => Some(jump_dest(pc, instr)),
+		Some(Opcode::Eq) | Some(Opcode::Less) | Some(Opcode::LessEq) |
+		Some(Opcode::Test) | Some(Opcode::TestSet) => Some(pc + 2),
+		Some(Opcode::LoadBool) if get_c(instr) != 0 => Some(pc + 2),
+		_ => None,
+	}
+}
+
+/// Split code into basic blocks, returned as ranges of pcs in order.
+///
+/// A block begins at the start of the code and at every successor of an
+/// instruction which does not simply fall through to the next, and ends
+/// after such an instruction. The `pc + 1` following a `LoadBool` which
+/// skips it therefore begins a block of its own, as in the code for
+/// `local x = (a < b)`. The `ExtraArg` after a `LoadKX` or `SetList` stays
+/// in the block of the instruction it belongs to.
+pub fn basic_blocks(code: &[Instruction]) -> Vec<Range<usize>> {
+	let mut leaders = BTreeSet::new();
+	leaders.insert(0);
+	let mut pc = 0;
+	while pc < code.len() {
+		let next = if extra_arg_value(code, pc).is_some() { pc + 2 } else { pc + 1 };
+		let succs = successors(code, pc);
+		if succs != [next] {
+			leaders.extend(succs);
+			leaders.insert(next);
+		}
+		pc = next;
+	}
+	let leaders: Vec<usize> = leaders.into_iter().filter(|&pc| pc < code.len()).collect();
+	leaders.iter().enumerate()
+		.map(|(i, &start)| start..leaders.get(i + 1).cloned().unwrap_or(code.len()))
+		.collect()
+}
+
+/// A definition of a register's value.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Def {
+	/// The pc of the defining instruction, or `None` for the value held on
+	/// entry to the function, such as a parameter.
+	pub pc: Option<usize>,
+	/// The register being defined.
+	pub reg: u8,
+}
+
+/// Def-use chains for the registers of a function.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DefUseGraph {
+	/// For each definition, the pcs of the instructions which may read it.
+	pub uses: BTreeMap<Def, Vec<usize>>,
+	/// For each register read, keyed by pc and register, the definitions
+	/// which may reach it.
+	pub defs: BTreeMap<(usize, u8), Vec<Def>>,
+}
+
+impl DefUseGraph {
+	/// The pcs which may read the value of `reg` defined at `pc`.
+	pub fn uses_of(&self, pc: Option<usize>, reg: u8) -> &[usize] {
+		self.uses.get(&Def { pc, reg }).map_or(&[], |v| &v[..])
+	}
+
+	/// The definitions which may reach the read of `reg` at `pc`.
+	pub fn defs_of(&self, pc: usize, reg: u8) -> &[Def] {
+		self.defs.get(&(pc, reg)).map_or(&[], |v| &v[..])
+	}
+}
+
+/// The definitions of each register which reach a point in the code.
+type Reaching = Vec<BTreeSet<Option<usize>>>;
+
+/// Whether the instruction at `pc` writes `reg` when control passes to `succ`.
+fn writes_on_edge(code: &[Instruction], pc: usize, reg: u8, succ: usize) -> bool {
+	let instr = code[pc];
+	match get_opcode(instr) {
+		Some(Opcode::TestSet) => succ == pc + 1,
+		Some(Opcode::ForLoop) => reg == get_a(instr) || succ != pc + 1,
+		Some(Opcode::TForLoop) => succ != pc + 1,
+		_ => true,
+	}
+}
+
+/// Compute def-use chains for the registers of a function.
+///
+/// This is a reaching-definitions analysis over the function's control flow
+/// graph, so values merging from several predecessors are all reported.
+/// Instructions which cannot be reached from the entry point are ignored.
+pub fn def_use(function: &Function) -> DefUseGraph {
+	let code = &function.code;
+	let effects: Vec<RegEffects> = (0..code.len()).map(|pc| reg_effects(function, pc)).collect();
+	let num_regs = effects.iter()
+		.flat_map(|fx| fx.reads.iter().chain(fx.writes.iter()))
+		.map(|&r| r as usize + 1)
+		.chain(Some(function.max_stack_size as usize))
+		.max()
+		.unwrap_or(0);
+
+	let mut states: Vec<Option<Reaching>> = vec![None; code.len()];
+	let mut worklist = vec![];
+	if !code.is_empty() {
+		let mut entry = BTreeSet::new();
+		entry.insert(None);
+		states[0] = Some(vec![entry; num_regs]);
+		worklist.push(0);
+	}
+	while let Some(pc) = worklist.pop() {
+		for succ in successors(code, pc) {
+			if succ >= code.len() {
+				continue;
+			}
+			let mut state = states[pc].clone().unwrap();
+			for &reg in &effects[pc].writes {
+				if writes_on_edge(code, pc, reg, succ) {
+					let defs = &mut state[reg as usize];
+					defs.clear();
+					defs.insert(Some(pc));
+				}
+			}
+			let changed = match states[succ] {
+				Some(ref mut existing) => {
+					let mut changed = false;
+					for (into, from) in existing.iter_mut().zip(state.iter()) {
+						for &def in from {
+							changed |= into.insert(def);
+						}
+					}
+					changed
+				}
+				ref mut empty => {
+					*empty = Some(state);
+					true
+				}
+			};
+			if changed && !worklist.contains(&succ) {
+				worklist.push(succ);
+			}
+		}
+	}
+
+	let mut graph = DefUseGraph::default();
+	for (pc, state) in states.iter().enumerate() {
+		let state = match *state {
+			Some(ref state) => state,
+			None => continue,
+		};
+		for &reg in &effects[pc].reads {
+			let defs: Vec<Def> = state[reg as usize].iter()
+				.map(|&def_pc| Def { pc: def_pc, reg })
+				.collect();
+			for &def in &defs {
+				graph.uses.entry(def).or_insert_with(Vec::new).push(pc);
+			}
+			graph.defs.insert((pc, reg), defs);
+		}
+	}
+	graph
+}
+
+/// The flavor of a `for` loop.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ForLoopKind {
+	/// A numeric loop, `for i = start, limit, step do`.
+	Numeric,
+	/// A generic loop, `for k, v in explist do`.
+	Generic {
+		/// The pc of the `TForCall` which calls the iterator.
+		call_pc: usize,
+		/// The number of loop variables.
+		num_vars: u8,
+	},
+}
+
+/// A `for` loop recognized in a function's code.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ForLoopInfo {
+	/// The flavor of the loop.
+	pub kind: ForLoopKind,
+	/// The pc of the `ForPrep`, or of the `Jump` entering a generic loop.
+	pub prep_pc: usize,
+	/// The pc of the `ForLoop` or `TForLoop` which jumps back to the body.
+	pub loop_pc: usize,
+	/// The pcs of the loop body.
+	pub body: Range<usize>,
+	/// The control registers. For a numeric loop these are the internal
+	/// index, limit, and step, and the visible loop variable. For a generic
+	/// loop they are the iterator function, state, and control variable,
+	/// and the first visible loop variable.
+	pub registers: [u8; 4],
+}
+
+/// Find the numeric and generic `for` loops in a function, ordered by pc.
+///
+/// A numeric loop is a `ForPrep` jumping to a `ForLoop` which jumps back to
+/// the instruction after the `ForPrep`. A generic loop is a `Jump` to a
+/// `TForCall` followed by a `TForLoop` which jumps back to the instruction
+/// after the `Jump`.
+pub fn for_loops(function: &Function) -> Vec<ForLoopInfo> {
+	let code = &function.code;
+	let mut loops = vec![];
+	for (pc, &instr) in code.iter().enumerate() {
+		match get_opcode(instr) {
+			Some(Opcode::ForPrep) => {
+				let a = get_a(instr);
+				let loop_pc = jump_dest(pc, instr);
+				let tail = match code.get(loop_pc) {
+					Some(&tail) => tail,
+					None => continue,
+				};
+				if get_opcode(tail) != Some(Opcode::ForLoop) || get_a(tail) != a ||
+					jump_dest(loop_pc, tail) != pc + 1
+				{
+					continue;
+				}
+				loops.push(ForLoopInfo {
+					kind: ForLoopKind::Numeric,
+					prep_pc: pc,
+					loop_pc,
+					body: pc + 1..loop_pc,
+					registers: [a, a.wrapping_add(1), a.wrapping_add(2), a.wrapping_add(3)],
+				});
+			}
+			Some(Opcode::TForCall) => {
+				let a = get_a(instr);
+				let loop_pc = pc + 1;
+				let tail = match code.get(loop_pc) {
+					Some(&tail) => tail,
+					None => continue,
+				};
+				if get_opcode(tail) != Some(Opcode::TForLoop) || get_a(tail) != a.wrapping_add(2) {
+					continue;
+				}
+				let body_start = jump_dest(loop_pc, tail);
+				let prep_pc = match body_start.checked_sub(1) {
+					Some(prep_pc) if prep_pc < pc => prep_pc,
+					_ => continue,
+				};
+				let prep = code[prep_pc];
+				if get_opcode(prep) != Some(Opcode::Jump) || jump_dest(prep_pc, prep) != pc {
+					continue;
+				}
+				loops.push(ForLoopInfo {
+					kind: ForLoopKind::Generic { call_pc: pc, num_vars: get_c(instr) as u8 },
+					prep_pc,
+					loop_pc,
+					body: body_start..pc,
+					registers: [a, a.wrapping_add(1), a.wrapping_add(2), a.wrapping_add(3)],
+				});
+			}
+			_ => {}
+		}
+	}
+	loops.sort_by_key(|l| l.prep_pc);
+	loops
+}
+
+/// Find the pcs of `Call`s which could be `TailCall`s.
+///
+/// This is a `Call` keeping all its results which is immediately followed
+/// by a `Return` of all of them, the shape Lua itself compiles
+/// `return f(x)` to, with the `Call` replaced.
+pub fn tailcall_candidates(function: &Function) -> Vec<usize> {
+	let code = &function.code;
+	(0..code.len().saturating_sub(1))
+		.filter(|&pc| {
+			let (call, ret) = (code[pc], code[pc + 1]);
+			get_opcode(call) == Some(Opcode::Call) && get_c(call) == 0 &&
+				get_opcode(ret) == Some(Opcode::Return) && get_b(ret) == 0 &&
+				get_a(ret) == get_a(call)
+		})
+		.collect()
+}
+
+/// A `Closure` instruction instantiating a nested function.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ClosureEdge {
+	/// The path of the function containing the `Closure`.
+	pub parent: Vec<u32>,
+	/// The pc of the `Closure` within the parent.
+	pub pc: usize,
+	/// The path of the instantiated function.
+	pub child: Vec<u32>,
+}
+
+/// Which functions instantiate which of their nested functions, and where.
+///
+/// Functions are identified by their path of proto indices from the main
+/// function, which is the empty path.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ClosureGraph {
+	/// The path of every function, in depth-first order.
+	pub nodes: Vec<Vec<u32>>,
+	/// Every `Closure` with a valid `Bx`, ordered by parent and then pc.
+	pub edges: Vec<ClosureEdge>,
+}
+
+impl ClosureGraph {
+	/// The `Closure` instructions which instantiate the function at `child`.
+	pub fn instantiations<'a>(&'a self, child: &'a [u32]) -> impl Iterator<Item = &'a ClosureEdge> + 'a {
+		self.edges.iter().filter(move |edge| edge.child == child)
+	}
+}
+
+/// Build the graph of `Closure` instructions in a function and its nested
+/// functions.
+///
+/// Since each function can only instantiate its own protos, the edges
+/// follow the nesting structure, but a proto may be instantiated at several
+/// pcs or at none.
+pub fn closure_graph(function: &Function) -> ClosureGraph {
+	let mut graph = ClosureGraph::default();
+	add_closures(&mut graph, function, &mut vec![]);
+	graph
+}
+
+fn add_closures(graph: &mut ClosureGraph, function: &Function, path: &mut Vec<u32>) {
+	graph.nodes.push(path.clone());
+	for (pc, &instr) in function.code.iter().enumerate() {
+		if function.closure_target(instr).is_some() {
+			let mut child = path.clone();
+			child.push(get_bx(instr));
+			graph.edges.push(ClosureEdge { parent: path.clone(), pc, child });
+		}
+	}
+	for (i, proto) in function.sub_functions() {
+		path.push(i);
+		add_closures(graph, proto, path);
+		path.pop();
+	}
+}
+
+/// Find the names of the globals read and written by a function and its
+/// nested functions, each sorted and without duplicates.
+///
+/// A global access is a `GetTabUp` or `SetTabUp` on the `_ENV` upvalue
+/// with a string constant as its key. Upvalues are recognized as `_ENV` by
+/// their debug names, or where those were stripped, by being the main
+/// function's first upvalue or captured from an enclosing function's
+/// `_ENV`. Accesses with computed keys, such as `_ENV[name]`, are not found.
+pub fn global_accesses(function: &Function) -> (Vec<String>, Vec<String>) {
+	let mut reads = BTreeSet::new();
+	let mut writes = BTreeSet::new();
+	let env: Vec<bool> = (0..function.upvalues.len()).map(|i| i == 0).collect();
+	add_global_accesses(function, &env, &mut reads, &mut writes);
+	(reads.into_iter().collect(), writes.into_iter().collect())
+}
+
+fn add_global_accesses(function: &Function, env: &[bool], reads: &mut BTreeSet<String>, writes: &mut BTreeSet<String>) {
+	// Without debug names, `env` says which upvalues are inherited `_ENV`s.
+	let names = &function.debug.upvalues;
+	let env: Vec<bool> = if names.len() == function.upvalues.len() {
+		names.iter().map(|name| name == ENV).collect()
+	} else {
+		env.to_vec()
+	};
+	let is_env = |upval: u32| env.get(upval as usize).cloned().unwrap_or(false);
+	let name = |key: u32| match RK::decode(key) {
+		rk @ RK::K(_) => match function.rk_value(rk) {
+			Some(&Constant::ShortString(ref s)) | Some(&Constant::LongString(ref s)) => Some(s.clone()),
+			_ => None,
+		},
+		RK::R(_) => None,
+	};
+	for &instr in &function.code {
+		match get_opcode(instr) {
+			Some(Opcode::GetTabUp) if is_env(get_b(instr)) => reads.extend(name(get_c(instr))),
+			Some(Opcode::SetTabUp) if is_env(get_a(instr) as u32) => writes.extend(name(get_b(instr))),
+			_ => {}
+		}
+	}
+	for proto in &function.protos {
+		let inherited: Vec<bool> = proto.upvalues.iter()
+			.map(|upval| match *upval {
+				Upvalue::Outer(idx) => is_env(idx as u32),
+				Upvalue::Stack(_) => false,
+			})
+			.collect();
+		add_global_accesses(proto, &inherited, reads, writes);
+	}
+}
+
+/// A conditional branch: a comparison or test and the `Jump` after it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ComparisonInfo {
+	/// The comparison or test opcode.
+	pub op: Opcode,
+	/// The left operand, or the register tested by `Test` and `TestSet`.
+	pub lhs: RK,
+	/// The right operand, or `None` for `Test` and `TestSet`, which test
+	/// the truthiness of a single value.
+	pub rhs: Option<RK>,
+	/// Whether the `Jump` is taken when the condition is false rather than
+	/// true. This is `A == 0` for comparisons and `C == 0` for tests.
+	pub invert: bool,
+	/// Where control passes when the condition is true.
+	pub then_target: usize,
+	/// Where control passes when the condition is false.
+	pub else_target: usize,
+}
+
+/// Decode the comparison or test at `pc` together with the `Jump` which
+/// must follow it.
+///
+/// The condition is `lhs op rhs` for `Eq`, `Less`, and `LessEq`, and the
+/// truthiness of `lhs` for `Test` and `TestSet`. The instruction skips the
+/// `Jump` unless the condition matches its flag, so one target is that of
+/// the `Jump` and the other is `pc + 2`. Returns `None` if the instruction
+/// is not a comparison or test or is not followed by a `Jump`.
+pub fn decode_comparison(code: &[Instruction], pc: usize) -> Option<ComparisonInfo> {
+	let instr = *code.get(pc)?;
+	let jump = *code.get(pc + 1)?;
+	if get_opcode(jump) != Some(Opcode::Jump) {
+		return None;
+	}
+	let op = get_opcode(instr)?;
+	let (lhs, rhs, flag) = match op {
+		Opcode::Eq | Opcode::Less | Opcode::LessEq =>
+			(RK::decode(get_b(instr)), Some(RK::decode(get_c(instr))), get_a(instr) != 0),
+		Opcode::Test => (RK::R(get_a(instr)), None, get_c(instr) != 0),
+		Opcode::TestSet => (RK::R(get_b(instr) as u8), None, get_c(instr) != 0),
+		_ => return None,
+	};
+	let (taken, skipped) = (jump_dest(pc + 1, jump), pc + 2);
+	Some(ComparisonInfo {
+		op,
+		lhs,
+		rhs,
+		invert: !flag,
+		then_target: if flag { taken } else { skipped },
+		else_target: if flag { skipped } else { taken },
+	})
+}
+
+/// A run of consecutive registers holding values passed to or from an
+/// instruction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ValueRange {
+	/// The first register.
+	pub first: u8,
+	/// The number of values whose count is known statically.
+	pub fixed: u32,
+	/// Whether the fixed values are followed by every value up to the stack
+	/// top, whose number is only known when the code runs.
+	pub open: bool,
+}
+
+impl ValueRange {
+	/// Decode a count encoded plus one, where zero means "up to the top".
+	fn counted(first: u32, count: u32, top: Option<u32>) -> ValueRange {
+		match count {
+			0 => {
+				let top = top.unwrap_or(first).max(first);
+				ValueRange { first: first as u8, fixed: top - first, open: true }
+			}
+			n => ValueRange { first: first as u8, fixed: n - 1, open: false },
+		}
+	}
+}
+
+/// The values an instruction takes from and leaves in a run of registers,
+/// as found by `stack_effect`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StackEffect {
+	/// The values taken: a call's arguments, the values returned, or the
+	/// values stored by `SetList`.
+	pub inputs: Option<ValueRange>,
+	/// The values left: a call's results or the values of `VarArg`.
+	pub outputs: Option<ValueRange>,
+}
+
+/// Determine the values passed by a `Call`, `TailCall`, `Return`, `SetList`,
+/// or `VarArg` at `pc`, resolving counts of zero.
+///
+/// A zero input count takes every value up to the stack top, which is set
+/// by the open `Call` or `VarArg` just before. Its values are counted in
+/// `fixed` up to the open instruction's first result, and `open` is set
+/// for the rest. A zero output count leaves every result and sets the
+/// stack top. Other instructions have no effect on the stack top and
+/// return the default.
+pub fn stack_effect(code: &[Instruction], pc: usize) -> StackEffect {
+	let instr = code[pc];
+	let (a, b, c) = (get_a(instr) as u32, get_b(instr), get_c(instr));
+	let top = open_top(code, pc);
+	match get_opcode(instr) {
+		Some(Opcode::Call) => StackEffect {
+			inputs: Some(ValueRange::counted(a + 1, b, top)),
+			outputs: Some(ValueRange::counted(a, c, None)),
+		},
+		Some(Opcode::TailCall) => StackEffect {
+			inputs: Some(ValueRange::counted(a + 1, b, top)),
+			outputs: None,
+		},
+		Some(Opcode::Return) => StackEffect {
+			inputs: Some(ValueRange::counted(a, b, top)),
+			outputs: None,
+		},
+		Some(Opcode::SetList) => StackEffect {
+			// Unlike the others, SetList's count is not encoded plus one.
+			inputs: Some(ValueRange::counted(a + 1, if b == 0 { 0 } else { b + 1 }, top)),
+			outputs: None,
+		},
+		Some(Opcode::VarArg) => StackEffect {
+			inputs: None,
+			outputs: Some(ValueRange::counted(a, b, None)),
+		},
+		_ => StackEffect::default(),
+	}
+}
+
+/// What is known about the value of a register during abstract
+/// interpretation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum AbstractValue {
+	/// Nothing is known; the value may be of any type, including nil.
+	Unknown,
+	/// The value is nil.
+	Nil,
+	/// The value is a boolean.
+	Boolean,
+	/// The value is a number, either integer or float.
+	Number,
+	/// The value is a string.
+	String,
+	/// The value is a table.
+	Table,
+	/// The value is a function.
+	Function,
+}
+
+impl AbstractValue {
+	/// Combine what is known on two paths which meet.
+	pub fn join(self, other: AbstractValue) -> AbstractValue {
+		if self == other { self } else { AbstractValue::Unknown }
+	}
+
+	/// The type of a constant.
+	pub fn of_constant(constant: &Constant) -> AbstractValue {
+		match *constant {
+			Constant::Nil => AbstractValue::Nil,
+			Constant::Boolean(_) => AbstractValue::Boolean,
+			Constant::Float(_) | Constant::Int(_) => AbstractValue::Number,
+			Constant::ShortString(_) | Constant::LongString(_) => AbstractValue::String,
+			Constant::Raw { .. } => AbstractValue::Unknown,
+		}
+	}
+}
+
+/// The abstract values of a function's registers at one point in its code.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AbstractState {
+	/// The value of each register. Registers past the end are `Unknown`.
+	pub registers: Vec<AbstractValue>,
+}
+
+impl AbstractState {
+	/// A state in which nothing is known about `size` registers.
+	pub fn unknown(size: usize) -> AbstractState {
+		AbstractState { registers: vec![AbstractValue::Unknown; size] }
+	}
+
+	/// The value of a register.
+	pub fn get(&self, reg: u8) -> AbstractValue {
+		self.registers.get(reg as usize).cloned().unwrap_or(AbstractValue::Unknown)
+	}
+
+	/// Set the value of a register, growing the state if needed.
+	pub fn set(&mut self, reg: u8, value: AbstractValue) {
+		let reg = reg as usize;
+		if reg >= self.registers.len() {
+			self.registers.resize(reg + 1, AbstractValue::Unknown);
+		}
+		self.registers[reg] = value;
+	}
+
+	/// Combine this state with that of another path meeting it, returning
+	/// whether anything changed.
+	pub fn join(&mut self, other: &AbstractState) -> bool {
+		let len = self.registers.len().max(other.registers.len());
+		let mut changed = false;
+		for reg in 0..len {
+			let joined = self.get(reg as u8).join(other.get(reg as u8));
+			if joined != self.get(reg as u8) {
+				self.set(reg as u8, joined);
+				changed = true;
+			}
+		}
+		changed
+	}
+}
+
+/// Update `state` for the effect of the instruction at `pc` on the values
+/// of registers.
+///
+/// Values are assumed to have no metamethods, so arithmetic yields a
+/// number, `Concat` a string, and `Len` a number. Table reads, upvalues,
+/// and call results are `Unknown`. `TestSet`, which assigns only on one
+/// path, joins its source with the old value. Jumps are not followed; the
+/// caller propagates the state to each of `successors`.
+pub fn transfer(function: &Function, pc: usize, state: &mut AbstractState) {
+	use self::AbstractValue::*;
+
+	let instr = function.code[pc];
+	let (a, b, c) = (get_a(instr), get_b(instr), get_c(instr));
+	// Read the sources before anything written is forgotten, since they
+	// may be the same registers.
+	let (old_a, old_b) = (state.get(a), state.get(b as u8));
+	// Anything written but not handled below becomes unknown.
+	for &reg in &reg_effects(function, pc).writes {
+		state.set(reg, Unknown);
+	}
+	let constant = |index: Option<u32>| index
+		.and_then(|k| function.constants.get(k as usize))
+		.map_or(Unknown, AbstractValue::of_constant);
+	let op = match get_opcode(instr) {
+		Some(op) => op,
+		None => return,
+	};
+	match op {
+		Opcode::Move => state.set(a, old_b),
+		Opcode::LoadK => state.set(a, constant(Some(get_bx(instr)))),
+		Opcode::LoadKX => state.set(a, constant(extra_arg_value(&function.code, pc))),
+		Opcode::LoadBool | Opcode::Not => state.set(a, Boolean),
+		Opcode::LoadNil => {
+			for reg in a as u32..a as u32 + b + 1 {
+				if reg <= 0xff {
+					state.set(reg as u8, Nil);
+				}
+			}
+		}
+		Opcode::NewTable => state.set(a, Table),
+		Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Mod | Opcode::Pow | Opcode::Div |
+		Opcode::IntDiv | Opcode::BinAnd | Opcode::BinOr | Opcode::BinXor | Opcode::ShLeft |
+		Opcode::ShRight | Opcode::UnMinus | Opcode::BinNot | Opcode::Len => state.set(a, Number),
+		Opcode::Concat => state.set(a, String),
+		Opcode::Self_ => state.set(a.wrapping_add(1), old_b),
+		Opcode::TestSet => state.set(a, old_a.join(old_b)),
+		Opcode::ForPrep | Opcode::ForLoop => {
+			state.set(a, Number);
+			if op == Opcode::ForLoop {
+				state.set(a.wrapping_add(3), Number);
+			}
+		}
+		Opcode::Closure => state.set(a, Function),
+		// Open results leave every register from A up unknown.
+		Opcode::Call | Opcode::VarArg => {
+			let open = if op == Opcode::Call { c == 0 } else { b == 0 };
+			if open {
+				for value in state.registers.iter_mut().skip(a as usize) {
+					*value = Unknown;
+				}
+			}
+		}
+		_ => {}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use builder::FunctionBuilder;
+	use bytecode::{Opcode, RK, encode, encode_ax, encode_bx, encode_sbx};
+	use super::super::{Constant, Function};
+	use super::{AbstractState, AbstractValue, Def, basic_blocks, decode_comparison, def_use, jump_target, transfer};
+	use super::AbstractValue::*;
+
+	fn function(code: &[u32]) -> Function {
+		let mut builder = FunctionBuilder::new();
+		builder.function_mut().max_stack_size = 8;
+		builder.constant(Constant::Int(1));
+		builder.constant(Constant::ShortString("m".into()));
+		for &instr in code {
+			builder.emit(instr);
+		}
+		builder.build()
+	}
+
+	/// The state after the first instruction of `code`, from registers
+	/// holding `before`.
+	fn after(code: &[u32], before: &[AbstractValue]) -> Vec<AbstractValue> {
+		let function = function(code);
+		let mut state = AbstractState { registers: before.to_vec() };
+		transfer(&function, 0, &mut state);
+		state.registers
+	}
+
+	#[test]
+	fn transfer_loads() {
+		assert_eq!(after(&[encode(Opcode::Move, 1, 0, 0)], &[Table, Nil]), [Table, Table]);
+		assert_eq!(after(&[encode_bx(Opcode::LoadK, 0, 0)], &[Nil]), [Number]);
+		assert_eq!(after(&[encode_bx(Opcode::LoadK, 0, 1)], &[Nil]), [String]);
+		let loadkx = [encode_bx(Opcode::LoadKX, 0, 0), encode_ax(Opcode::ExtraArg, 1)];
+		assert_eq!(after(&loadkx, &[Nil]), [String]);
+		assert_eq!(after(&[encode(Opcode::LoadBool, 0, 1, 0)], &[Nil]), [Boolean]);
+		assert_eq!(after(&[encode(Opcode::Not, 0, 1, 0)], &[Nil, Table]), [Boolean, Table]);
+		assert_eq!(after(&[encode(Opcode::LoadNil, 1, 1, 0)], &[Table; 4]), [Table, Nil, Nil, Table]);
+		assert_eq!(after(&[encode(Opcode::NewTable, 0, 0, 0)], &[Nil]), [Table]);
+		assert_eq!(after(&[encode_bx(Opcode::Closure, 0, 0)], &[Nil]), [Function]);
+		assert_eq!(after(&[encode(Opcode::GetUpval, 0, 0, 0)], &[Table]), [Unknown]);
+	}
+
+	#[test]
+	fn transfer_operators() {
+		let add = encode(Opcode::Add, 0, 1, RK::K(0).encode());
+		assert_eq!(after(&[add], &[Nil, Unknown]), [Number, Unknown]);
+		assert_eq!(after(&[encode(Opcode::Len, 0, 0, 0)], &[Table]), [Number]);
+		assert_eq!(after(&[encode(Opcode::Concat, 0, 1, 2)], &[Nil, String, Number]), [String, String, Number]);
+		let get = encode(Opcode::GetTable, 0, 0, RK::K(1).encode());
+		assert_eq!(after(&[get], &[Table]), [Unknown]);
+	}
+
+	#[test]
+	fn transfer_self() {
+		let method = RK::K(1).encode();
+		assert_eq!(after(&[encode(Opcode::Self_, 1, 0, method)], &[Table, Nil, Nil]), [Table, Unknown, Table]);
+		// f():m(), where the object and method replace the call's result.
+		assert_eq!(after(&[encode(Opcode::Self_, 0, 0, method)], &[Table, Nil]), [Unknown, Table]);
+	}
+
+	#[test]
+	fn transfer_test_set() {
+		// The result is the source on one path and the old value on the other.
+		let test_set = encode(Opcode::TestSet, 0, 1, 0);
+		assert_eq!(after(&[test_set], &[Number, Number]), [Number, Number]);
+		assert_eq!(after(&[test_set], &[Nil, Number]), [Unknown, Number]);
+	}
+
+	#[test]
+	fn transfer_loops_and_calls() {
+		let prep = encode_sbx(Opcode::ForPrep, 0, 0);
+		assert_eq!(after(&[prep], &[Unknown, Number, Number, Nil]), [Number, Number, Number, Nil]);
+		let step = encode_sbx(Opcode::ForLoop, 0, -1);
+		assert_eq!(after(&[step], &[Number, Number, Number, Nil]), [Number, Number, Number, Number]);
+		// A call with fixed results leaves them unknown, and one with open
+		// results everything from its function up.
+		let call = encode(Opcode::Call, 0, 1, 2);
+		assert_eq!(after(&[call], &[Function, Table]), [Unknown, Table]);
+		let call = encode(Opcode::Call, 1, 1, 0);
+		assert_eq!(after(&[call], &[Table, Function, Table, Number]), [Table, Unknown, Unknown, Unknown]);
+	}
+
+	#[test]
+	fn comparison_to_boolean() {
+		// local a, b = ...; local x = (a < b)
+		let code = [
+			encode(Opcode::VarArg, 0, 3, 0),
+			encode(Opcode::Less, 1, 0, 1),
+			encode_sbx(Opcode::Jump, 0, 1),
+			encode(Opcode::LoadBool, 2, 0, 1),
+			encode(Opcode::LoadBool, 2, 1, 0),
+			encode(Opcode::Return, 0, 1, 0),
+		];
+		let info = decode_comparison(&code, 1).unwrap();
+		assert_eq!(info.op, Opcode::Less);
+		assert_eq!(info.lhs, RK::R(0));
+		assert_eq!(info.rhs, Some(RK::R(1)));
+		assert!(!info.invert);
+		// When a < b, the jump is taken to the LoadBool of true.
+		assert_eq!(info.then_target, 4);
+		assert_eq!(info.else_target, 3);
+
+		assert!(decode_comparison(&code, 0).is_none());
+		assert!(decode_comparison(&code, 3).is_none());
+
+		assert_eq!(jump_target(&code, 0), None);
+		assert_eq!(jump_target(&code, 1), Some(3));
+		assert_eq!(jump_target(&code, 2), Some(4));
+		assert_eq!(jump_target(&code, 3), Some(5));
+		assert_eq!(jump_target(&code, 4), None);
+		assert_eq!(basic_blocks(&code), vec![0..2, 2..3, 3..4, 4..5, 5..6]);
+	}
+
+	#[test]
+	fn blocks_keep_extra_arg() {
+		let code = [
+			encode_bx(Opcode::LoadKX, 0, 0),
+			encode_ax(Opcode::ExtraArg, 300),
+			encode(Opcode::Test, 0, 0, 0),
+			encode_sbx(Opcode::Jump, 0, 2),
+			encode(Opcode::SetList, 1, 2, 0),
+			encode_ax(Opcode::ExtraArg, 600),
+			encode(Opcode::Return, 0, 1, 0),
+		];
+		assert_eq!(basic_blocks(&code), vec![0..3, 3..4, 4..6, 6..7]);
+	}
+
+	fn defs(pcs: &[Option<usize>], reg: u8) -> Vec<Def> {
+		pcs.iter().map(|&pc| Def { pc, reg }).collect()
+	}
+
+	#[test]
+	fn def_use_loop() {
+		// local s = 1; for i = 1, 1, 1 do s = s + i end; return s
+		let mut function = function(&[
+			encode_bx(Opcode::LoadK, 4, 0),
+			encode_bx(Opcode::LoadK, 0, 0),
+			encode_bx(Opcode::LoadK, 1, 0),
+			encode_bx(Opcode::LoadK, 2, 0),
+			encode_sbx(Opcode::ForPrep, 0, 1),
+			encode(Opcode::Add, 4, 4, RK::R(3).encode()),
+			encode_sbx(Opcode::ForLoop, 0, -2),
+			encode(Opcode::Return, 4, 2, 0),
+		]);
+		function.max_stack_size = 5;
+		let graph = def_use(&function);
+		// The sum merges its first value with the one from the back edge.
+		assert_eq!(graph.defs_of(5, 4), &defs(&[Some(0), Some(5)], 4)[..]);
+		assert_eq!(graph.defs_of(7, 4), &defs(&[Some(0), Some(5)], 4)[..]);
+		assert_eq!(graph.uses_of(Some(5), 4), [5, 7]);
+		// ForLoop writes the loop variable only when it jumps back, and its
+		// counter on both edges.
+		assert_eq!(graph.defs_of(5, 3), &defs(&[Some(6)], 3)[..]);
+		assert_eq!(graph.defs_of(6, 0), &defs(&[Some(4), Some(6)], 0)[..]);
+	}
+
+	#[test]
+	fn def_use_test_set() {
+		// If p is false, copy it to R1 and jump to the return.
+		let mut function = function(&[
+			encode(Opcode::TestSet, 1, 0, 0),
+			encode_sbx(Opcode::Jump, 0, 1),
+			encode(Opcode::Move, 2, 1, 0),
+			encode(Opcode::Return, 1, 2, 0),
+		]);
+		function.num_params = 1;
+		let graph = def_use(&function);
+		// TestSet writes R1 only when it does not skip the jump.
+		assert_eq!(graph.defs_of(2, 1), &defs(&[None], 1)[..]);
+		assert_eq!(graph.defs_of(3, 1), &defs(&[None, Some(0)], 1)[..]);
+		assert_eq!(graph.uses_of(Some(0), 1), [3]);
+		assert_eq!(graph.uses_of(None, 1), [2, 3]);
+	}
+
+	#[test]
+	fn def_use_branches() {
+		// local x; if p then x = 1 else x = nil end; return x
+		let mut function = function(&[
+			encode(Opcode::Test, 0, 0, 0),
+			encode_sbx(Opcode::Jump, 0, 2),
+			encode_bx(Opcode::LoadK, 1, 0),
+			encode_sbx(Opcode::Jump, 0, 1),
+			encode(Opcode::LoadNil, 1, 0, 0),
+			encode(Opcode::Return, 1, 2, 0),
+		]);
+		function.num_params = 1;
+		let graph = def_use(&function);
+		assert_eq!(graph.defs_of(5, 1), &defs(&[Some(2), Some(4)], 1)[..]);
+		assert_eq!(graph.uses_of(Some(2), 1), [5]);
+		assert_eq!(graph.uses_of(Some(4), 1), [5]);
+		assert_eq!(graph.defs_of(0, 0), &defs(&[None], 0)[..]);
+	}
+}
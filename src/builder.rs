@@ -0,0 +1,176 @@
+//! Incremental construction of functions.
+
+use super::bytecode::{EncodeError, Opcode, RK, encode};
+use super::optimize::add_constant;
+use super::{Int, Instruction, Constant, Upvalue, Debug, Function};
+
+/// The name of the upvalue through which globals are accessed.
+pub const ENV: &str = "_ENV";
+
+/// A builder for a `Function`, which keeps its parts consistent.
+#[derive(Clone, Debug)]
+pub struct FunctionBuilder {
+	function: Function,
+}
+
+impl Default for FunctionBuilder {
+	fn default() -> FunctionBuilder {
+		FunctionBuilder::new()
+	}
+}
+
+/// Finishes building, as by `build`.
+impl From<FunctionBuilder> for Function {
+	fn from(builder: FunctionBuilder) -> Function {
+		builder.build()
+	}
+}
+
+impl FunctionBuilder {
+	/// Start building an empty function.
+	pub fn new() -> FunctionBuilder {
+		FunctionBuilder {
+			function: Function {
+				source: String::new(),
+				line_start: 0,
+				line_end: 0,
+				num_params: 0,
+				is_vararg: false,
+				max_stack_size: 2,
+				code: vec![],
+				constants: vec![],
+				upvalues: vec![],
+				protos: vec![],
+				debug: Debug::none(),
+			},
+		}
+	}
+
+	/// Continue building an existing function, appending to its code.
+	pub fn from_function(function: Function) -> FunctionBuilder {
+		FunctionBuilder { function }
+	}
+
+	/// Start building a main chunk.
+	///
+	/// Like those compiled by Lua, it is vararg and its only upvalue is
+	/// `_ENV`, which the loader sets to the globals table.
+	pub fn main_chunk() -> FunctionBuilder {
+		let mut builder = FunctionBuilder::new();
+		builder.function.is_vararg = true;
+		builder.upvalue(Upvalue::Stack(0), ENV);
+		builder
+	}
+
+	/// Add an upvalue with the given debug name, returning its index.
+	///
+	/// # Panics
+	///
+	/// Panics if the function already has 256 upvalues, since the index of
+	/// another would not fit in a `u8`.
+	pub fn upvalue(&mut self, upvalue: Upvalue, name: &str) -> u8 {
+		assert!(self.function.upvalues.len() < 0x100, "function has too many upvalues");
+		self.function.upvalues.push(upvalue);
+		self.function.debug.upvalues.push(name.to_owned());
+		(self.function.upvalues.len() - 1) as u8
+	}
+
+	/// Find the index of the `_ENV` upvalue, if there is one.
+	pub fn env_upvalue(&self) -> Option<u8> {
+		self.function.debug.upvalues.iter().position(|name| name == ENV).map(|idx| idx as u8)
+	}
+
+	/// Find or add a constant, returning its index.
+	pub fn constant(&mut self, value: Constant) -> usize {
+		add_constant(&mut self.function.constants, value)
+	}
+
+	/// Append an instruction, returning its pc.
+	pub fn emit(&mut self, instr: Instruction) -> usize {
+		self.function.code.push(instr);
+		self.function.code.len() - 1
+	}
+
+	/// Append an instruction attributed to `line` in the line info,
+	/// returning its pc.
+	///
+	/// Line info must cover every instruction or none, so instructions
+	/// emitted before without a line are given that of the one before them,
+	/// or `line` if there is none.
+	pub fn emit_at_line(&mut self, instr: Instruction, line: Int) -> usize {
+		let pc = self.emit(instr);
+		let debug = &mut self.function.debug;
+		let fill = debug.lineinfo.last().cloned().unwrap_or(line);
+		debug.lineinfo.resize(pc, fill);
+		debug.push_line(line);
+		pc
+	}
+
+	/// Emit a `GetTabUp` loading the global named by constant `name` into
+	/// register `a`.
+	///
+	/// Fails if `name` is too large for an `RK` operand.
+	///
+	/// # Panics
+	///
+	/// Panics if the function has no `_ENV` upvalue.
+	pub fn emit_global_get(&mut self, a: u8, name: usize) -> Result<usize, EncodeError> {
+		let name = RK::constant(name)?;
+		let env = self.env_upvalue().expect("function has no _ENV upvalue");
+		Ok(self.emit(encode(Opcode::GetTabUp, a, env as u32, name.encode())))
+	}
+
+	/// Emit a `SetTabUp` storing `value` in the global named by constant
+	/// `name`.
+	///
+	/// Fails if `name` is too large for an `RK` operand.
+	///
+	/// # Panics
+	///
+	/// Panics if the function has no `_ENV` upvalue.
+	pub fn emit_global_set(&mut self, name: usize, value: RK) -> Result<usize, EncodeError> {
+		let name = RK::constant(name)?;
+		let env = self.env_upvalue().expect("function has no _ENV upvalue");
+		Ok(self.emit(encode(Opcode::SetTabUp, env, name.encode(), value.encode())))
+	}
+
+	/// Access the function built so far.
+	pub fn function_mut(&mut self) -> &mut Function {
+		&mut self.function
+	}
+
+	/// Finish building the function.
+	pub fn build(self) -> Function {
+		self.function
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bytecode::{Opcode, encode};
+	use super::super::Upvalue;
+	use super::FunctionBuilder;
+
+	#[test]
+	fn emit_at_line() {
+		let mut builder = FunctionBuilder::new();
+		builder.emit(encode(Opcode::LoadNil, 0, 0, 0));
+		assert_eq!(builder.emit_at_line(encode(Opcode::LoadNil, 1, 0, 0), 3), 1);
+		builder.emit(encode(Opcode::Move, 0, 1, 0));
+		builder.emit_at_line(encode(Opcode::Return, 0, 1, 0), 4);
+		let function = builder.build();
+		assert_eq!(function.debug.lineinfo, [3, 3, 3, 4]);
+		assert_eq!(function.debug.line_for_pc(3), Some(4));
+		function.validate().unwrap();
+	}
+
+	#[test]
+	#[should_panic(expected = "too many upvalues")]
+	fn upvalue_limit() {
+		let mut builder = FunctionBuilder::new();
+		for i in 0..0x100 {
+			assert_eq!(builder.upvalue(Upvalue::Outer(0), "x"), i as u8);
+		}
+		builder.upvalue(Upvalue::Outer(0), "x");
+	}
+}
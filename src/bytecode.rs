@@ -1,5 +1,13 @@
 //! Tools for bytecode generation.
 
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+
+use super::builder::FunctionBuilder;
+use super::disasm::format_operands;
+use super::Constant;
+
 const BITRK: u32 = 1 << 8;
 
 /// A slot which is either a register (`R`) or constant (`K`).
@@ -22,13 +30,48 @@ impl RK {
 	}
 	/// Convert this `RK` to a number.
 	pub fn encode(&self) -> u32 {
-		match self {
-			&RK::R(r) => r as u32,
-			&RK::K(k) => (k as u32) | BITRK,
+		match *self {
+			RK::R(r) => r as u32,
+			RK::K(k) => (k as u32) | BITRK,
+		}
+	}
+
+	/// Refer to the constant at `index`, which must be below 256 to fit in
+	/// an `RK` operand.
+	pub fn constant(index: usize) -> Result<RK, EncodeError> {
+		if index <= 0xff {
+			Ok(RK::K(index as u8))
+		} else {
+			Err(EncodeError::ConstantIndexTooLarge(index.min(u32::MAX as usize) as u32))
+		}
+	}
+}
+
+/// An operand which cannot be encoded in an instruction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EncodeError {
+	/// A constant index is too large for an `RK` operand, which can refer
+	/// only to the first 256 constants. Larger indices must be loaded into
+	/// a register with `LoadK` or `LoadKX` first.
+	ConstantIndexTooLarge(u32),
+	/// Code would need this many registers, more than the 255 a function
+	/// can have.
+	TooManyRegisters(u32),
+}
+
+impl fmt::Display for EncodeError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			EncodeError::ConstantIndexTooLarge(index) =>
+				write!(f, "constant index {} is too large for an RK operand", index),
+			EncodeError::TooManyRegisters(count) =>
+				write!(f, "{} registers are needed but a function can have at most 255", count),
 		}
 	}
 }
 
+impl Error for EncodeError {}
+
 /// Encode an instruction with `A`, `B`, and `C` parameters.
 pub fn encode(op: Opcode, a: u8, b: u32, c: u32) -> u32 {
 	(op as u32) | ((a as u32) << 6) | ((c & 0x1ff) << 14) | ((b & 0x1ff) << 23)
@@ -41,7 +84,7 @@ pub fn encode_bx(op: Opcode, a: u8, bx: u32) -> u32 {
 
 /// Encode an instruction with `A` and `sBx` parameters.
 pub fn encode_sbx(op: Opcode, a: u8, sbx: i32) -> u32 {
-	(op as u32) | ((a as u32) << 6) | ((((sbx + 0x20000) as u32) & 0x3ffff) << 14)
+	(op as u32) | ((a as u32) << 6) | ((((sbx + 0x1ffff) as u32) & 0x3ffff) << 14)
 }
 
 /// Encode an instruction with an `Ax` parameter.
@@ -49,6 +92,136 @@ pub fn encode_ax(op: Opcode, ax: u32) -> u32 {
 	(op as u32) | ((ax & 0x3ffffff) << 6)
 }
 
+/// Encode a count for `B` or `C`, where zero means "up to the stack top".
+fn count(n: Option<u32>) -> u32 {
+	n.map_or(0, |n| {
+		// 511 would wrap around to the "up to the stack top" zero.
+		assert!(n < 0x1ff, "count {} is too large for a 9-bit operand", n);
+		n + 1
+	})
+}
+
+/// Encode a `Call` of the function in register `a`.
+///
+/// The arguments are taken from the registers after `a`, and the results
+/// are placed starting at `a`. Counts are encoded plus one, because zero is
+/// reserved: `None` for `nargs` passes every value up to the stack top, as
+/// left by an open call or `VarArg`, and `None` for `nresults` keeps every
+/// result and sets the stack top after them.
+///
+/// # Panics
+///
+/// Panics if a count is more than 510.
+pub fn emit_call(a: u8, nargs: Option<u32>, nresults: Option<u32>) -> u32 {
+	encode(Opcode::Call, a, count(nargs), count(nresults))
+}
+
+/// Encode a `TailCall` of the function in register `a`.
+///
+/// `nargs` is as for `emit_call`. All results are returned.
+///
+/// # Panics
+///
+/// Panics if `nargs` is more than 510.
+pub fn emit_tailcall(a: u8, nargs: Option<u32>) -> u32 {
+	encode(Opcode::TailCall, a, count(nargs), 0)
+}
+
+/// Encode a `Return` of `nresults` values starting at register `a`.
+///
+/// `None` returns every value up to the stack top.
+///
+/// # Panics
+///
+/// Panics if `nresults` is more than 510.
+pub fn emit_return(a: u8, nresults: Option<u32>) -> u32 {
+	encode(Opcode::Return, a, count(nresults), 0)
+}
+
+/// Emit a generic `for` loop, as for `for k, v in pairs(t) do ... end`.
+///
+/// Registers `base`, `base + 1`, and `base + 2` must already hold the
+/// iterator function, state, and control value, such as the three results
+/// of calling `pairs`. The `num_vars` loop variables are assigned to the
+/// registers from `base + 3` on each iteration, after which `body` emits
+/// the loop body. `max_stack_size` is raised to fit the loop variables.
+///
+/// Fails without emitting anything if the loop variables would not fit in
+/// the 255 registers a function can have, as when `base` is over 252.
+pub fn build_generic_for<F>(builder: &mut FunctionBuilder, base: u8, num_vars: u8, body: F) -> Result<(), EncodeError>
+	where F: FnOnce(&mut FunctionBuilder)
+{
+	let needed = base as u32 + 3 + num_vars as u32;
+	if needed > 0xff {
+		return Err(EncodeError::TooManyRegisters(needed));
+	}
+	let jump = builder.emit(encode_sbx(Opcode::Jump, 0, 0));
+	body(builder);
+	let call = builder.emit(encode(Opcode::TForCall, base, 0, num_vars as u32));
+	let ret = builder.emit(encode_sbx(Opcode::TForLoop, base + 2, 0));
+
+	let function = builder.function_mut();
+	function.code[jump] = encode_sbx(Opcode::Jump, 0, (call - (jump + 1)) as i32);
+	function.code[ret] = encode_sbx(Opcode::TForLoop, base + 2, (jump + 1) as i32 - (ret + 1) as i32);
+	function.max_stack_size = function.max_stack_size.max(needed as u8);
+	Ok(())
+}
+
+/// Decode the opcode of an instruction, if it is valid.
+pub fn get_opcode(instr: u32) -> Option<Opcode> {
+	Opcode::from_u8((instr & 0x3f) as u8)
+}
+
+/// Decode the `A` parameter of an instruction.
+pub fn get_a(instr: u32) -> u8 {
+	(instr >> 6) as u8
+}
+
+/// Decode the `B` parameter of an instruction.
+pub fn get_b(instr: u32) -> u32 {
+	(instr >> 23) & 0x1ff
+}
+
+/// Decode the `C` parameter of an instruction.
+pub fn get_c(instr: u32) -> u32 {
+	(instr >> 14) & 0x1ff
+}
+
+/// Decode the `Bx` parameter of an instruction.
+pub fn get_bx(instr: u32) -> u32 {
+	(instr >> 14) & 0x3ffff
+}
+
+/// Decode the `sBx` parameter of an instruction.
+pub fn get_sbx(instr: u32) -> i32 {
+	get_bx(instr) as i32 - 0x1ffff
+}
+
+/// Decode the `Ax` parameter of an instruction.
+pub fn get_ax(instr: u32) -> u32 {
+	(instr >> 6) & 0x3ffffff
+}
+
+/// Get the argument carried by the `ExtraArg` following the instruction at
+/// `pc`, if that instruction is one which takes an extra argument.
+///
+/// `LoadKX` and `SetList` with `C == 0` take their real operand from the
+/// `Ax` of the next instruction, which must be `ExtraArg`.
+pub fn extra_arg_value(code: &[u32], pc: usize) -> Option<u32> {
+	let instr = *code.get(pc)?;
+	match get_opcode(instr) {
+		Some(Opcode::LoadKX) => {}
+		Some(Opcode::SetList) if get_c(instr) == 0 => {}
+		_ => return None,
+	}
+	let next = *code.get(pc + 1)?;
+	if get_opcode(next) == Some(Opcode::ExtraArg) {
+		Some(get_ax(next))
+	} else {
+		None
+	}
+}
+
 // LSB 6      8         9         9  MSB
 // |------|--------|---------|---------|
 // |opcode|   A    |    C    |    B    |
@@ -58,72 +231,513 @@ pub fn encode_ax(op: Opcode, ax: u32) -> u32 {
 
 /// A Lua opcode.
 #[repr(u8)]
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum Opcode { // Args   Action
-	Move,     // A B    R(A) := R(B)
-	LoadK,    // A Bx   R(A) := Kst(Bx)
-	LoadKX,   // A      R(A) := Kst(extra arg)
+	Move = 0,      // A B    R(A) := R(B)
+	LoadK = 1,     // A Bx   R(A) := Kst(Bx)
+	LoadKX = 2,    // A      R(A) := Kst(extra arg)
 	// ^- the next 'instruction' is always EXTRAARG.
-	LoadBool, // A B C  R(A) := (Bool)B; if (C) pc++
-	LoadNil,  // A B    R(A), R(A+1), ..., R(A+B) := nil
-
-	GetUpval, // A B    R(A) := UpValue[B]
-	GetTabUp, // A B C  R(A) := UpValue[B][RK(C)]
-	GetTable, // A B C  R(A) := R(B)[RK(C)]
-
-	SetTabUp, // A B C  UpValue[A][RK(B)] := RK(C)
-	SetUpval, // A B    UpValue[B] := R(A)
-	SetTable, // A B C  R(A)[RK(B)] := RK(C)
-
-	NewTable, // A B C  R(A) := {} (size = B,C)
-
-	Self_,     // A B C  R(A+1) := R(B); R(A) := R(B)[RK(C)]
-
-	Add,      // A B C  R(A) := RK(B) + RK(C)
-	Sub,      // A B C  R(A) := RK(B) - RK(C)
-	Mul,      // A B C  R(A) := RK(B) * RK(C)
-	Mod,      // A B C  R(A) := RK(B) % RK(C)
-	Pow,      // A B C  R(A) := RK(B) ^ RK(C)
-	Div,      // A B C  R(A) := RK(B) / RK(C)
-	IntDiv,   // A B C  R(A) := RK(B) // RK(C)
-	BinAnd,   // A B C  R(A) := RK(B) & RK(C)
-	BinOr,    // A B C  R(A) := RK(B) | RK(C)
-	BinXor,   // A B C  R(A) := RK(B) ~ RK(C)
-	ShLeft,   // A B C  R(A) := RK(B) << RK(C)
-	ShRight,  // A B C  R(A) := RK(B) >> RK(C)
-	UnMinus,  // A B    R(A) := -R(B)
-	BinNot,   // A B    R(A) := ~R(B)
-	Not,      // A B    R(A) := not R(B)
-	Len,      // A B    R(A) := length of R(B)
-
-	Concat,   // A B C  R(A) := R(B).. ... ..R(C)
-
-	Jump,     // A sBx  pc += sBx; if(A) close all upvalues >= R(A - 1)
-	Eq,       // A B C  if ((RK(B) == RK(C)) ~= A) then pc++
-	Less,     // A B C  if ((RK(B) <  RK(C)) ~= A) then pc++
-	LessEq,   // A B C  if ((RK(B) <= RK(C)) ~= A) then pc++
+	LoadBool = 3,  // A B C  R(A) := (Bool)B; if (C) pc++
+	LoadNil = 4,   // A B    R(A), R(A+1), ..., R(A+B) := nil
+
+	GetUpval = 5,  // A B    R(A) := UpValue[B]
+	GetTabUp = 6,  // A B C  R(A) := UpValue[B][RK(C)]
+	GetTable = 7,  // A B C  R(A) := R(B)[RK(C)]
+
+	SetTabUp = 8,  // A B C  UpValue[A][RK(B)] := RK(C)
+	SetUpval = 9,  // A B    UpValue[B] := R(A)
+	SetTable = 10, // A B C  R(A)[RK(B)] := RK(C)
+
+	NewTable = 11, // A B C  R(A) := {} (size = B,C)
+
+	Self_ = 12,    // A B C  R(A+1) := R(B); R(A) := R(B)[RK(C)]
+
+	Add = 13,      // A B C  R(A) := RK(B) + RK(C)
+	Sub = 14,      // A B C  R(A) := RK(B) - RK(C)
+	Mul = 15,      // A B C  R(A) := RK(B) * RK(C)
+	Mod = 16,      // A B C  R(A) := RK(B) % RK(C)
+	Pow = 17,      // A B C  R(A) := RK(B) ^ RK(C)
+	Div = 18,      // A B C  R(A) := RK(B) / RK(C)
+	IntDiv = 19,   // A B C  R(A) := RK(B) // RK(C)
+	BinAnd = 20,   // A B C  R(A) := RK(B) & RK(C)
+	BinOr = 21,    // A B C  R(A) := RK(B) | RK(C)
+	BinXor = 22,   // A B C  R(A) := RK(B) ~ RK(C)
+	ShLeft = 23,   // A B C  R(A) := RK(B) << RK(C)
+	ShRight = 24,  // A B C  R(A) := RK(B) >> RK(C)
+	UnMinus = 25,  // A B    R(A) := -R(B)
+	BinNot = 26,   // A B    R(A) := ~R(B)
+	Not = 27,      // A B    R(A) := not R(B)
+	Len = 28,      // A B    R(A) := length of R(B)
+
+	Concat = 29,   // A B C  R(A) := R(B).. ... ..R(C)
+
+	Jump = 30,     // A sBx  pc += sBx; if(A) close all upvalues >= R(A - 1)
+	Eq = 31,       // A B C  if ((RK(B) == RK(C)) ~= A) then pc++
+	Less = 32,     // A B C  if ((RK(B) <  RK(C)) ~= A) then pc++
+	LessEq = 33,   // A B C  if ((RK(B) <= RK(C)) ~= A) then pc++
 	// ^- A specifies what condition the test should accept (true or false).
-	Test,     // A   C 	if not (R(A) <=> C) then pc++
-	TestSet,  // A B C  if (R(B) <=> C) then R(A) := R(B) else pc++
+	Test = 34,     // A   C 	if not (R(A) <=> C) then pc++
+	TestSet = 35,  // A B C  if (R(B) <=> C) then R(A) := R(B) else pc++
 
-	Call,     // A B C  R(A), ... ,R(A+C-2) := R(A)(R(A+1), ... ,R(A+B-1))
+	Call = 36,     // A B C  R(A), ... ,R(A+C-2) := R(A)(R(A+1), ... ,R(A+B-1))
 	// ^- if (B == 0) then B = top. If (C == 0), then 'top' is
-    //    set to last_result+1, so next open instruction (OP_CALL, OP_RETURN,
-    //    OP_SETLIST) may use 'top'.
-	TailCall, // A B C  return R(A)(R(A+1), ... ,R(A+B-1))
-	Return,   // A B    return R(A), ... ,R(A+B-2)
+	//    set to last_result+1, so next open instruction (OP_CALL, OP_RETURN,
+	//    OP_SETLIST) may use 'top'.
+	TailCall = 37, // A B C  return R(A)(R(A+1), ... ,R(A+B-1))
+	Return = 38,   // A B    return R(A), ... ,R(A+B-2)
 	// ^- if (B == 0) then return up to 'top'
 
-	ForLoop,  // A sBx  R(A)+=R(A+2); if R(A) <?= R(A+1) then { pc+=sBx; R(A+3)=R(A) }
-	ForPrep,  // A sBx  R(A)-=R(A+2); pc+=sBx
-	TForCall, // A   C  R(A+3), ... ,R(A+2+C) := R(A)(R(A+1), R(A+2));
-	TForLoop, // A sBx  if R(A+1) ~= nil then { R(A)=R(A+1); pc += sBx }
-	SetList,  // A B C  R(A)[(C-1)*FPF+i] := R(A+i), 1 <= i <= B
+	ForLoop = 39,  // A sBx  R(A)+=R(A+2); if R(A) <?= R(A+1) then { pc+=sBx; R(A+3)=R(A) }
+	ForPrep = 40,  // A sBx  R(A)-=R(A+2); pc+=sBx
+	TForCall = 41, // A   C  R(A+3), ... ,R(A+2+C) := R(A)(R(A+1), R(A+2));
+	TForLoop = 42, // A sBx  if R(A+1) ~= nil then { R(A)=R(A+1); pc += sBx }
+	SetList = 43,  // A B C  R(A)[(C-1)*FPF+i] := R(A+i), 1 <= i <= B
 	// ^- if (B == 0) then B = 'top'; if (C == 0) then next
 	//    'instruction' is EXTRAARG(real C).
-	Closure,  // A Bx   R(A) := closure(KPROTO[Bx])
-	VarArg,   // A B    R(A), R(A+1), ..., R(A+B-2) = vararg
+	Closure = 44,  // A Bx   R(A) := closure(KPROTO[Bx])
+	VarArg = 45,   // A B    R(A), R(A+1), ..., R(A+B-2) = vararg
 	// ^- if (B == 0) then use actual number of varargs and
-    //    set top (like in OP_CALL with C == 0).
-	ExtraArg, // Ax     extra (larger) argument for previous opcode
+	//    set top (like in OP_CALL with C == 0).
+	ExtraArg = 46, // Ax     extra (larger) argument for previous opcode
+}
+
+const OPCODES: [Opcode; 47] = [
+	Opcode::Move, Opcode::LoadK, Opcode::LoadKX, Opcode::LoadBool, Opcode::LoadNil,
+	Opcode::GetUpval, Opcode::GetTabUp, Opcode::GetTable,
+	Opcode::SetTabUp, Opcode::SetUpval, Opcode::SetTable,
+	Opcode::NewTable, Opcode::Self_,
+	Opcode::Add, Opcode::Sub, Opcode::Mul, Opcode::Mod, Opcode::Pow, Opcode::Div,
+	Opcode::IntDiv, Opcode::BinAnd, Opcode::BinOr, Opcode::BinXor, Opcode::ShLeft,
+	Opcode::ShRight, Opcode::UnMinus, Opcode::BinNot, Opcode::Not, Opcode::Len,
+	Opcode::Concat,
+	Opcode::Jump, Opcode::Eq, Opcode::Less, Opcode::LessEq, Opcode::Test, Opcode::TestSet,
+	Opcode::Call, Opcode::TailCall, Opcode::Return,
+	Opcode::ForLoop, Opcode::ForPrep, Opcode::TForCall, Opcode::TForLoop,
+	Opcode::SetList, Opcode::Closure, Opcode::VarArg, Opcode::ExtraArg,
+];
+
+// Refuse to compile if the table above disagrees with the discriminants.
+const _: () = {
+	let mut i = 0;
+	while i < OPCODES.len() {
+		assert!(OPCODES[i] as usize == i, "OPCODES does not match Opcode discriminants");
+		i += 1;
+	}
+};
+
+impl Opcode {
+	/// Convert a number to an `Opcode`, if it is in range.
+	pub fn from_u8(value: u8) -> Option<Opcode> {
+		OPCODES.get(value as usize).cloned()
+	}
+
+	/// Every opcode, in numeric order.
+	pub fn all() -> &'static [Opcode] {
+		&OPCODES
+	}
+}
+
+/// The set of opcodes which appear in `code`, skipping invalid
+/// instructions.
+///
+/// Together with `Opcode::all`, this finds the opcodes a code generator's
+/// tests never exercise.
+pub fn opcodes_used(code: &[u32]) -> HashSet<Opcode> {
+	code.iter().filter_map(|&instr| get_opcode(instr)).collect()
+}
+
+/// The layout of an instruction's operands.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+	/// `A`, `B`, and `C` operands.
+	ABC,
+	/// `A` and `Bx` operands.
+	ABx,
+	/// `A` and `sBx` operands.
+	AsBx,
+	/// An `Ax` operand.
+	Ax,
+}
+
+/// The role an operand plays in an instruction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Operand {
+	/// The operand is not used.
+	Unused,
+	/// A register index.
+	Register,
+	/// A constant table index.
+	Constant,
+	/// Either a register or constant table index, encoded as an `RK`.
+	RegisterOrConstant,
+	/// An upvalue index.
+	Upvalue,
+	/// A nested function prototype index.
+	Proto,
+	/// A jump offset relative to the next instruction.
+	Jump,
+	/// A number with an opcode-specific meaning, such as a count or flag.
+	Value,
+}
+
+/// The operand layout of an opcode.
+///
+/// For the `ABx` and `AsBx` formats, `b` describes the `Bx` or `sBx` operand
+/// and `c` is unused. For the `Ax` format, `a` describes the `Ax` operand.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OpMode {
+	/// The layout of the operands.
+	pub format: Format,
+	/// The role of the `A` operand.
+	pub a: Operand,
+	/// The role of the `B` operand.
+	pub b: Operand,
+	/// The role of the `C` operand.
+	pub c: Operand,
+}
+
+impl Opcode {
+	/// Get the operand layout of this opcode.
+	pub const fn mode(self) -> OpMode {
+		use self::Format::*;
+		use self::Operand::*;
+		let (format, a, b, c) = match self {
+			Opcode::Move => (ABC, Register, Register, Unused),
+			Opcode::LoadK => (ABx, Register, Constant, Unused),
+			Opcode::LoadKX => (ABx, Register, Unused, Unused),
+			Opcode::LoadBool => (ABC, Register, Value, Value),
+			Opcode::LoadNil => (ABC, Register, Value, Unused),
+			Opcode::GetUpval => (ABC, Register, Upvalue, Unused),
+			Opcode::GetTabUp => (ABC, Register, Upvalue, RegisterOrConstant),
+			Opcode::GetTable => (ABC, Register, Register, RegisterOrConstant),
+			Opcode::SetTabUp => (ABC, Upvalue, RegisterOrConstant, RegisterOrConstant),
+			Opcode::SetUpval => (ABC, Register, Upvalue, Unused),
+			Opcode::SetTable => (ABC, Register, RegisterOrConstant, RegisterOrConstant),
+			Opcode::NewTable => (ABC, Register, Value, Value),
+			Opcode::Self_ => (ABC, Register, Register, RegisterOrConstant),
+			Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Mod | Opcode::Pow | Opcode::Div |
+			Opcode::IntDiv | Opcode::BinAnd | Opcode::BinOr | Opcode::BinXor | Opcode::ShLeft |
+			Opcode::ShRight => (ABC, Register, RegisterOrConstant, RegisterOrConstant),
+			Opcode::UnMinus | Opcode::BinNot | Opcode::Not | Opcode::Len => (ABC, Register, Register, Unused),
+			Opcode::Concat => (ABC, Register, Register, Register),
+			Opcode::Jump => (AsBx, Value, Jump, Unused),
+			Opcode::Eq | Opcode::Less | Opcode::LessEq => (ABC, Value, RegisterOrConstant, RegisterOrConstant),
+			Opcode::Test => (ABC, Register, Unused, Value),
+			Opcode::TestSet => (ABC, Register, Register, Value),
+			Opcode::Call | Opcode::TailCall => (ABC, Register, Value, Value),
+			Opcode::Return => (ABC, Register, Value, Unused),
+			Opcode::ForLoop | Opcode::ForPrep | Opcode::TForLoop => (AsBx, Register, Jump, Unused),
+			Opcode::TForCall => (ABC, Register, Unused, Value),
+			Opcode::SetList => (ABC, Register, Value, Value),
+			Opcode::Closure => (ABx, Register, Proto, Unused),
+			Opcode::VarArg => (ABC, Register, Value, Unused),
+			Opcode::ExtraArg => (Ax, Value, Unused, Unused),
+		};
+		OpMode { format, a, b, c }
+	}
+}
+
+/// Metadata about an opcode, mirroring Lua's `luaP_opnames` and
+/// `luaP_opmodes`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OpcodeInfo {
+	/// The opcode.
+	pub opcode: Opcode,
+	/// The name Lua uses for the opcode, such as `"GETTABUP"`.
+	pub name: &'static str,
+	/// The operand layout.
+	pub mode: OpMode,
+	/// Whether the opcode is a test, which conditionally skips the next
+	/// instruction. The next instruction is then always a `Jump`.
+	pub test: bool,
+	/// Whether the opcode assigns to register `A`.
+	pub sets_a: bool,
+}
+
+const fn opcode_info(opcode: Opcode) -> OpcodeInfo {
+	use self::Opcode::*;
+	let name = match opcode {
+		Move => "MOVE",
+		LoadK => "LOADK",
+		LoadKX => "LOADKX",
+		LoadBool => "LOADBOOL",
+		LoadNil => "LOADNIL",
+		GetUpval => "GETUPVAL",
+		GetTabUp => "GETTABUP",
+		GetTable => "GETTABLE",
+		SetTabUp => "SETTABUP",
+		SetUpval => "SETUPVAL",
+		SetTable => "SETTABLE",
+		NewTable => "NEWTABLE",
+		Self_ => "SELF",
+		Add => "ADD",
+		Sub => "SUB",
+		Mul => "MUL",
+		Mod => "MOD",
+		Pow => "POW",
+		Div => "DIV",
+		IntDiv => "IDIV",
+		BinAnd => "BAND",
+		BinOr => "BOR",
+		BinXor => "BXOR",
+		ShLeft => "SHL",
+		ShRight => "SHR",
+		UnMinus => "UNM",
+		BinNot => "BNOT",
+		Not => "NOT",
+		Len => "LEN",
+		Concat => "CONCAT",
+		Jump => "JMP",
+		Eq => "EQ",
+		Less => "LT",
+		LessEq => "LE",
+		Test => "TEST",
+		TestSet => "TESTSET",
+		Call => "CALL",
+		TailCall => "TAILCALL",
+		Return => "RETURN",
+		ForLoop => "FORLOOP",
+		ForPrep => "FORPREP",
+		TForCall => "TFORCALL",
+		TForLoop => "TFORLOOP",
+		SetList => "SETLIST",
+		Closure => "CLOSURE",
+		VarArg => "VARARG",
+		ExtraArg => "EXTRAARG",
+	};
+	let test = matches!(opcode, Eq | Less | LessEq | Test | TestSet);
+	let sets_a = !matches!(
+		opcode,
+		SetTabUp | SetUpval | SetTable | Jump | Eq | Less | LessEq | Test | Return |
+		TForCall | SetList | ExtraArg
+	);
+	OpcodeInfo { opcode, name, mode: opcode.mode(), test, sets_a }
+}
+
+static OPCODE_TABLE: [OpcodeInfo; 47] = {
+	let mut table = [opcode_info(Opcode::Move); 47];
+	let mut i = 0;
+	while i < OPCODES.len() {
+		table[i] = opcode_info(OPCODES[i]);
+		i += 1;
+	}
+	table
+};
+
+/// Get the metadata of every opcode, indexed by opcode number.
+pub fn opcode_table() -> &'static [OpcodeInfo] {
+	&OPCODE_TABLE
+}
+
+impl Opcode {
+	/// Get the metadata of this opcode.
+	pub fn info(self) -> &'static OpcodeInfo {
+		&OPCODE_TABLE[self as usize]
+	}
+}
+
+/// An instruction split into its opcode and operands.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DecodedInstruction {
+	/// The opcode.
+	pub opcode: Opcode,
+	/// The `A` operand, or `Ax` for the `Ax` format.
+	pub a: i32,
+	/// The `B`, `Bx`, or `sBx` operand, depending on the format.
+	pub b: i32,
+	/// The `C` operand, or zero for formats without one.
+	pub c: i32,
+}
+
+impl DecodedInstruction {
+	/// Decode an instruction, if its opcode is valid.
+	pub fn decode(instr: u32) -> Option<DecodedInstruction> {
+		let opcode = get_opcode(instr)?;
+		let (a, b, c) = match opcode.mode().format {
+			Format::ABC => (get_a(instr) as i32, get_b(instr) as i32, get_c(instr) as i32),
+			Format::ABx => (get_a(instr) as i32, get_bx(instr) as i32, 0),
+			Format::AsBx => (get_a(instr) as i32, get_sbx(instr), 0),
+			Format::Ax => (get_ax(instr) as i32, 0, 0),
+		};
+		Some(DecodedInstruction { opcode, a, b, c })
+	}
+
+	/// Encode this instruction.
+	pub fn encode(&self) -> u32 {
+		match self.opcode.mode().format {
+			Format::ABC => encode(self.opcode, self.a as u8, self.b as u32, self.c as u32),
+			Format::ABx => encode_bx(self.opcode, self.a as u8, self.b as u32),
+			Format::AsBx => encode_sbx(self.opcode, self.a as u8, self.b),
+			Format::Ax => encode_ax(self.opcode, self.a as u32),
+		}
+	}
+
+	/// Render the operands, each labeled by its role: `R0` for a register,
+	/// `K3` for a constant, `U1` for an upvalue, and `P2` for a nested
+	/// function. Jump offsets are signed and other values are plain numbers.
+	/// Unused operands are omitted.
+	pub fn display_operands(&self) -> String {
+		let mode = self.opcode.mode();
+		let operands = [(mode.a, self.a), (mode.b, self.b), (mode.c, self.c)];
+		let mut out = String::new();
+		for &(role, value) in &operands {
+			let text = match role {
+				Operand::Unused => continue,
+				Operand::Register => format!("R{}", value),
+				Operand::Constant => format!("K{}", value),
+				Operand::RegisterOrConstant => match RK::decode(value as u32) {
+					RK::R(r) => format!("R{}", r),
+					RK::K(k) => format!("K{}", k),
+				},
+				Operand::Upvalue => format!("U{}", value),
+				Operand::Proto => format!("P{}", value),
+				Operand::Jump => format!("{:+}", value),
+				Operand::Value => format!("{}", value),
+			};
+			if !out.is_empty() {
+				out.push(' ');
+			}
+			out.push_str(&text);
+		}
+		out
+	}
+}
+
+/// Render an instruction as a disassembly line: its pc, opcode, and
+/// operands, followed by the values of any constants it refers to if
+/// `constants` is given.
+///
+/// This formats instructions as `disasm::instruction_text` does without
+/// needing the whole `Function`. The constant loaded by a `LoadKX` is in
+/// the following `ExtraArg`, so it is not shown.
+pub fn format_instruction(instr: u32, pc: usize, constants: Option<&[Constant]>) -> String {
+	format!("[{}] {}", pc, format_operands(instr, None, constants))
+}
+
+/// A malformed instruction, as found by `validate_instruction`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InstrError {
+	/// The opcode number is not that of any opcode.
+	InvalidOpcode(u8),
+	/// An operand which the opcode does not use is not zero.
+	UnusedOperand {
+		/// The opcode.
+		opcode: Opcode,
+		/// The name of the operand, such as `"B"`.
+		operand: &'static str,
+		/// The operand's value.
+		value: u32,
+	},
+	/// A register or upvalue operand exceeds 255, as when a constant is
+	/// given where only a register is allowed.
+	OperandOutOfRange {
+		/// The opcode.
+		opcode: Opcode,
+		/// The name of the operand, such as `"C"`.
+		operand: &'static str,
+		/// The operand's value.
+		value: u32,
+	},
+}
+
+impl fmt::Display for InstrError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			InstrError::InvalidOpcode(op) => write!(f, "invalid opcode {}", op),
+			InstrError::UnusedOperand { opcode, operand, value } =>
+				write!(f, "{:?} does not use {}, but it is {}", opcode, operand, value),
+			InstrError::OperandOutOfRange { opcode, operand, value } =>
+				write!(f, "{:?} operand {} is out of range: {}", opcode, operand, value),
+		}
+	}
+}
+
+impl Error for InstrError {}
+
+/// Check that an instruction's operands suit its opcode's mode.
+///
+/// Operands the opcode does not use must be zero, and `B` and `C` operands
+/// which are registers or upvalues must fit in 8 bits. The `A` operand
+/// and the `Bx`, `sBx`, and `Ax` operands are always in range. Unlike
+/// `Function::validate`, this does not look at the rest of the function.
+pub fn validate_instruction(instr: u32) -> Result<(), InstrError> {
+	let opcode = get_opcode(instr).ok_or(InstrError::InvalidOpcode((instr & 0x3f) as u8))?;
+	let mode = opcode.mode();
+	let operands = match mode.format {
+		Format::ABC => [("B", mode.b, get_b(instr)), ("C", mode.c, get_c(instr))],
+		Format::ABx | Format::AsBx => [("Bx", mode.b, get_bx(instr)), ("C", Operand::Unused, 0)],
+		Format::Ax => return Ok(()),
+	};
+	for &(operand, role, value) in &operands {
+		match role {
+			Operand::Unused if value != 0 =>
+				return Err(InstrError::UnusedOperand { opcode, operand, value }),
+			Operand::Register | Operand::Upvalue if value > 0xff =>
+				return Err(InstrError::OperandOutOfRange { opcode, operand, value }),
+			_ => {}
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use builder::FunctionBuilder;
+	use super::{
+		EncodeError, Opcode, build_generic_for, emit_call, emit_return, emit_tailcall, encode, encode_sbx,
+		get_a, get_b, get_c, get_opcode, get_sbx,
+	};
+
+	#[test]
+	fn sbx_roundtrip() {
+		for &sbx in &[-0x1ffff, -1, 0, 1, 0x1ffff] {
+			assert_eq!(get_sbx(encode_sbx(Opcode::Jump, 0, sbx)), sbx);
+		}
+	}
+
+	#[test]
+	fn luac_jump() {
+		// `while true do end`, as compiled by luac 5.3: `JMP 0 -1`.
+		let instr = 0x7fff801e;
+		assert_eq!(get_opcode(instr), Some(Opcode::Jump));
+		assert_eq!(get_a(instr), 0);
+		assert_eq!(get_sbx(instr), -1);
+		assert_eq!(encode_sbx(Opcode::Jump, 0, -1), instr);
+	}
+
+	#[test]
+	fn call_counts() {
+		// Counts are stored plus one, with zero for "up to the stack top".
+		assert_eq!(emit_call(0, None, None), encode(Opcode::Call, 0, 0, 0));
+		assert_eq!(emit_call(0, Some(0), Some(0)), encode(Opcode::Call, 0, 1, 1));
+		// print("x"), as compiled by luac: CALL 0 2 1.
+		assert_eq!(emit_call(0, Some(1), Some(0)), encode(Opcode::Call, 0, 2, 1));
+		let call = emit_call(3, Some(510), Some(510));
+		assert_eq!((get_a(call), get_b(call), get_c(call)), (3, 511, 511));
+		assert!(::std::panic::catch_unwind(|| emit_call(0, Some(511), None)).is_err());
+
+		// return f(...): TAILCALL 0 0 0 and RETURN 0 0.
+		assert_eq!(emit_tailcall(0, None), encode(Opcode::TailCall, 0, 0, 0));
+		assert_eq!(emit_tailcall(1, Some(2)), encode(Opcode::TailCall, 1, 3, 0));
+		assert_eq!(emit_return(0, None), encode(Opcode::Return, 0, 0, 0));
+		assert_eq!(emit_return(0, Some(0)), encode(Opcode::Return, 0, 1, 0));
+		assert_eq!(emit_return(2, Some(1)), encode(Opcode::Return, 2, 2, 0));
+	}
+
+	#[test]
+	fn generic_for_registers() {
+		let mut builder = FunctionBuilder::new();
+		build_generic_for(&mut builder, 250, 2, |_| {}).unwrap();
+		assert_eq!(builder.function_mut().max_stack_size, 255);
+		assert_eq!(builder.function_mut().code, [
+			encode_sbx(Opcode::Jump, 0, 0),
+			encode(Opcode::TForCall, 250, 0, 2),
+			encode_sbx(Opcode::TForLoop, 252, -2),
+		]);
+
+		let mut builder = FunctionBuilder::new();
+		assert_eq!(build_generic_for(&mut builder, 253, 1, |_| {}), Err(EncodeError::TooManyRegisters(257)));
+		assert_eq!(build_generic_for(&mut builder, 251, 2, |_| {}), Err(EncodeError::TooManyRegisters(256)));
+		assert!(builder.function_mut().code.is_empty());
+	}
 }
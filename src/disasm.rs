@@ -0,0 +1,508 @@
+//! Human-readable listings of bytecode.
+
+use std::fmt::{self, Write};
+use std::mem::size_of;
+use std::ops::Range;
+use byteorder::NativeEndian;
+
+use super::bytecode::{DecodedInstruction, Opcode, Operand, RK, extra_arg_value};
+use super::string_len::{StringLenCodec, V53};
+use super::text::{escape_lua_string, format_lua_number};
+use super::{
+	SIGNATURE, FORMAT, VERSION, DATA, TEST_INT, TEST_NUMBER,
+	Int, Size, Instruction, Integer, Number,
+	Constant, Function,
+};
+
+/// Render a constant as it would appear in Lua source.
+pub fn constant_text(constant: &Constant) -> String {
+	match *constant {
+		Constant::Nil => "nil".to_owned(),
+		Constant::Boolean(b) => b.to_string(),
+		Constant::Float(n) => format_lua_number(n),
+		Constant::Int(n) => n.to_string(),
+		Constant::ShortString(ref s) | Constant::LongString(ref s) => escape_lua_string(s.as_bytes()),
+		Constant::Raw { tag, ref bytes } => format!("<type {:#04x}, {} bytes>", tag, bytes.len()),
+	}
+}
+
+/// Render the instruction at `pc` as its opcode and operands, followed by
+/// the values of any constants it refers to.
+///
+/// The operand of a `LoadKX` or of a `SetList` with `C == 0` is taken from
+/// the `ExtraArg` which follows it and shown in its place.
+pub fn instruction_text(function: &Function, pc: usize) -> String {
+	format_operands(function.code[pc], extra_arg_value(&function.code, pc), Some(&function.constants))
+}
+
+/// The pcs shown in a listing, leaving out each `ExtraArg` whose value is
+/// shown as part of the instruction before it.
+fn listed_pcs(code: &[Instruction]) -> Vec<usize> {
+	let mut pcs = Vec::with_capacity(code.len());
+	let mut pc = 0;
+	while pc < code.len() {
+		pcs.push(pc);
+		pc += if extra_arg_value(code, pc).is_some() { 2 } else { 1 };
+	}
+	pcs
+}
+
+/// Render an instruction as by `instruction_text`, given the `ExtraArg`
+/// which follows a `LoadKX` or `SetList` if known, and the constants if
+/// known.
+pub(crate) fn format_operands(instr: Instruction, extra_arg: Option<u32>, constants: Option<&[Constant]>) -> String {
+	let mut decoded = match DecodedInstruction::decode(instr) {
+		Some(decoded) => decoded,
+		None => return format!("<invalid {:#010x}>", instr),
+	};
+	let extra_arg = match (decoded.opcode, extra_arg) {
+		(Opcode::SetList, Some(value)) => {
+			decoded.c = value as i32;
+			None
+		}
+		(Opcode::LoadKX, extra_arg) => extra_arg,
+		_ => None,
+	};
+	let mut out = format!("{:<9} {}", format!("{:?}", decoded.opcode), decoded.display_operands());
+	if let Some(value) = extra_arg {
+		let _ = write!(out, " K{}", value);
+	}
+	let constants = match constants {
+		Some(constants) => constants,
+		None => return out,
+	};
+
+	let mode = decoded.opcode.mode();
+	let mut indices = Vec::new();
+	for &(role, value) in &[(mode.a, decoded.a), (mode.b, decoded.b), (mode.c, decoded.c)] {
+		match role {
+			Operand::Constant => indices.push(value as usize),
+			Operand::RegisterOrConstant => if let RK::K(k) = RK::decode(value as u32) {
+				indices.push(k as usize);
+			},
+			_ => {}
+		}
+	}
+	if let Some(value) = extra_arg {
+		indices.push(value as usize);
+	}
+	let values: Vec<String> = indices.iter()
+		.map(|&k| constants.get(k).map_or_else(|| "?".to_owned(), constant_text))
+		.collect();
+	if !values.is_empty() {
+		let _ = write!(out, "\t; {}", values.join(" "));
+	}
+	out
+}
+
+/// Produce a listing of a function and its nested functions.
+///
+/// Each function is introduced by a header naming its path, such as
+/// `main.0`, and each instruction is shown with its pc and, if there is
+/// line info, its line number.
+pub fn listing(function: &Function) -> String {
+	let mut out = String::new();
+	list_function(&mut out, function, "main");
+	out
+}
+
+fn list_function(out: &mut String, function: &Function, path: &str) {
+	let _ = writeln!(out, "function {} ({} instructions)", path, function.code.len());
+	for pc in listed_pcs(&function.code) {
+		let line = function.debug.line_for_pc(pc).map_or_else(|| "-".to_owned(), |line| line.to_string());
+		let _ = writeln!(out, "\t[{}]\t{}\t{}", pc, line, instruction_text(function, pc));
+	}
+	for (i, proto) in function.sub_functions() {
+		list_function(out, proto, &format!("{}.{}", path, i));
+	}
+}
+
+/// A wrapper whose `Debug` output shows a function's instructions decoded
+/// and its constants as Lua values, for use with `{:#?}` and `dbg!`.
+///
+/// Nested functions are shown the same way. Debug information is shown as
+/// by `Function`'s own `Debug`.
+#[derive(Clone, Copy)]
+pub struct Pretty<'a>(pub &'a Function);
+
+/// Text which is shown as is by `Debug`, without quotes.
+struct Unquoted(String);
+
+impl fmt::Debug for Unquoted {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.0)
+	}
+}
+
+impl<'a> fmt::Debug for Pretty<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let function = self.0;
+		let code: Vec<Unquoted> = listed_pcs(&function.code).into_iter()
+			.map(|pc| Unquoted(format!("[{}] {}", pc, instruction_text(function, pc).replace('\t', "  "))))
+			.collect();
+		let constants: Vec<Unquoted> = function.constants.iter()
+			.enumerate()
+			.map(|(i, constant)| Unquoted(format!("K{} = {}", i, constant_text(constant))))
+			.collect();
+		let upvalues: Vec<Unquoted> = function.upvalues.iter()
+			.map(|upval| Unquoted(format!("{:?}", upval)))
+			.collect();
+		let protos: Vec<Pretty> = function.protos.iter().map(Pretty).collect();
+		f.debug_struct("Function")
+			.field("source", &function.source)
+			.field("line_start", &function.line_start)
+			.field("line_end", &function.line_end)
+			.field("num_params", &function.num_params)
+			.field("is_vararg", &function.is_vararg)
+			.field("max_stack_size", &function.max_stack_size)
+			.field("code", &code)
+			.field("constants", &constants)
+			.field("upvalues", &upvalues)
+			.field("protos", &protos)
+			.field("debug", &function.debug)
+			.finish()
+	}
+}
+
+/// Compare the listings of two functions as a unified diff.
+///
+/// Each function whose instructions differ is shown in full, with removed
+/// lines marked `-` and added lines `+`. Functions present on only one side
+/// are shown as entirely removed or added. Line numbers and pcs are left
+/// out so that inserting an instruction does not mark every one after it
+/// as changed. Returns an empty string if no instructions differ.
+pub fn diff_listing(a: &Function, b: &Function) -> String {
+	let mut out = String::new();
+	diff_function(&mut out, Some(a), Some(b), "main");
+	if out.is_empty() {
+		return out;
+	}
+	format!("--- a\n+++ b\n{}", out)
+}
+
+fn lines(function: Option<&Function>) -> Vec<String> {
+	function.map_or_else(Vec::new, |f| listed_pcs(&f.code).into_iter().map(|pc| instruction_text(f, pc)).collect())
+}
+
+fn diff_function(out: &mut String, a: Option<&Function>, b: Option<&Function>, path: &str) {
+	let (old, new) = (lines(a), lines(b));
+	if old != new || a.is_none() != b.is_none() {
+		let _ = writeln!(out, "@@ function {} @@", path);
+		// Lines shared at either end are left out of the search for a longest
+		// common subsequence, which is usually all that differs.
+		let prefix = old.iter().zip(&new).take_while(|&(x, y)| x == y).count();
+		let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|&(x, y)| x == y).count();
+		let mut edits = Vec::new();
+		edits.extend(old[..prefix].iter().map(|line| (' ', line)));
+		diff_lines(&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix], &mut edits);
+		edits.extend(old[old.len() - suffix..].iter().map(|line| (' ', line)));
+		for (mark, line) in edits {
+			let _ = writeln!(out, "{}{}", mark, line);
+		}
+	}
+
+	let protos = a.map_or(0, |f| f.protos.len()).max(b.map_or(0, |f| f.protos.len()));
+	for i in 0..protos {
+		diff_function(
+			out,
+			a.and_then(|f| f.protos.get(i)),
+			b.and_then(|f| f.protos.get(i)),
+			&format!("{}.{}", path, i),
+		);
+	}
+}
+
+/// Find the edits turning `old` into `new` by a longest common subsequence,
+/// as lines marked ` `, `-`, or `+`.
+///
+/// This is Hirschberg's algorithm, which splits `old` in half and finds
+/// where the subsequence crosses the split in `new`, so it needs space
+/// linear in the length of the input rather than quadratic.
+fn diff_lines<'a>(old: &'a [String], new: &'a [String], edits: &mut Vec<(char, &'a String)>) {
+	if old.is_empty() || new.is_empty() {
+		edits.extend(old.iter().map(|line| ('-', line)));
+		edits.extend(new.iter().map(|line| ('+', line)));
+		return;
+	}
+	if old.len() == 1 {
+		match new.iter().position(|line| *line == old[0]) {
+			Some(k) => {
+				edits.extend(new[..k].iter().map(|line| ('+', line)));
+				edits.push((' ', &old[0]));
+				edits.extend(new[k + 1..].iter().map(|line| ('+', line)));
+			}
+			None => {
+				edits.push(('-', &old[0]));
+				edits.extend(new.iter().map(|line| ('+', line)));
+			}
+		}
+		return;
+	}
+	let mid = old.len() / 2;
+	let forward = lcs_lengths(old[..mid].iter(), new.iter());
+	let backward = lcs_lengths(old[mid..].iter().rev(), new.iter().rev());
+	let split = (0..=new.len())
+		.max_by_key(|&k| (forward[k] + backward[new.len() - k], usize::MAX - k))
+		.unwrap_or(0);
+	diff_lines(&old[..mid], &new[..split], edits);
+	diff_lines(&old[mid..], &new[split..], edits);
+}
+
+/// The lengths of the longest common subsequences of all of `old` and each
+/// prefix of `new`, indexed by the length of the prefix.
+fn lcs_lengths<'a, I, J>(old: I, new: J) -> Vec<usize>
+	where I: Iterator<Item = &'a String>, J: Iterator<Item = &'a String> + Clone
+{
+	let mut row = vec![0; new.clone().count() + 1];
+	for x in old {
+		// The entry diagonally before the one being computed.
+		let mut diagonal = 0;
+		for (j, y) in new.clone().enumerate() {
+			let above = row[j + 1];
+			row[j + 1] = if x == y { diagonal + 1 } else { above.max(row[j]) };
+			diagonal = above;
+		}
+	}
+	row
+}
+
+/// Render a chunk as a hexdump with each field labeled by its meaning,
+/// such as `sizeof(int) 4` or `main const[3] tag=0x04 "foo"`.
+///
+/// The chunk is walked as by `read_file` but nothing is checked, so a
+/// chunk which fails to load is annotated up to where its structure runs
+/// out, and the bytes after that are labeled `unparsed`. Header fields
+/// which `read_file` would reject are marked `(unexpected)`.
+pub fn annotated_hexdump(bytes: &[u8]) -> String {
+	let mut dump = Hexdump { bytes, pos: 0, fields: vec![] };
+	let _ = dump.chunk();
+	if dump.pos < bytes.len() {
+		dump.fields.push((dump.pos..bytes.len(), "unparsed".to_owned()));
+	}
+
+	let mut out = String::new();
+	for (range, label) in dump.fields {
+		for (i, row) in bytes[range.clone()].chunks(16).enumerate() {
+			let hex: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+			let label = if i == 0 { &label[..] } else { "" };
+			let _ = writeln!(out, "{:08x}  {:<47}  {}", range.start + i * 16, hex.join(" "), label);
+		}
+	}
+	out
+}
+
+struct Hexdump<'a> {
+	bytes: &'a [u8],
+	pos: usize,
+	fields: Vec<(Range<usize>, String)>,
+}
+
+/// Copy the first `N` bytes of a field for `from_ne_bytes`.
+fn array<const N: usize>(bytes: &[u8]) -> [u8; N] {
+	let mut array = [0; N];
+	array.copy_from_slice(&bytes[..N]);
+	array
+}
+
+fn unexpected(ok: bool) -> &'static str {
+	if ok { "" } else { " (unexpected)" }
+}
+
+impl<'a> Hexdump<'a> {
+	/// Label the next `n` bytes, or return `None` if there are not enough.
+	fn field<F: FnOnce(&[u8]) -> String>(&mut self, n: usize, label: F) -> Option<&'a [u8]> {
+		let start = self.pos;
+		let bytes = self.bytes.get(start..start.checked_add(n)?)?;
+		self.pos += n;
+		self.fields.push((start..self.pos, label(bytes)));
+		Some(bytes)
+	}
+
+	fn byte(&mut self, label: &str, expected: Option<usize>) -> Option<u8> {
+		self.field(1, |b| format!("{} {}{}", label, b[0], unexpected(expected.is_none_or(|e| b[0] as usize == e))))
+			.map(|b| b[0])
+	}
+
+	fn int(&mut self, label: &str) -> Option<u32> {
+		self.field(size_of::<Int>(), |b| format!("{} {}", label, i32::from_ne_bytes(array(b))))
+			.map(|b| u32::from_ne_bytes(array(b)))
+	}
+
+	/// Read a string's length prefix and bytes, or `None` for a null string.
+	fn string_bytes(&mut self) -> Option<Option<&'a [u8]>> {
+		let mut rest = self.bytes.get(self.pos..)?;
+		let before = rest.len();
+		let len = V53.read_len::<NativeEndian, _>(&mut rest).ok()?;
+		let prefix = before - rest.len();
+		let text = match len {
+			Some(len) => Some(rest.get(..len)?),
+			None => None,
+		};
+		self.pos += prefix + text.map_or(0, |t| t.len());
+		Some(text)
+	}
+
+	fn string(&mut self, label: &str) -> Option<()> {
+		let start = self.pos;
+		let text = self.string_bytes()?;
+		let text = text.map_or_else(|| "null".to_owned(), escape_lua_string);
+		self.fields.push((start..self.pos, format!("{} {}", label, text)));
+		Some(())
+	}
+
+	fn chunk(&mut self) -> Option<()> {
+		self.field(SIGNATURE.len(), |b| format!("signature{}", unexpected(b == SIGNATURE)))?;
+		self.field(1, |b| format!("version {:#04x}{}", b[0], unexpected(b[0] == VERSION)))?;
+		self.byte("format", Some(FORMAT as usize))?;
+		self.field(DATA.len(), |b| format!("data{}", unexpected(b == DATA)))?;
+		self.byte("sizeof(int)", Some(size_of::<Int>()))?;
+		self.byte("sizeof(size_t)", Some(size_of::<Size>()))?;
+		self.byte("sizeof(Instruction)", Some(size_of::<Instruction>()))?;
+		self.byte("sizeof(Integer)", Some(size_of::<Integer>()))?;
+		self.byte("sizeof(Number)", Some(size_of::<Number>()))?;
+		self.field(8, |b| {
+			let n = i64::from_ne_bytes(array(b));
+			format!("test integer {:#x}{}", n, unexpected(n == TEST_INT))
+		})?;
+		self.field(8, |b| {
+			let n = f64::from_ne_bytes(array(b));
+			format!("test number {}{}", format_lua_number(n), unexpected(n == TEST_NUMBER))
+		})?;
+		self.byte("upvalue count", None)?;
+		self.function("main")
+	}
+
+	fn function(&mut self, path: &str) -> Option<()> {
+		self.string(&format!("{} source", path))?;
+		self.int(&format!("{} line_start", path))?;
+		self.int(&format!("{} line_end", path))?;
+		self.byte(&format!("{} num_params", path), None)?;
+		self.byte(&format!("{} is_vararg", path), None)?;
+		self.byte(&format!("{} max_stack_size", path), None)?;
+
+		for pc in 0..self.int(&format!("{} code count", path))? {
+			self.field(size_of::<Instruction>(), |b| {
+				let instr = u32::from_ne_bytes(array(b));
+				let text = DecodedInstruction::decode(instr).map_or_else(
+					|| "<invalid>".to_owned(),
+					|d| format!("{:?} {}", d.opcode, d.display_operands()),
+				);
+				format!("{} code[{}] {}", path, pc, text)
+			})?;
+		}
+
+		for i in 0..self.int(&format!("{} constant count", path))? {
+			let start = self.pos;
+			let tag = *self.bytes.get(start)?;
+			self.pos += 1;
+			let value = match tag {
+				0x00 => "nil".to_owned(),
+				0x01 => (self.bytes.get(self.pos)? != &0).to_string(),
+				0x03 => format_lua_number(f64::from_ne_bytes(array(self.bytes.get(self.pos..self.pos + 8)?))),
+				0x13 => i64::from_ne_bytes(array(self.bytes.get(self.pos..self.pos + 8)?)).to_string(),
+				0x04 | 0x14 => self.string_bytes()?.map_or_else(|| "null".to_owned(), escape_lua_string),
+				_ => "(unknown type)".to_owned(),
+			};
+			self.pos += match tag {
+				0x01 => 1,
+				0x03 | 0x13 => 8,
+				_ => 0,
+			};
+			self.fields.push((start..self.pos, format!("{} const[{}] tag={:#04x} {}", path, i, tag, value)));
+			if !matches!(tag, 0x00 | 0x01 | 0x03 | 0x13 | 0x04 | 0x14) {
+				return None;
+			}
+		}
+
+		for i in 0..self.int(&format!("{} upvalue count", path))? {
+			self.field(2, |b| format!("{} upvalue[{}] instack={} idx={}", path, i, b[0], b[1]))?;
+		}
+
+		for i in 0..self.int(&format!("{} proto count", path))? {
+			self.function(&format!("{}.{}", path, i))?;
+		}
+
+		for pc in 0..self.int(&format!("{} lineinfo count", path))? {
+			self.int(&format!("{} line[{}]", path, pc))?;
+		}
+		for i in 0..self.int(&format!("{} localvar count", path))? {
+			self.string(&format!("{} localvar[{}] name", path, i))?;
+			self.int(&format!("{} localvar[{}] start_pc", path, i))?;
+			self.int(&format!("{} localvar[{}] end_pc", path, i))?;
+		}
+		for i in 0..self.int(&format!("{} upvalue name count", path))? {
+			self.string(&format!("{} upvalue name[{}]", path, i))?;
+		}
+		Some(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use builder::FunctionBuilder;
+	use bytecode::{Opcode, encode, encode_ax, encode_bx};
+	use super::super::Constant;
+	use super::{diff_lines, diff_listing, listing};
+
+	#[test]
+	fn extra_arg_folded() {
+		let mut builder = FunctionBuilder::new();
+		builder.constant(Constant::ShortString("x".into()));
+		builder.emit(encode_bx(Opcode::LoadKX, 0, 0));
+		builder.emit(encode_ax(Opcode::ExtraArg, 0));
+		builder.emit(encode(Opcode::SetList, 1, 2, 0));
+		builder.emit(encode_ax(Opcode::ExtraArg, 600));
+		builder.emit(encode(Opcode::Return, 0, 1, 0));
+		let text = listing(&builder.build());
+		assert_eq!(text, "function main (5 instructions)\n\
+			\t[0]\t-\tLoadKX    R0 K0\t; \"x\"\n\
+			\t[2]\t-\tSetList   R1 2 600\n\
+			\t[4]\t-\tReturn    R0 1\n");
+	}
+
+	#[test]
+	fn diff_finds_common_lines() {
+		let diff = |old: &str, new: &str| -> Vec<String> {
+			let old: Vec<String> = old.chars().map(|c| c.to_string()).collect();
+			let new: Vec<String> = new.chars().map(|c| c.to_string()).collect();
+			let mut edits = Vec::new();
+			diff_lines(&old, &new, &mut edits);
+			edits.iter().map(|&(mark, line)| format!("{}{}", mark, line)).collect()
+		};
+		// Any longest common subsequence will do, so check that this one is
+		// as long as it can be and that the edits give back both sides.
+		let edits = diff("abcabba", "cbabac");
+		let side = |skip: char| -> String {
+			edits.iter().filter(|edit| !edit.starts_with(skip)).map(|edit| &edit[1..]).collect()
+		};
+		assert_eq!(edits.iter().filter(|edit| edit.starts_with(' ')).count(), 4);
+		assert_eq!(side('+'), "abcabba");
+		assert_eq!(side('-'), "cbabac");
+
+		assert_eq!(diff("abc", ""), ["-a", "-b", "-c"]);
+		assert_eq!(diff("", "ab"), ["+a", "+b"]);
+		assert_eq!(diff("x", "axb"), ["+a", " x", "+b"]);
+		assert_eq!(diff("ab", "cd"), ["-a", "-b", "+c", "+d"]);
+	}
+
+	#[test]
+	fn diff_listing_keeps_ends() {
+		let function = |middle: &[u32]| {
+			let mut builder = FunctionBuilder::new();
+			builder.emit(encode(Opcode::Move, 0, 1, 0));
+			for &instr in middle {
+				builder.emit(instr);
+			}
+			builder.emit(encode(Opcode::Return, 0, 1, 0));
+			builder.build()
+		};
+		let a = function(&[encode(Opcode::Move, 1, 0, 0)]);
+		let b = function(&[encode(Opcode::Move, 2, 0, 0), encode(Opcode::Move, 1, 0, 0)]);
+		assert_eq!(diff_listing(&a, &b), "--- a\n+++ b\n@@ function main @@\n \
+			Move      R0 R1\n\
+			+Move      R2 R0\n \
+			Move      R1 R0\n \
+			Return    R0 1\n");
+		assert_eq!(diff_listing(&a, &a), "");
+	}
+}
@@ -0,0 +1,40 @@
+//! Plain-text exports of bytecode for external tools.
+
+use std::fmt::Write;
+
+use super::bytecode::DecodedInstruction;
+use super::Function;
+
+/// Export the instructions of a function and its nested functions as CSV.
+///
+/// The first line is the header `function,pc,raw,opcode,a,b,c`. Each
+/// following line is one instruction, with:
+///
+/// * `function`: the path of the function, `main` for the main function
+///   and for example `main.0.2` for the third function nested in the
+///   first function nested in it.
+/// * `pc`: the index of the instruction in its function.
+/// * `raw`: the encoded instruction as a decimal number.
+/// * `opcode`: the name Lua uses for the opcode, such as `GETTABUP`.
+/// * `a`, `b`, `c`: the operands, where `b` holds `Bx` or `sBx` and `a`
+///   holds `Ax` for formats with those, and unused operands are zero.
+///
+/// An instruction with an invalid opcode has empty `opcode`, `a`, `b`, and
+/// `c` fields. Functions are listed depth-first, parents first.
+pub fn instructions_csv(function: &Function) -> String {
+	let mut out = String::from("function,pc,raw,opcode,a,b,c\n");
+	export_function(&mut out, function, "main");
+	out
+}
+
+fn export_function(out: &mut String, function: &Function, path: &str) {
+	for (pc, &instr) in function.code.iter().enumerate() {
+		let _ = match DecodedInstruction::decode(instr) {
+			Some(d) => writeln!(out, "{},{},{},{},{},{},{}", path, pc, instr, d.opcode.info().name, d.a, d.b, d.c),
+			None => writeln!(out, "{},{},{},,,,", path, pc, instr),
+		};
+	}
+	for (i, proto) in function.sub_functions() {
+		export_function(out, proto, &format!("{}.{}", path, i));
+	}
+}
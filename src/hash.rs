@@ -0,0 +1,338 @@
+//! Content hashing for deduplication and reproducible output.
+
+use super::bytecode::{Opcode, encode_bx, get_opcode, get_a, get_bx};
+use super::{Constant, Upvalue, Function};
+#[cfg(feature = "sha2")]
+use super::{Layout, WriteOptions, write_file_with_options};
+
+/// 64-bit FNV-1a, chosen over `DefaultHasher` because its output is
+/// specified and so stays the same across runs and Rust versions.
+struct Fnv(u64);
+
+impl Fnv {
+	fn bytes(&mut self, bytes: &[u8]) {
+		for &byte in bytes {
+			self.0 ^= byte as u64;
+			self.0 = self.0.wrapping_mul(0x100000001b3);
+		}
+	}
+
+	fn u8(&mut self, value: u8) {
+		self.bytes(&[value]);
+	}
+
+	fn u64(&mut self, value: u64) {
+		self.bytes(&value.to_le_bytes());
+	}
+
+	fn str(&mut self, value: &str) {
+		self.u64(value.len() as u64);
+		self.bytes(value.as_bytes());
+	}
+}
+
+impl Function {
+	/// A hash of this function's behavior, for finding duplicates.
+	///
+	/// Covers the code, constants, upvalues, parameters, stack size, and
+	/// nested functions, but not the source name, line numbers, or other
+	/// debug information. Floats are hashed by their bits. The result does
+	/// not depend on the platform or the run.
+	pub fn content_hash(&self) -> u64 {
+		let mut hasher = Fnv(0xcbf29ce484222325);
+		self.hash_content(&mut hasher);
+		hasher.0
+	}
+
+	/// Sort the nested functions of this function, and of each nested
+	/// function, by `content_hash`, renumbering `Closure` operands to match.
+	///
+	/// Two functions which differ only in the order of their nested
+	/// functions become identical.
+	pub fn canonicalize_protos(&mut self) {
+		for proto in &mut self.protos {
+			proto.canonicalize_protos();
+		}
+		let mut order: Vec<(u64, usize)> = self.protos.iter()
+			.map(Function::content_hash)
+			.enumerate()
+			.map(|(i, hash)| (hash, i))
+			.collect();
+		order.sort_by_key(|&(hash, _)| hash);
+
+		let mut new_index = vec![0; order.len()];
+		for (new, &(_, old)) in order.iter().enumerate() {
+			new_index[old] = new as u32;
+		}
+		for instr in &mut self.code {
+			if get_opcode(*instr) == Some(Opcode::Closure) {
+				if let Some(&new) = new_index.get(get_bx(*instr) as usize) {
+					*instr = encode_bx(Opcode::Closure, get_a(*instr), new);
+				}
+			}
+		}
+		let mut protos: Vec<Option<Function>> = self.protos.drain(..).map(Some).collect();
+		self.protos = order.iter().map(|&(_, old)| protos[old].take().unwrap()).collect();
+	}
+
+	fn hash_content(&self, h: &mut Fnv) {
+		h.u8(self.num_params);
+		h.u8(self.is_vararg as u8);
+		h.u8(self.max_stack_size);
+
+		h.u64(self.code.len() as u64);
+		for &instr in &self.code {
+			h.u64(instr as u64);
+		}
+
+		h.u64(self.constants.len() as u64);
+		for cons in &self.constants {
+			match *cons {
+				Constant::Nil => h.u8(0x00),
+				Constant::Boolean(b) => {
+					h.u8(0x01);
+					h.u8(b as u8);
+				}
+				Constant::Float(n) => {
+					h.u8(0x03);
+					h.u64(n.to_bits());
+				}
+				Constant::Int(n) => {
+					h.u8(0x13);
+					h.u64(n as u64);
+				}
+				Constant::ShortString(ref s) => {
+					h.u8(0x04);
+					h.str(s);
+				}
+				Constant::LongString(ref s) => {
+					h.u8(0x14);
+					h.str(s);
+				}
+				Constant::Raw { tag, ref bytes } => {
+					h.u8(tag);
+					h.u64(bytes.len() as u64);
+					h.bytes(bytes);
+				}
+			}
+		}
+
+		h.u64(self.upvalues.len() as u64);
+		for upval in &self.upvalues {
+			match *upval {
+				Upvalue::Outer(idx) => h.bytes(&[0, idx]),
+				Upvalue::Stack(idx) => h.bytes(&[1, idx]),
+			}
+		}
+
+		h.u64(self.protos.len() as u64);
+		for proto in &self.protos {
+			proto.hash_content(h);
+		}
+	}
+}
+
+/// A SHA-256 digest of this function's canonical serialization, for
+/// checking the integrity of compiled assets.
+///
+/// The function is serialized with `Layout::lua53_64`, whatever the host,
+/// and with NaN constants replaced as by `canonicalize_nans`, so the digest
+/// is the same on every host and for functions which differ only in NaN
+/// payloads. Unlike `content_hash`, it covers debug information. Use
+/// `canonicalize_protos` first to also ignore the order of nested
+/// functions.
+///
+/// # Panics
+///
+/// Panics if the function cannot be serialized, such as if it has more
+/// than `u32::MAX` instructions.
+#[cfg(feature = "sha2")]
+pub fn digest_sha256(f: &Function) -> [u8; 32] {
+	let mut canonical = f.clone();
+	canonical.canonicalize_nans();
+	let mut sha = Sha256::new();
+	let options = WriteOptions { layout: Layout::lua53_64(), ..WriteOptions::default() };
+	write_file_with_options(&mut sha, &canonical, &options).expect("function cannot be serialized");
+	sha.finish()
+}
+
+/// SHA-256, as specified in FIPS 180-4, fed as a writer so the
+/// serialization is hashed as it is produced.
+#[cfg(feature = "sha2")]
+struct Sha256 {
+	state: [u32; 8],
+	block: [u8; 64],
+	filled: usize,
+	length: u64,
+}
+
+#[cfg(feature = "sha2")]
+const K: [u32; 64] = [
+	0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+	0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+	0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+	0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+	0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+	0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+	0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+	0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+#[cfg(feature = "sha2")]
+impl Sha256 {
+	fn new() -> Sha256 {
+		Sha256 {
+			state: [
+				0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+				0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+			],
+			block: [0; 64],
+			filled: 0,
+			length: 0,
+		}
+	}
+
+	fn update(&mut self, mut bytes: &[u8]) {
+		self.length += bytes.len() as u64;
+		while !bytes.is_empty() {
+			let n = (64 - self.filled).min(bytes.len());
+			self.block[self.filled..self.filled + n].copy_from_slice(&bytes[..n]);
+			self.filled += n;
+			bytes = &bytes[n..];
+			if self.filled == 64 {
+				self.compress();
+				self.filled = 0;
+			}
+		}
+	}
+
+	fn compress(&mut self) {
+		let mut w = [0u32; 64];
+		for (i, chunk) in self.block.chunks(4).enumerate() {
+			w[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+		}
+		for i in 16..64 {
+			let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+			let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+			w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+		}
+
+		let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+		for i in 0..64 {
+			let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+			let ch = (e & f) ^ (!e & g);
+			let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+			let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+			let maj = (a & b) ^ (a & c) ^ (b & c);
+			let t2 = s0.wrapping_add(maj);
+			h = g;
+			g = f;
+			f = e;
+			e = d.wrapping_add(t1);
+			d = c;
+			c = b;
+			b = a;
+			a = t1.wrapping_add(t2);
+		}
+		for (state, value) in self.state.iter_mut().zip(&[a, b, c, d, e, f, g, h]) {
+			*state = state.wrapping_add(*value);
+		}
+	}
+
+	fn finish(mut self) -> [u8; 32] {
+		let bits = self.length * 8;
+		self.update(&[0x80]);
+		while self.filled != 56 {
+			self.update(&[0]);
+		}
+		self.update(&bits.to_be_bytes());
+
+		let mut digest = [0; 32];
+		for (chunk, word) in digest.chunks_mut(4).zip(&self.state) {
+			chunk.copy_from_slice(&word.to_be_bytes());
+		}
+		digest
+	}
+}
+
+#[cfg(feature = "sha2")]
+impl std::io::Write for Sha256 {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.update(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
+}
+
+#[cfg(all(test, feature = "sha2"))]
+mod tests {
+	use std::io::Write;
+
+	use builder::FunctionBuilder;
+	use bytecode::{Opcode, encode};
+	use super::super::Constant;
+	use super::{Sha256, digest_sha256};
+
+	fn hex(digest: [u8; 32]) -> String {
+		digest.iter().map(|b| format!("{:02x}", b)).collect()
+	}
+
+	fn sha256(bytes: &[u8]) -> String {
+		let mut sha = Sha256::new();
+		sha.update(bytes);
+		hex(sha.finish())
+	}
+
+	#[test]
+	fn known_answers() {
+		// The examples of FIPS 180-4, and the empty message.
+		assert_eq!(sha256(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+		assert_eq!(sha256(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+		assert_eq!(
+			sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+			"248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+		);
+		assert_eq!(
+			sha256(b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu"),
+			"cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1",
+		);
+	}
+
+	#[test]
+	fn block_boundaries() {
+		// A million 'a's, written in pieces which straddle blocks.
+		let mut sha = Sha256::new();
+		let piece = [b'a'; 1000];
+		for &len in [1, 63, 64, 65, 807].iter().cycle().take(5000) {
+			sha.write_all(&piece[..len]).unwrap();
+		}
+		assert_eq!(sha.length, 5000 / 5 * 1000);
+		let mut rest = 1_000_000 - sha.length as usize;
+		while rest > 0 {
+			let n = rest.min(piece.len());
+			sha.write_all(&piece[..n]).unwrap();
+			rest -= n;
+		}
+		assert_eq!(hex(sha.finish()), "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+		// Messages whose padding just fits in, or just spills out of, the
+		// last block.
+		assert_eq!(sha256(&[b'a'; 55]), "9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318");
+		assert_eq!(sha256(&[b'a'; 56]), "b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a");
+		assert_eq!(sha256(&[b'a'; 64]), "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb");
+	}
+
+	#[test]
+	fn digest_is_fixed() {
+		let mut builder = FunctionBuilder::main_chunk();
+		builder.constant(Constant::ShortString("hello".into()));
+		builder.emit(encode(Opcode::Return, 0, 1, 0));
+		let mut function = builder.build();
+		function.source = "@digest.lua".into();
+		// Checked against the SHA-256 of the chunk assembled by hand in the
+		// lua53_64 layout, which every host must reproduce.
+		assert_eq!(hex(digest_sha256(&function)), "255c1d7b88b9af95cdfbab88dff61ae433f7eced17d8ae8b0093d05a5a30d804");
+	}
+}
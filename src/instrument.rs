@@ -0,0 +1,147 @@
+//! Insertion of extra code into existing functions.
+
+use std::mem;
+
+use super::analysis::reg_effects;
+use super::builder::{ENV, FunctionBuilder};
+use super::{Function, Upvalue};
+
+/// Insert code at the start of a function and each of its nested functions.
+///
+/// `build` is called once per function with a builder holding that
+/// function with its code removed, and the code it emits is placed before
+/// the original code. Constants and upvalues it adds are kept. Only the
+/// registers from `num_params` up are free for the prologue to use, and
+/// `max_stack_size` is raised to cover any it does.
+///
+/// A nested function which does not capture `_ENV` is given an upvalue
+/// capturing its parent's while the prologue is built, so that prologues can
+/// use `emit_global_get` and `emit_global_set` throughout the tree. The
+/// upvalue is removed again if neither the prologue nor a function nested
+/// in it uses it. `_ENV` is found by its upvalue name, so in a function
+/// without one, such as a stripped main chunk, those methods still panic.
+///
+/// Jumps need no adjustment, being relative, and debug information is
+/// updated as by `Debug::splice`, with the prologue attributed to the
+/// function's first line.
+pub fn add_prologue<F: Fn(&mut FunctionBuilder)>(function: &mut Function, build: F) {
+	add_prologue_with(function, &build, None);
+}
+
+fn add_prologue_with<F: Fn(&mut FunctionBuilder)>(function: &mut Function, build: &F, outer_env: Option<u8>) {
+	let stripped = function.debug.upvalues.is_empty() && !function.upvalues.is_empty();
+	let mut added_env = None;
+	let env = match function.debug.upvalues.iter().position(|name| name == ENV) {
+		Some(index) => Some(index as u8),
+		None => outer_env.map(|outer| {
+			// A stripped function's other upvalues are named only until the
+			// names are cleared again below.
+			function.debug.upvalues.resize(function.upvalues.len(), String::new());
+			function.upvalues.push(Upvalue::Outer(outer));
+			function.debug.upvalues.push(ENV.to_owned());
+			let index = (function.upvalues.len() - 1) as u8;
+			added_env = Some(index);
+			index
+		}),
+	};
+	for proto in &mut function.protos {
+		add_prologue_with(proto, build, env);
+	}
+
+	let mut taken = mem::replace(function, FunctionBuilder::new().build());
+	let code = mem::take(&mut taken.code);
+	let lineinfo = mem::take(&mut taken.debug.lineinfo);
+	let mut builder = FunctionBuilder::from_function(taken);
+	build(&mut builder);
+	*function = builder.build();
+
+	let prologue = function.code.len();
+	for pc in 0..prologue {
+		let fx = reg_effects(function, pc);
+		for &reg in fx.reads.iter().chain(fx.writes.iter()) {
+			function.max_stack_size = function.max_stack_size.max(reg.saturating_add(1));
+		}
+	}
+	function.code.extend(code);
+	function.debug.lineinfo = lineinfo;
+	if let Some(index) = added_env {
+		let last = function.upvalues.len() - 1;
+		if index as usize == last && !function.used_upvalues()[last] {
+			function.upvalues.pop();
+			function.debug.upvalues.pop();
+		}
+	}
+	if stripped {
+		function.debug.upvalues.clear();
+	}
+	let line = function.debug.lineinfo.first().cloned().unwrap_or(function.line_start);
+	function.debug.splice(0, &vec![line; prologue]);
+}
+
+#[cfg(test)]
+mod tests {
+	use builder::FunctionBuilder;
+	use bytecode::{Opcode, encode, encode_sbx};
+	use super::super::{Constant, Function, LocalVar, Upvalue};
+	use super::add_prologue;
+
+	fn sample() -> Function {
+		// function(p) if p then p = nil end end
+		let mut nested = FunctionBuilder::new();
+		nested.emit(encode(Opcode::Test, 0, 0, 0));
+		nested.emit(encode_sbx(Opcode::Jump, 0, 1));
+		nested.emit(encode(Opcode::LoadNil, 0, 0, 0));
+		nested.emit(encode(Opcode::Return, 0, 1, 0));
+		let mut nested = nested.build();
+		nested.num_params = 1;
+		nested.line_start = 9;
+		nested.debug.lineinfo = vec![10, 10, 11, 12];
+		nested.debug.localvars.push(LocalVar { name: "p".into(), start_pc: 0, end_pc: 4 });
+
+		let mut main = FunctionBuilder::main_chunk();
+		main.emit(encode(Opcode::Return, 0, 1, 0));
+		let mut main = main.build();
+		main.protos.push(nested);
+		main
+	}
+
+	/// Count calls by calling the global `hit`.
+	fn call_hit(builder: &mut FunctionBuilder) {
+		let reg = builder.function_mut().num_params;
+		let name = builder.constant(Constant::ShortString("hit".into()));
+		builder.emit_global_get(reg, name).unwrap();
+		builder.emit(encode(Opcode::Call, reg, 1, 1));
+	}
+
+	#[test]
+	fn nested_prologue() {
+		let original = sample();
+		let mut function = original.clone();
+		add_prologue(&mut function, call_hit);
+
+		assert_eq!(function.upvalues, original.upvalues);
+		assert_eq!(function.code[2..], original.code[..]);
+
+		let nested = &function.protos[0];
+		// _ENV is captured from the main function for the prologue.
+		assert_eq!(nested.upvalues, [Upvalue::Outer(0)]);
+		assert_eq!(nested.debug.upvalues, ["_ENV"]);
+		assert_eq!(nested.code[..2], [encode(Opcode::GetTabUp, 1, 0, 0x100), encode(Opcode::Call, 1, 1, 1)]);
+		// The jump is unchanged, since it is relative.
+		assert_eq!(nested.code[2..], original.protos[0].code[..]);
+		assert_eq!(nested.max_stack_size, 2);
+		assert_eq!(nested.debug.lineinfo, [10, 10, 10, 10, 11, 12]);
+		assert_eq!((nested.debug.localvars[0].start_pc, nested.debug.localvars[0].end_pc), (2, 6));
+	}
+
+	#[test]
+	fn unused_env_removed() {
+		let mut function = sample();
+		add_prologue(&mut function, |builder| {
+			builder.emit(encode(Opcode::LoadNil, 0, 0, 0));
+		});
+		assert!(function.protos[0].upvalues.is_empty());
+		assert!(function.protos[0].debug.upvalues.is_empty());
+		assert_eq!(function.protos[0].code.len(), 5);
+	}
+}
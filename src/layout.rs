@@ -0,0 +1,163 @@
+//! Descriptions of the sizes and byte order declared by chunk headers.
+//!
+//! `ReadOptions::layout` and `WriteOptions::layout` choose the layout of
+//! the chunks read and written, by default that of the host. Functions
+//! which choose a byte order themselves, such as `read_file_le`, use the
+//! host's sizes.
+
+use std::io;
+use std::mem::size_of;
+
+use super::{Int, Size, Instruction, Integer, Number};
+
+/// The sizes of the C types and the byte order of a bytecode chunk, as
+/// declared by its header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Layout {
+	/// The size of C's `int`, used for line numbers.
+	pub int_size: u8,
+	/// The size of C's `size_t`, used for string lengths.
+	pub size_t_size: u8,
+	/// The size of an instruction.
+	pub instruction_size: u8,
+	/// The size of `lua_Integer`.
+	pub integer_size: u8,
+	/// The size of `lua_Number`. Only 8, C's `double`, is supported.
+	pub number_size: u8,
+	/// Whether multi-byte values are big-endian.
+	pub big_endian: bool,
+}
+
+/// The layout used by `read_file` and `write_file`, that of the host.
+pub const DEFAULT_LAYOUT: Layout = Layout::host();
+
+impl Default for Layout {
+	fn default() -> Layout {
+		DEFAULT_LAYOUT
+	}
+}
+
+impl Layout {
+	/// The layout of the host, with the sizes of this crate's type aliases
+	/// and its native byte order.
+	pub const fn host() -> Layout {
+		Layout {
+			int_size: size_of::<Int>() as u8,
+			size_t_size: size_of::<Size>() as u8,
+			instruction_size: size_of::<Instruction>() as u8,
+			integer_size: size_of::<Integer>() as u8,
+			number_size: size_of::<Number>() as u8,
+			big_endian: cfg!(target_endian = "big"),
+		}
+	}
+
+	/// The layout of a stock Lua 5.3 on a 64-bit little-endian platform,
+	/// such as x86-64 Linux. Writing with it gives the same bytes on every
+	/// host.
+	pub const fn lua53_64() -> Layout {
+		Layout {
+			int_size: 4,
+			size_t_size: 8,
+			instruction_size: 4,
+			integer_size: 8,
+			number_size: 8,
+			big_endian: false,
+		}
+	}
+
+	/// The layout of a stock Lua 5.3 on a 32-bit little-endian platform,
+	/// which differs from `lua53_64` only in the size of `size_t`.
+	pub const fn lua53_32() -> Layout {
+		Layout { size_t_size: 4, ..Layout::lua53_64() }
+	}
+
+	/// Check that chunks of this layout can be read and written.
+	///
+	/// `int` and `size_t` may be 4 or 8 bytes. Instructions must be 4
+	/// bytes, though the reader accepts narrower or wider ones whose values
+	/// fit, and `lua_Integer` and `lua_Number` must be 8 bytes, as this
+	/// crate's types are.
+	pub(crate) fn check(&self) -> io::Result<()> {
+		let supported = matches!(self.int_size, 4 | 8) && matches!(self.size_t_size, 4 | 8) &&
+			self.instruction_size == size_of::<Instruction>() as u8 &&
+			self.integer_size == size_of::<Integer>() as u8 && self.number_size == size_of::<Number>() as u8;
+		if supported {
+			Ok(())
+		} else {
+			Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported layout {:?}", self)))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use builder::FunctionBuilder;
+	use bytecode::{Opcode, encode};
+	use super::super::{
+		Constant, Function, LocalVar, ReadOptions, WriteOptions, read_file, read_file_with_options,
+		write_file_with_options,
+	};
+	use super::Layout;
+
+	fn sample() -> Function {
+		let mut builder = FunctionBuilder::main_chunk();
+		builder.constant(Constant::ShortString("x".repeat(300)));
+		builder.emit(encode(Opcode::Return, 0, 1, 0));
+		let mut function = builder.build();
+		function.source = "@layout.lua".into();
+		function.line_end = -7;
+		function.debug.lineinfo = vec![3];
+		function.debug.localvars.push(LocalVar { name: "v".into(), start_pc: 0, end_pc: 1 });
+		function
+	}
+
+	fn write(function: &Function, layout: Layout) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		let options = WriteOptions { layout, ..WriteOptions::default() };
+		write_file_with_options(&mut bytes, function, &options).unwrap();
+		bytes
+	}
+
+	#[test]
+	fn roundtrip_layouts() {
+		let function = sample();
+		let big = Layout { big_endian: true, int_size: 8, ..Layout::lua53_32() };
+		for &layout in &[Layout::lua53_64(), Layout::lua53_32(), big] {
+			let bytes = write(&function, layout);
+			assert_eq!(bytes[12..17], [layout.int_size, layout.size_t_size, 4, 8, 8]);
+			let options = ReadOptions { layout, ..ReadOptions::default() };
+			assert_eq!(read_file_with_options(&bytes[..], &options).unwrap(), function);
+		}
+	}
+
+	#[test]
+	fn fixed_output() {
+		let bytes = write(&sample(), Layout::lua53_64());
+		// The test integer, then the long string's 8-byte length after the
+		// main function's source.
+		assert_eq!(bytes[17..25], 0x5678i64.to_le_bytes());
+		let long = bytes.windows(9).position(|w| w == [0xff, 45, 1, 0, 0, 0, 0, 0, 0]);
+		assert!(long.is_some());
+		if Layout::host() == Layout::lua53_64() {
+			assert_eq!(read_file(&bytes[..]).unwrap(), sample());
+		}
+	}
+
+	#[test]
+	fn mismatch() {
+		let bytes = write(&sample(), Layout::lua53_32());
+		let options = ReadOptions { layout: Layout::lua53_64(), ..ReadOptions::default() };
+		let err = read_file_with_options(&bytes[..], &options).unwrap_err();
+		assert!(err.to_string().contains("sizeof(size_t)"), "{}", err);
+		let options = ReadOptions { layout: Layout { big_endian: true, ..Layout::lua53_32() }, ..ReadOptions::default() };
+		assert!(read_file_with_options(&bytes[..], &options).is_err());
+	}
+
+	#[test]
+	fn unsupported() {
+		let options = WriteOptions { layout: Layout { number_size: 4, ..Layout::host() }, ..WriteOptions::default() };
+		assert!(write_file_with_options(Vec::new(), &sample(), &options).is_err());
+		let options = WriteOptions { layout: Layout { int_size: 2, ..Layout::host() }, ..WriteOptions::default() };
+		assert!(write_file_with_options(Vec::new(), &sample(), &options).is_err());
+	}
+}
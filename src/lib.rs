@@ -5,21 +5,53 @@
 extern crate byteorder;
 extern crate libc;
 
+pub mod analysis;
+pub mod builder;
 pub mod bytecode;
+pub mod disasm;
+pub mod export;
+pub mod instrument;
+pub mod text;
+#[cfg(feature = "testutil")]
+pub mod testutil;
+pub mod optimize;
+pub mod patch;
+pub mod prelude;
+pub mod string_len;
+pub mod v52;
+pub mod v54;
 mod write;
 mod read;
+mod validate;
+mod hash;
+mod layout;
+mod permute;
+mod strip_info;
 
-pub use write::write_file;
-pub use read::read_file;
+pub use write::{
+	limited_write_file, to_bytes, write_file, write_file_be, write_file_le, write_file_with_options,
+	write_file_with_trailer, write_framed, write_to_slice, OutputTooLarge, TooManyItems, WriteOptions,
+};
+pub use layout::{Layout, DEFAULT_LAYOUT};
+pub use validate::ValidateOptions;
+pub use strip_info::{read_file_with_strip_info, write_file_with_strip_info, StripInfo};
+#[cfg(feature = "sha2")]
+pub use hash::digest_sha256;
+pub use read::{
+	read_all_chunks, read_any, read_file, read_file_be, read_file_le, read_file_lenient,
+	read_file_lenient_with_options, read_file_no_debug, read_file_with_offsets, read_file_with_options,
+	read_file_with_ranges, read_file_with_trailer, read_framed, DEFAULT_MAX_CHUNK_LEN,
+	ChunkParser, CorruptHeader, Diagnostic, LuaVersion, OffsetMap, ReadOptions, UnsupportedNumberSize,
+};
 
 /// Signature to mark Lua bytecode files.
-pub const SIGNATURE: &'static [u8] = b"\x1bLua";
+pub const SIGNATURE: &[u8] = b"\x1bLua";
 /// The Lua version, in the form `(MAJOR << 4) | MINOR`.
 pub const VERSION: u8 = 0x53;
 /// The Lua bytecode format.
 pub const FORMAT: u8 = 0;
 /// Test text to catch translation errors.
-pub const DATA: &'static [u8] = b"\x19\x93\r\n\x1a\n";
+pub const DATA: &[u8] = b"\x19\x93\r\n\x1a\n";
 /// A test integer to know endianness.
 pub const TEST_INT: Integer = 0x5678;
 /// A test floating-point number to know endianness.
@@ -51,6 +83,56 @@ pub enum Constant {
 	ShortString(String),
 	/// A long string. Behaves the same as `ShortString`.
 	LongString(String),
+	/// A constant of a type this crate does not know, kept byte for byte.
+	///
+	/// Read only when `ReadOptions::raw_constant_sizes` gives the size of
+	/// its type, and written back unchanged, so chunks from a slightly
+	/// newer Lua can be edited without losing such constants.
+	Raw {
+		/// The type tag.
+		tag: u8,
+		/// The bytes following the tag.
+		bytes: Vec<u8>,
+	},
+}
+
+/// The length above which Lua stores strings as long strings.
+pub const MAX_SHORT_LEN: usize = 40;
+
+impl From<bool> for Constant {
+	fn from(b: bool) -> Constant {
+		Constant::Boolean(b)
+	}
+}
+
+impl From<Integer> for Constant {
+	fn from(n: Integer) -> Constant {
+		Constant::Int(n)
+	}
+}
+
+impl From<Number> for Constant {
+	fn from(n: Number) -> Constant {
+		Constant::Float(n)
+	}
+}
+
+/// Strings become `ShortString` or `LongString` by length, as in Lua.
+impl From<String> for Constant {
+	fn from(s: String) -> Constant {
+		if s.len() <= MAX_SHORT_LEN {
+			Constant::ShortString(s)
+		} else {
+			Constant::LongString(s)
+		}
+	}
+}
+
+/// Strings become `ShortString` or `LongString` by length, as in Lua.
+impl From<&str> for Constant {
+	fn from(s: &str) -> Constant {
+		Constant::from(s.to_owned())
+	}
 }
 
 /// An entry in the upvalue table.
@@ -62,6 +144,26 @@ pub enum Upvalue {
 	Stack(u8),
 }
 
+impl Upvalue {
+	/// Decode an upvalue from the pair of bytes which represent it in
+	/// bytecode: a flag which is nonzero for `Stack`, and the index.
+	pub fn from_raw(stack: u8, idx: u8) -> Upvalue {
+		match stack {
+			0 => Upvalue::Outer(idx),
+			_ => Upvalue::Stack(idx),
+		}
+	}
+
+	/// Encode this upvalue as the pair of bytes which represent it in
+	/// bytecode, `(0, idx)` for `Outer` and `(1, idx)` for `Stack`.
+	pub fn to_raw(&self) -> (u8, u8) {
+		match *self {
+			Upvalue::Outer(idx) => (0, idx),
+			Upvalue::Stack(idx) => (1, idx),
+		}
+	}
+}
+
 /// An entry in the local variable debug table.
 #[derive(Clone, Debug, PartialEq)]
 pub struct LocalVar {
@@ -93,12 +195,89 @@ impl Debug {
 			upvalues: vec![],
 		}
 	}
+
+	/// Append the line number of the next instruction.
+	pub fn push_line(&mut self, line: Int) {
+		self.lineinfo.push(line);
+	}
+
+	/// Look up the line number of the instruction at `pc`, if present.
+	pub fn line_for_pc(&self, pc: usize) -> Option<Int> {
+		self.lineinfo.get(pc).cloned()
+	}
+
+	/// Update the debug info for `lines.len()` instructions having been
+	/// inserted into the code at `at`, with the given line numbers.
+	///
+	/// Local variable scopes which start at or after `at`, or end after it,
+	/// are moved to follow the inserted code. If there is no line info, only
+	/// the scopes are updated.
+	pub fn splice(&mut self, at: usize, lines: &[Int]) {
+		if !self.lineinfo.is_empty() {
+			self.lineinfo.splice(at..at, lines.iter().cloned());
+		}
+		let at = at as Int;
+		let count = lines.len() as Int;
+		for var in &mut self.localvars {
+			if var.start_pc >= at {
+				var.start_pc += count;
+			}
+			if var.end_pc > at {
+				var.end_pc += count;
+			}
+		}
+	}
+}
+
+/// The resources needed by a function and its nested functions, as found by
+/// `Function::resource_summary`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResourceSummary {
+	/// The largest `max_stack_size` of any function.
+	pub max_stack_size: u8,
+	/// The number of constants in all functions.
+	pub total_constants: usize,
+	/// The number of instructions in all functions.
+	pub total_instructions: usize,
+	/// The maximum depth of nested functions, as by `max_nesting_depth`.
+	pub max_nesting_depth: usize,
+	/// Whether any function has a `VarArg` instruction.
+	pub uses_varargs: bool,
+	/// Whether any function has a `Call`, `TailCall`, or `TForCall`.
+	pub makes_calls: bool,
+}
+
+/// The shape of a function and its nested functions, without their code or
+/// constants, as found by `Function::signature_tree`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignatureNode {
+	/// The number of fixed parameters the function takes.
+	pub num_params: u8,
+	/// Whether the function accepts a variable number of arguments.
+	pub is_vararg: bool,
+	/// The source filename of the function, inherited from the enclosing
+	/// function if it has none of its own.
+	pub source: String,
+	/// The start line number of the function.
+	pub line_start: Int,
+	/// The end line number of the function.
+	pub line_end: Int,
+	/// The nested functions, in order.
+	pub children: Vec<SignatureNode>,
 }
 
 /// A Lua function prototype.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Function {
 	/// The source filename of the function. May be empty.
+	///
+	/// Lua writes a null source for nested functions which share their
+	/// parent's, and for every function when stripping debug information,
+	/// though other tools may keep the main function's source when
+	/// stripping. A null source is read as empty and an empty source is
+	/// written as null, so an empty source means "same as the parent" or
+	/// "stripped". Use `has_debug` rather than this to tell whether a chunk
+	/// was stripped, and `normalize_sources` to clear repeated sources.
 	pub source: String,
 	/// The start line number of the function.
 	pub line_start: Int,
@@ -121,3 +300,569 @@ pub struct Function {
 	/// Debugging information for the function.
 	pub debug: Debug,
 }
+
+impl Function {
+	/// Wrap bare code and constants in a function which can be loaded as a
+	/// main chunk.
+	///
+	/// The function has a single upvalue for `_ENV`, no nested functions,
+	/// and no debug information, and is not vararg.
+	pub fn from_code(code: Vec<Instruction>, constants: Vec<Constant>, num_params: u8, max_stack_size: u8) -> Function {
+		Function {
+			source: String::new(),
+			line_start: 0,
+			line_end: 0,
+			num_params,
+			is_vararg: false,
+			max_stack_size,
+			code,
+			constants,
+			upvalues: vec![Upvalue::Stack(0)],
+			protos: vec![],
+			debug: Debug::none(),
+		}
+	}
+
+	/// Decode every instruction of this function, for editing with
+	/// `encode_code`.
+	///
+	/// Fails if any instruction has an invalid opcode, since it cannot be
+	/// represented as a `DecodedInstruction`.
+	pub fn decode_code(&self) -> std::io::Result<Vec<bytecode::DecodedInstruction>> {
+		self.code.iter().enumerate()
+			.map(|(pc, &instr)| bytecode::DecodedInstruction::decode(instr).ok_or_else(|| std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				format!("invalid opcode {} at pc {}", instr & 0x3f, pc),
+			)))
+			.collect()
+	}
+
+	/// Replace the code of this function by encoding each of `decoded`.
+	///
+	/// Encoding the result of `decode_code` gives back the same code. If the
+	/// number of instructions changes, `debug.lineinfo` must be updated to
+	/// match.
+	pub fn encode_code(&mut self, decoded: &[bytecode::DecodedInstruction]) {
+		self.code = decoded.iter().map(bytecode::DecodedInstruction::encode).collect();
+	}
+
+	/// The number of instructions in this function and all nested functions.
+	pub fn total_instructions(&self) -> usize {
+		self.code.len() + self.protos.iter().map(Function::total_instructions).sum::<usize>()
+	}
+
+	/// The number of function prototypes nested within this function, at
+	/// any depth. Does not count this function itself.
+	pub fn proto_count(&self) -> usize {
+		self.protos.len() + self.protos.iter().map(Function::proto_count).sum::<usize>()
+	}
+
+	/// The maximum depth of nested function prototypes. A function with no
+	/// nested prototypes has a depth of zero.
+	pub fn max_nesting_depth(&self) -> usize {
+		self.protos.iter().map(|p| p.max_nesting_depth() + 1).max().unwrap_or(0)
+	}
+
+	/// Summarize the resources needed by this function and its nested
+	/// functions, such as to check them against limits before loading.
+	pub fn resource_summary(&self) -> ResourceSummary {
+		let mut summary = ResourceSummary {
+			max_stack_size: self.max_stack_size,
+			total_constants: self.constants.len(),
+			total_instructions: self.code.len(),
+			max_nesting_depth: 0,
+			uses_varargs: false,
+			makes_calls: false,
+		};
+		for &instr in &self.code {
+			match bytecode::get_opcode(instr) {
+				Some(bytecode::Opcode::VarArg) => summary.uses_varargs = true,
+				Some(bytecode::Opcode::Call) | Some(bytecode::Opcode::TailCall) |
+				Some(bytecode::Opcode::TForCall) => summary.makes_calls = true,
+				_ => {}
+			}
+		}
+		for proto in &self.protos {
+			let nested = proto.resource_summary();
+			summary.max_stack_size = summary.max_stack_size.max(nested.max_stack_size);
+			summary.total_constants += nested.total_constants;
+			summary.total_instructions += nested.total_instructions;
+			summary.max_nesting_depth = summary.max_nesting_depth.max(nested.max_nesting_depth + 1);
+			summary.uses_varargs |= nested.uses_varargs;
+			summary.makes_calls |= nested.makes_calls;
+		}
+		summary
+	}
+
+	/// Describe the shape of this function and its nested functions, such
+	/// as to document the structure of a compiled module.
+	pub fn signature_tree(&self) -> SignatureNode {
+		self.signature_node("")
+	}
+
+	fn signature_node(&self, parent_source: &str) -> SignatureNode {
+		let source = if self.source.is_empty() { parent_source } else { &self.source };
+		SignatureNode {
+			num_params: self.num_params,
+			is_vararg: self.is_vararg,
+			source: source.to_owned(),
+			line_start: self.line_start,
+			line_end: self.line_end,
+			children: self.protos.iter().map(|proto| proto.signature_node(source)).collect(),
+		}
+	}
+
+	/// Find the nested function instantiated by a `Closure` instruction.
+	///
+	/// Returns `None` if `instr` is not a `Closure` or its `Bx` is out of
+	/// range.
+	pub fn closure_target(&self, instr: Instruction) -> Option<&Function> {
+		if bytecode::get_opcode(instr) != Some(bytecode::Opcode::Closure) {
+			return None;
+		}
+		self.protos.get(bytecode::get_bx(instr) as usize)
+	}
+
+	/// Remove and return the nested function at `index`, renumbering the
+	/// `Closure` operands which refer to those after it.
+	///
+	/// Returns `None`, leaving this function unchanged, if there is no
+	/// such nested function or a `Closure` still instantiates it, since
+	/// removing it would leave that `Closure` dangling.
+	pub fn take_proto(&mut self, index: usize) -> Option<Function> {
+		use bytecode::{Opcode, get_opcode, get_a, get_bx, encode_bx};
+
+		let closures = || self.code.iter().filter(|&&instr| get_opcode(instr) == Some(Opcode::Closure));
+		if index >= self.protos.len() || closures().any(|&instr| get_bx(instr) as usize == index) {
+			return None;
+		}
+		for instr in &mut self.code {
+			if get_opcode(*instr) == Some(Opcode::Closure) && get_bx(*instr) as usize > index {
+				*instr = encode_bx(Opcode::Closure, get_a(*instr), get_bx(*instr) - 1);
+			}
+		}
+		Some(self.protos.remove(index))
+	}
+
+	/// Turn a nested function into one which can be loaded as a main chunk.
+	///
+	/// The only upvalue a main chunk may have is `_ENV`, which the loader
+	/// sets to the globals table, so this fails, listing the other upvalues
+	/// by name, if the function captures anything else. Upvalues are
+	/// identified by their debug names, so this also fails if those were
+	/// stripped. Otherwise its upvalue table becomes a single `_ENV`, with
+	/// the instructions and nested functions referring to it renumbered.
+	pub fn make_standalone(&mut self) -> std::io::Result<()> {
+		use std::io::{Error, ErrorKind};
+		use bytecode::{Opcode, get_opcode, get_a, get_b, get_c, encode};
+
+		if self.debug.upvalues.len() != self.upvalues.len() {
+			return Err(Error::new(ErrorKind::InvalidInput, "upvalue names are needed to find _ENV"));
+		}
+		let captures: Vec<String> = self.debug.upvalues.iter()
+			.enumerate()
+			.filter(|&(_, name)| name != builder::ENV)
+			.map(|(i, name)| format!("{} (upvalue {})", name, i))
+			.collect();
+		if !captures.is_empty() {
+			return Err(Error::new(ErrorKind::InvalidInput, format!(
+				"function captures more than _ENV: {}", captures.join(", "),
+			)));
+		}
+
+		// Every upvalue is _ENV, so every reference to one becomes upvalue 0.
+		for instr in &mut self.code {
+			let (a, b, c) = (get_a(*instr), get_b(*instr), get_c(*instr));
+			match get_opcode(*instr) {
+				Some(op @ Opcode::GetUpval) | Some(op @ Opcode::SetUpval) | Some(op @ Opcode::GetTabUp) =>
+					*instr = encode(op, a, 0, c),
+				Some(Opcode::SetTabUp) => *instr = encode(Opcode::SetTabUp, 0, b, c),
+				_ => {}
+			}
+		}
+		for proto in &mut self.protos {
+			for upval in &mut proto.upvalues {
+				if let Upvalue::Outer(_) = *upval {
+					*upval = Upvalue::Outer(0);
+				}
+			}
+		}
+		self.upvalues = vec![Upvalue::Stack(0)];
+		self.debug.upvalues = vec![builder::ENV.to_owned()];
+		Ok(())
+	}
+
+	/// Bundle several main chunks into one which runs them in order.
+	///
+	/// Each chunk becomes a nested function of the new main chunk, which
+	/// instantiates and calls it with its own arguments, discarding the
+	/// results. Chunks compiled by Lua have one upvalue, which the loader
+	/// sets to the globals table; it is bound to the new chunk's `_ENV`.
+	///
+	/// The loader leaves any other upvalues of a chunk nil, which cannot be
+	/// arranged for a nested function, so this fails if a chunk has more
+	/// than one upvalue. It also fails if there are more chunks than a
+	/// `Closure` can address.
+	pub fn sequence(chunks: Vec<Function>) -> std::io::Result<Function> {
+		use std::io::{Error, ErrorKind};
+		use bytecode::{Opcode, encode, encode_bx};
+
+		if chunks.len() > 0x40000 {
+			return Err(Error::new(ErrorKind::InvalidInput, format!(
+				"{} chunks are more than a Closure can address", chunks.len(),
+			)));
+		}
+		let mut builder = builder::FunctionBuilder::main_chunk();
+		for (index, mut chunk) in chunks.into_iter().enumerate() {
+			if chunk.upvalues.len() > 1 {
+				return Err(Error::new(ErrorKind::InvalidInput, format!(
+					"chunk {} has {} upvalues, but only its first can be bound", index, chunk.upvalues.len(),
+				)));
+			}
+			for upval in &mut chunk.upvalues {
+				*upval = Upvalue::Outer(0);
+			}
+			builder.function_mut().protos.push(chunk);
+			builder.emit(encode_bx(Opcode::Closure, 0, index as u32));
+			builder.emit(encode(Opcode::VarArg, 1, 0, 0));
+			builder.emit(encode(Opcode::Call, 0, 0, 1));
+		}
+		builder.emit(encode(Opcode::Return, 0, 1, 0));
+		Ok(builder.build())
+	}
+
+	/// Convert every integer constant in this function and its nested
+	/// functions to a float, for VMs built without an integer subtype.
+	///
+	/// No instructions need rewriting, since the integer-only operations
+	/// (integer division and the bitwise operators) accept floats with exact
+	/// integer values. Integers beyond 2^53 in magnitude lose precision.
+	pub fn lower_integers_to_floats(&mut self) {
+		for cons in &mut self.constants {
+			if let Constant::Int(n) = *cons {
+				*cons = Constant::Float(n as Number);
+			}
+		}
+		for proto in &mut self.protos {
+			proto.lower_integers_to_floats();
+		}
+	}
+
+	/// Replace every NaN float constant in this function and its nested
+	/// functions with the canonical quiet NaN, `f64::NAN`.
+	///
+	/// This changes the bytes written for NaNs with other payloads or signs
+	/// but not the value seen by Lua, which cannot distinguish NaNs, so
+	/// equivalent functions serialize identically.
+	pub fn canonicalize_nans(&mut self) {
+		for cons in &mut self.constants {
+			if let Constant::Float(ref mut n) = *cons {
+				if n.is_nan() {
+					*n = Number::NAN;
+				}
+			}
+		}
+		for proto in &mut self.protos {
+			proto.canonicalize_nans();
+		}
+	}
+
+	/// Attribute every instruction of this function to `line`, and every
+	/// instruction of each nested function to its `line_start`.
+	///
+	/// Useful for generated code without precise line numbers, so that
+	/// errors and tracebacks point at the function's definition.
+	pub fn set_uniform_line(&mut self, line: Int) {
+		self.debug.lineinfo = vec![line; self.code.len()];
+		for proto in &mut self.protos {
+			let line_start = proto.line_start;
+			proto.set_uniform_line(line_start);
+		}
+	}
+
+	/// Add `delta` to every line number of this function and its nested
+	/// functions, in `line_start`, `line_end`, and `debug.lineinfo`, for code
+	/// moved to another place in its source. Lines which would become
+	/// negative become zero instead.
+	///
+	/// A function whose `line_start` and `line_end` are both zero, as for a
+	/// main chunk, keeps them, since Lua takes that to mean a main chunk.
+	pub fn offset_lines(&mut self, delta: Int) {
+		let offset = |line: Int| line.saturating_add(delta).max(0);
+		if self.line_start != 0 || self.line_end != 0 {
+			self.line_start = offset(self.line_start);
+			self.line_end = offset(self.line_end);
+		}
+		for line in &mut self.debug.lineinfo {
+			*line = offset(*line);
+		}
+		for proto in &mut self.protos {
+			proto.offset_lines(delta);
+		}
+	}
+
+	/// Rename every local variable and upvalue in the debug info of this
+	/// function and its nested functions. Scopes are left unchanged.
+	pub fn rename_locals<F: FnMut(&str) -> String>(&mut self, mut f: F) {
+		self.rename_locals_with(&mut f);
+	}
+
+	fn rename_locals_with<F: FnMut(&str) -> String>(&mut self, f: &mut F) {
+		for var in &mut self.debug.localvars {
+			var.name = f(&var.name);
+		}
+		for name in &mut self.debug.upvalues {
+			*name = f(name);
+		}
+		for proto in &mut self.protos {
+			proto.rename_locals_with(f);
+		}
+	}
+
+	/// Clear the source of nested functions which share their parent's, as
+	/// luac does, so it is written only once.
+	pub fn normalize_sources(&mut self) {
+		let source = self.source.clone();
+		for proto in &mut self.protos {
+			proto.normalize_sources_from(&source);
+		}
+	}
+
+	fn normalize_sources_from(&mut self, parent: &str) {
+		if self.source == parent {
+			self.source.clear();
+		}
+		let source = if self.source.is_empty() { parent.to_owned() } else { self.source.clone() };
+		for proto in &mut self.protos {
+			proto.normalize_sources_from(&source);
+		}
+	}
+
+	/// Whether this function or any nested function has line info, local
+	/// variable names, or upvalue names. The source name is not considered.
+	pub fn has_debug(&self) -> bool {
+		!self.debug.lineinfo.is_empty() || !self.debug.localvars.is_empty() ||
+			!self.debug.upvalues.is_empty() || self.protos.iter().any(Function::has_debug)
+	}
+
+	/// Whether this function or any nested function has an integer
+	/// constant, which a VM built without an integer subtype cannot load.
+	/// See `lower_integers_to_floats`.
+	pub fn uses_integers(&self) -> bool {
+		self.constants.iter().any(|cons| matches!(*cons, Constant::Int(_))) ||
+			self.protos.iter().any(Function::uses_integers)
+	}
+
+	/// The immediately nested functions, paired with their indices as used
+	/// by the `Bx` operand of `Closure`.
+	pub fn sub_functions(&self) -> impl Iterator<Item = (u32, &Function)> {
+		self.protos.iter().enumerate().map(|(i, proto)| (i as u32, proto))
+	}
+
+	/// Count this function's upvalues captured from the parent's registers
+	/// and from the parent's upvalues, in that order.
+	pub fn upvalue_stats(&self) -> (usize, usize) {
+		let stack = self.upvalues.iter().filter(|upval| matches!(**upval, Upvalue::Stack(_))).count();
+		(stack, self.upvalues.len() - stack)
+	}
+
+	/// The parent registers captured by this function's `Stack` upvalues.
+	pub fn stack_upvalues(&self) -> Vec<u8> {
+		self.upvalues.iter()
+			.filter_map(|upval| match *upval {
+				Upvalue::Stack(reg) => Some(reg),
+				Upvalue::Outer(_) => None,
+			})
+			.collect()
+	}
+
+	/// Which of this function's upvalues are used, indexed like `upvalues`.
+	///
+	/// An upvalue is used if an instruction such as `GetUpval` or
+	/// `SetTabUp` refers to it, or a nested function captures it with an
+	/// `Outer` upvalue which that function in turn uses.
+	pub fn used_upvalues(&self) -> Vec<bool> {
+		use bytecode::{DecodedInstruction, Operand};
+
+		let mut used = vec![false; self.upvalues.len()];
+		let mut mark = |index: usize| if let Some(slot) = used.get_mut(index) {
+			*slot = true;
+		};
+		for decoded in self.code.iter().filter_map(|&instr| DecodedInstruction::decode(instr)) {
+			let mode = decoded.opcode.mode();
+			for &(role, value) in &[(mode.a, decoded.a), (mode.b, decoded.b), (mode.c, decoded.c)] {
+				if role == Operand::Upvalue {
+					mark(value as usize);
+				}
+			}
+		}
+		for proto in &self.protos {
+			for (upval, used_by_proto) in proto.upvalues.iter().zip(proto.used_upvalues()) {
+				if let (Upvalue::Outer(index), true) = (*upval, used_by_proto) {
+					mark(index as usize);
+				}
+			}
+		}
+		used
+	}
+
+	/// Look up the constant an RK operand refers to.
+	///
+	/// Returns `None` if `rk` is a register or the constant index is out of
+	/// range.
+	pub fn rk_value(&self, rk: bytecode::RK) -> Option<&Constant> {
+		match rk {
+			bytecode::RK::K(k) => self.constants.get(k as usize),
+			bytecode::RK::R(_) => None,
+		}
+	}
+
+	/// The pcs of this function's `Call`, `TailCall`, and `TForCall`
+	/// instructions. Nested functions are not included.
+	pub fn calls(&self) -> Vec<usize> {
+		use bytecode::Opcode::{Call, TailCall, TForCall};
+		self.code.iter().enumerate()
+			.filter(|&(_, &instr)| matches!(
+				bytecode::get_opcode(instr),
+				Some(Call) | Some(TailCall) | Some(TForCall)
+			))
+			.map(|(pc, _)| pc)
+			.collect()
+	}
+
+	/// Whether this function makes no calls. Nested functions are not
+	/// considered.
+	pub fn is_leaf(&self) -> bool {
+		self.calls().is_empty()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use builder::FunctionBuilder;
+	use bytecode::{Format, Opcode, encode, encode_ax, encode_bx, encode_sbx};
+	use super::{Constant, Function, ResourceSummary, Upvalue};
+
+	#[test]
+	fn nesting_metrics() {
+		let mut innermost = FunctionBuilder::new();
+		innermost.function_mut().max_stack_size = 5;
+		innermost.constant(Constant::Int(1));
+		innermost.emit(encode(Opcode::VarArg, 0, 2, 0));
+		innermost.emit(encode(Opcode::Call, 0, 1, 1));
+		innermost.emit(encode(Opcode::Return, 0, 1, 0));
+
+		let mut middle = FunctionBuilder::new();
+		middle.emit(encode(Opcode::Return, 0, 1, 0));
+		middle.function_mut().protos.push(innermost.build());
+
+		let mut leaf = FunctionBuilder::new();
+		leaf.emit(encode(Opcode::Return, 0, 1, 0));
+
+		let mut main = FunctionBuilder::main_chunk();
+		main.constant(Constant::ShortString("x".into()));
+		main.constant(Constant::Boolean(true));
+		main.emit(encode_bx(Opcode::Closure, 0, 0));
+		main.emit(encode(Opcode::Return, 0, 1, 0));
+		main.function_mut().protos.push(middle.build());
+		main.function_mut().protos.push(leaf.build());
+		let main: Function = main.into();
+
+		assert_eq!(main.max_nesting_depth(), 2);
+		assert_eq!(main.protos[0].max_nesting_depth(), 1);
+		assert_eq!(main.protos[1].max_nesting_depth(), 0);
+		assert_eq!(main.total_instructions(), 7);
+		assert_eq!(main.proto_count(), 3);
+		assert_eq!(main.protos[0].proto_count(), 1);
+		assert_eq!(main.resource_summary(), ResourceSummary {
+			max_stack_size: 5,
+			total_constants: 3,
+			total_instructions: 7,
+			max_nesting_depth: 2,
+			uses_varargs: true,
+			makes_calls: true,
+		});
+	}
+
+	#[test]
+	fn decode_encode_code() {
+		let mut builder = FunctionBuilder::new();
+		for &op in Opcode::all() {
+			match op.mode().format {
+				Format::ABC => {
+					builder.emit(encode(op, 0, 0, 0));
+					builder.emit(encode(op, 0xff, 0x1ff, 0x1ff));
+					builder.emit(encode(op, 1, 0x100, 0xff));
+				}
+				Format::ABx => {
+					builder.emit(encode_bx(op, 0, 0));
+					builder.emit(encode_bx(op, 0xff, 0x3ffff));
+				}
+				Format::AsBx => for &sbx in &[0, -1, 1, -0x1ffff, 0x1ffff] {
+					builder.emit(encode_sbx(op, 0x7f, sbx));
+				},
+				Format::Ax => {
+					builder.emit(encode_ax(op, 0));
+					builder.emit(encode_ax(op, 0x3ffffff));
+				}
+			}
+		}
+		builder.emit(encode_bx(Opcode::LoadKX, 3, 0));
+		builder.emit(encode_ax(Opcode::ExtraArg, 0x40000));
+		let function = builder.build();
+
+		let decoded = function.decode_code().unwrap();
+		assert_eq!(decoded.len(), function.code.len());
+		let jumps: Vec<i32> = decoded.iter()
+			.filter(|instr| instr.opcode == Opcode::Jump)
+			.map(|instr| instr.b)
+			.collect();
+		assert_eq!(jumps, [0, -1, 1, -0x1ffff, 0x1ffff]);
+		let extra = &decoded[decoded.len() - 2..];
+		assert_eq!((extra[0].opcode, extra[0].a), (Opcode::LoadKX, 3));
+		assert_eq!((extra[1].opcode, extra[1].a), (Opcode::ExtraArg, 0x40000));
+
+		let mut rebuilt = function.clone();
+		rebuilt.code.clear();
+		rebuilt.encode_code(&decoded);
+		assert_eq!(rebuilt.code, function.code);
+	}
+
+	#[test]
+	fn sequence_chunks() {
+		let chunk = |name: &str| {
+			let mut builder = FunctionBuilder::main_chunk();
+			let name = builder.constant(Constant::ShortString(name.into()));
+			builder.emit_global_get(0, name).unwrap();
+			builder.emit(encode(Opcode::Return, 0, 1, 0));
+			builder.build()
+		};
+		let mut stripped = chunk("b");
+		stripped.debug.upvalues.clear();
+		let function = Function::sequence(vec![chunk("a"), stripped]).unwrap();
+
+		assert_eq!(function.upvalues, [Upvalue::Stack(0)]);
+		assert_eq!(function.code, [
+			encode_bx(Opcode::Closure, 0, 0),
+			encode(Opcode::VarArg, 1, 0, 0),
+			encode(Opcode::Call, 0, 0, 1),
+			encode_bx(Opcode::Closure, 0, 1),
+			encode(Opcode::VarArg, 1, 0, 0),
+			encode(Opcode::Call, 0, 0, 1),
+			encode(Opcode::Return, 0, 1, 0),
+		]);
+		for proto in &function.protos {
+			assert_eq!(proto.upvalues, [Upvalue::Outer(0)]);
+		}
+		assert_eq!(function.protos[0].debug.upvalues, ["_ENV"]);
+		assert!(function.protos[1].debug.upvalues.is_empty());
+	}
+
+	#[test]
+	fn sequence_rejects_captures() {
+		let mut chunk = FunctionBuilder::main_chunk();
+		chunk.upvalue(Upvalue::Stack(1), "x");
+		let err = Function::sequence(vec![FunctionBuilder::main_chunk().build(), chunk.build()]).unwrap_err();
+		assert!(err.to_string().contains("chunk 1 has 2 upvalues"), "{}", err);
+	}
+}
@@ -0,0 +1,827 @@
+//! Transformations which make bytecode smaller or faster.
+
+use std::collections::BTreeSet;
+use std::mem;
+
+use super::analysis::{def_use, reg_effects, DefUseGraph};
+use super::bytecode::{
+	DecodedInstruction, Opcode, Operand, RK, encode_ax, encode_bx, encode_sbx, extra_arg_value,
+	get_opcode, get_a, get_b, get_c, get_bx, get_sbx,
+};
+use super::{Function, Constant, Instruction, Integer, Number, Upvalue};
+
+/// Fold arithmetic on constant operands into a single `LoadK`.
+///
+/// An operand is constant if it is an RK constant or a register whose only
+/// reaching definition is a `LoadK`. Such `LoadK`s are removed once nothing
+/// else reads them. Folding follows Lua's own compiler: integer operations
+/// wrap on overflow, and operations which would raise an error or produce
+/// NaN or zero floats (such as division by zero) are left alone.
+/// Nested functions are not affected.
+pub fn fold_constants(function: &mut Function) {
+	while fold_pass(function) {}
+}
+
+fn fold_pass(function: &mut Function) -> bool {
+	let graph = def_use(function);
+	let mut changed = false;
+	let mut remove = BTreeSet::new();
+	for pc in 0..function.code.len() {
+		let instr = function.code[pc];
+		let op = match get_opcode(instr) {
+			Some(op) => op,
+			None => continue,
+		};
+		if !is_foldable(op) {
+			continue;
+		}
+		let (lhs, lhs_def) = match constant_operand(function, &graph, pc, get_b(instr)) {
+			Some(operand) => operand,
+			None => continue,
+		};
+		let (rhs, rhs_def) = match constant_operand(function, &graph, pc, get_c(instr)) {
+			Some(operand) => operand,
+			None => continue,
+		};
+		let result = match arith(op, &lhs, &rhs) {
+			Some(result) => result,
+			None => continue,
+		};
+		let len = function.constants.len();
+		let index = add_constant(&mut function.constants, result);
+		if index > 0x3ffff {
+			// Out of `LoadK`'s reach, so take back the constant if it is new.
+			function.constants.truncate(len);
+			continue;
+		}
+
+		function.code[pc] = encode_bx(Opcode::LoadK, get_a(instr), index as u32);
+		changed = true;
+		for &def in lhs_def.iter().chain(rhs_def.iter()) {
+			let reg = get_a(function.code[def]);
+			if graph.uses_of(Some(def), reg) == [pc] && !is_local(function, def, reg) &&
+				!follows_skip(&function.code, def)
+			{
+				remove.insert(def);
+			}
+		}
+	}
+	remove_instructions(function, &remove);
+	changed
+}
+
+fn is_foldable(op: Opcode) -> bool {
+	matches!(
+		op,
+		Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Mod | Opcode::Pow | Opcode::Div |
+		Opcode::IntDiv | Opcode::BinAnd | Opcode::BinOr | Opcode::BinXor | Opcode::ShLeft |
+		Opcode::ShRight
+	)
+}
+
+/// Resolve an RK operand to a constant, along with the pc of the `LoadK`
+/// it was loaded by if it is a register.
+fn constant_operand(function: &Function, graph: &DefUseGraph, pc: usize, rk: u32) -> Option<(Constant, Option<usize>)> {
+	match RK::decode(rk) {
+		k @ RK::K(_) => function.rk_value(k).map(|c| (c.clone(), None)),
+		RK::R(reg) => {
+			let defs = graph.defs_of(pc, reg);
+			if defs.len() != 1 {
+				return None;
+			}
+			let def = defs[0].pc?;
+			let instr = function.code[def];
+			if get_opcode(instr) != Some(Opcode::LoadK) || get_a(instr) != reg {
+				return None;
+			}
+			function.constants.get(get_bx(instr) as usize).map(|c| (c.clone(), Some(def)))
+		}
+	}
+}
+
+/// Whether `reg` holds a named local variable just after `pc`.
+///
+/// Lua allocates active locals to the lowest registers in order.
+fn is_local(function: &Function, pc: usize, reg: u8) -> bool {
+	let pc = pc as i64 + 1;
+	let active = function.debug.localvars.iter()
+		.filter(|var| var.start_pc as i64 <= pc && pc < var.end_pc as i64)
+		.count();
+	(reg as usize) < active
+}
+
+/// Whether the instruction at `pc` may be skipped by the one before it, in
+/// which case removing it would change which instruction is skipped.
+fn follows_skip(code: &[Instruction], pc: usize) -> bool {
+	let prev = match pc.checked_sub(1) {
+		Some(prev) => code[prev],
+		None => return false,
+	};
+	match get_opcode(prev) {
+		Some(Opcode::Eq) | Some(Opcode::Less) | Some(Opcode::LessEq) |
+		Some(Opcode::Test) | Some(Opcode::TestSet) | Some(Opcode::LoadKX) => true,
+		Some(Opcode::LoadBool) => get_c(prev) != 0,
+		_ => false,
+	}
+}
+
+/// Find or append a constant, returning its index.
+pub(crate) fn add_constant(constants: &mut Vec<Constant>, value: Constant) -> usize {
+	let found = constants.iter().position(|c| match (c, &value) {
+		(&Constant::Float(a), &Constant::Float(b)) => a.to_bits() == b.to_bits(),
+		(a, b) => a == b,
+	});
+	match found {
+		Some(index) => index,
+		None => {
+			constants.push(value);
+			constants.len() - 1
+		}
+	}
+}
+
+fn to_number(value: &Constant) -> Option<Number> {
+	match *value {
+		Constant::Int(i) => Some(i as Number),
+		Constant::Float(n) => Some(n),
+		_ => None,
+	}
+}
+
+/// Convert a constant to an integer if it has an exact integer value.
+fn to_integer(value: &Constant) -> Option<Integer> {
+	match *value {
+		Constant::Int(i) => Some(i),
+		Constant::Float(n) if n.floor() == n && (-9223372036854775808.0..9223372036854775808.0).contains(&n) => {
+			Some(n as Integer)
+		}
+		_ => None,
+	}
+}
+
+fn shift_left(x: Integer, y: Integer) -> Integer {
+	if y <= -64 || y >= 64 {
+		0
+	} else if y >= 0 {
+		((x as u64) << y) as Integer
+	} else {
+		((x as u64) >> -y) as Integer
+	}
+}
+
+/// Evaluate an arithmetic operation as Lua would, if it is safe to fold.
+fn arith(op: Opcode, lhs: &Constant, rhs: &Constant) -> Option<Constant> {
+	// Bitwise operations work on integers, converting floats if exact.
+	let bitwise: Option<fn(Integer, Integer) -> Integer> = match op {
+		Opcode::BinAnd => Some(|a, b| a & b),
+		Opcode::BinOr => Some(|a, b| a | b),
+		Opcode::BinXor => Some(|a, b| a ^ b),
+		Opcode::ShLeft => Some(shift_left),
+		Opcode::ShRight => Some(|a, b| shift_left(a, b.wrapping_neg())),
+		_ => None,
+	};
+	if let Some(f) = bitwise {
+		return Some(Constant::Int(f(to_integer(lhs)?, to_integer(rhs)?)));
+	}
+
+	let (a, b) = (to_number(lhs)?, to_number(rhs)?);
+	if (op == Opcode::Div || op == Opcode::IntDiv || op == Opcode::Mod) && b == 0.0 {
+		return None;
+	}
+	// Integer operations on integer operands stay integers.
+	if let (&Constant::Int(x), &Constant::Int(y)) = (lhs, rhs) {
+		let result = match op {
+			Opcode::Add => Some(x.wrapping_add(y)),
+			Opcode::Sub => Some(x.wrapping_sub(y)),
+			Opcode::Mul => Some(x.wrapping_mul(y)),
+			Opcode::IntDiv => {
+				let q = x.wrapping_div(y);
+				Some(if x.wrapping_rem(y) != 0 && (x ^ y) < 0 { q - 1 } else { q })
+			}
+			Opcode::Mod => {
+				let m = x.wrapping_rem(y);
+				Some(if m != 0 && (m ^ y) < 0 { m + y } else { m })
+			}
+			_ => None,
+		};
+		if let Some(result) = result {
+			return Some(Constant::Int(result));
+		}
+	}
+	let result = match op {
+		Opcode::Add => a + b,
+		Opcode::Sub => a - b,
+		Opcode::Mul => a * b,
+		Opcode::Div => a / b,
+		Opcode::Pow => a.powf(b),
+		Opcode::IntDiv => (a / b).floor(),
+		Opcode::Mod => {
+			let m = a % b;
+			if m * b < 0.0 { m + b } else { m }
+		}
+		_ => return None,
+	};
+	if result.is_nan() || result == 0.0 {
+		None
+	} else {
+		Some(Constant::Float(result))
+	}
+}
+
+/// Refer to constants directly from RK operands rather than loading them
+/// into registers first.
+///
+/// A `LoadK` is removed if every instruction which reads the register it
+/// loads does so only through RK operands, and it is the only definition of
+/// that register which reaches them; those operands then refer to the
+/// constant instead. RK operands can only refer to the first 256
+/// constants, so `LoadK`s of others are kept, as are those which load named
+/// local variables. Nested functions are not affected.
+pub fn inline_constants(function: &mut Function) {
+	let graph = def_use(function);
+	let mut remove = BTreeSet::new();
+	for def in 0..function.code.len() {
+		let instr = function.code[def];
+		if get_opcode(instr) != Some(Opcode::LoadK) || get_bx(instr) > 0xff {
+			continue;
+		}
+		let (reg, k) = (get_a(instr), get_bx(instr) as u8);
+		if is_local(function, def, reg) || follows_skip(&function.code, def) {
+			continue;
+		}
+		let uses = graph.uses_of(Some(def), reg);
+		if uses.is_empty() {
+			continue;
+		}
+
+		let mut rewritten = Vec::with_capacity(uses.len());
+		for &pc in uses {
+			let only_def = graph.defs_of(pc, reg).iter().all(|other| other.pc == Some(def));
+			let new = match inline_operands(function.code[pc], reg, k) {
+				Some(new) if only_def => new,
+				_ => break,
+			};
+			// The instruction must not read the register some other way.
+			let old = mem::replace(&mut function.code[pc], new);
+			let still_read = reg_effects(function, pc).reads.contains(&reg);
+			function.code[pc] = old;
+			if still_read {
+				break;
+			}
+			rewritten.push((pc, new));
+		}
+		if rewritten.len() == uses.len() {
+			for (pc, new) in rewritten {
+				function.code[pc] = new;
+			}
+			remove.insert(def);
+		}
+	}
+	remove_instructions(function, &remove);
+}
+
+/// Replace the RK operands of `instr` which refer to `reg` with references
+/// to constant `k`, or return `None` if there are none.
+fn inline_operands(instr: Instruction, reg: u8, k: u8) -> Option<Instruction> {
+	let mut decoded = DecodedInstruction::decode(instr)?;
+	let mode = decoded.opcode.mode();
+	let mut changed = false;
+	for &mut (role, ref mut value) in &mut [(mode.b, &mut decoded.b), (mode.c, &mut decoded.c)] {
+		if role == Operand::RegisterOrConstant && RK::decode(**value as u32) == RK::R(reg) {
+			**value = RK::K(k).encode() as i32;
+			changed = true;
+		}
+	}
+	if changed {
+		Some(decoded.encode())
+	} else {
+		None
+	}
+}
+
+/// Renumber registers so that those in use are contiguous, and lower
+/// `max_stack_size` to match.
+///
+/// A register is in use if any instruction reads or writes it, including
+/// as part of a range such as a call's arguments or the three registers
+/// `TForCall` copies its operands to, if it holds a parameter, or if it
+/// holds a named local variable. Registers keep their order, so
+/// ranges stay contiguous and local variables, which Lua assigns to
+/// registers by position, need no change. The `Upvalue::Stack` entries of
+/// immediately nested functions are updated, but nested functions are
+/// otherwise not affected.
+pub fn minimize_stack(function: &mut Function) {
+	let mut used = [false; 256];
+	for param in used.iter_mut().take(function.num_params as usize) {
+		*param = true;
+	}
+	for pc in 0..function.code.len() {
+		let fx = reg_effects(function, pc);
+		for &reg in fx.reads.iter().chain(fx.writes.iter()) {
+			used[reg as usize] = true;
+		}
+		// Before calling the iterator, `TForCall` copies it and its state
+		// and control variable above them, however few results it keeps.
+		let instr = function.code[pc];
+		if get_opcode(instr) == Some(Opcode::TForCall) {
+			for reg in get_a(instr) as usize + 3..=get_a(instr) as usize + 5 {
+				if reg < used.len() {
+					used[reg] = true;
+				}
+			}
+		}
+	}
+	let vars = &function.debug.localvars;
+	for (i, var) in vars.iter().enumerate() {
+		let reg = vars[..i].iter()
+			.filter(|outer| outer.start_pc <= var.start_pc && var.start_pc < outer.end_pc)
+			.count();
+		if reg < used.len() {
+			used[reg] = true;
+		}
+	}
+
+	// A register moves down by the number of unused registers below it.
+	let mut new_reg = [0u8; 256];
+	let mut count = 0;
+	for reg in 0..used.len() {
+		new_reg[reg] = count as u8;
+		if used[reg] {
+			count += 1;
+		}
+	}
+	let map = |reg: i32| new_reg[reg as usize & 0xff] as i32;
+	let remap = |role: Operand, value: &mut i32| match role {
+		Operand::Register => *value = map(*value),
+		Operand::RegisterOrConstant => if let RK::R(reg) = RK::decode(*value as u32) {
+			*value = map(reg as i32);
+		},
+		_ => {}
+	};
+
+	for instr in &mut function.code {
+		let mut decoded = match DecodedInstruction::decode(*instr) {
+			Some(decoded) => decoded,
+			None => continue,
+		};
+		let mode = decoded.opcode.mode();
+		remap(mode.a, &mut decoded.a);
+		remap(mode.b, &mut decoded.b);
+		remap(mode.c, &mut decoded.c);
+		// A nonzero `A` of `Jump` closes upvalues from register `A - 1` up.
+		if decoded.opcode == Opcode::Jump && decoded.a > 0 {
+			decoded.a = map(decoded.a - 1) + 1;
+		}
+		*instr = decoded.encode();
+	}
+	for proto in &mut function.protos {
+		for upval in &mut proto.upvalues {
+			if let Upvalue::Stack(ref mut reg) = *upval {
+				*reg = new_reg[*reg as usize];
+			}
+		}
+	}
+	function.max_stack_size = function.max_stack_size.min(count.max(2) as u8);
+}
+
+/// Remove constants which no instruction refers to, renumbering the rest.
+///
+/// Constants are referred to by `LoadK`, by the `ExtraArg` following
+/// `LoadKX`, and by RK operands. Nested functions are compacted too, each
+/// independently.
+pub fn compact_constants(function: &mut Function) {
+	// Find the referenced constants, and where the operands which refer to
+	// them are.
+	let mut used = vec![false; function.constants.len()];
+	let mut mark = |index: u32| if let Some(slot) = used.get_mut(index as usize) {
+		*slot = true;
+	};
+	for pc in 0..function.code.len() {
+		let instr = function.code[pc];
+		let decoded = match DecodedInstruction::decode(instr) {
+			Some(decoded) => decoded,
+			None => continue,
+		};
+		let mode = decoded.opcode.mode();
+		for &(role, value) in &[(mode.a, decoded.a), (mode.b, decoded.b), (mode.c, decoded.c)] {
+			match role {
+				Operand::Constant => mark(value as u32),
+				Operand::RegisterOrConstant => if let RK::K(k) = RK::decode(value as u32) {
+					mark(k as u32);
+				},
+				_ => {}
+			}
+		}
+		if decoded.opcode == Opcode::LoadKX {
+			if let Some(index) = extra_arg_value(&function.code, pc) {
+				mark(index);
+			}
+		}
+	}
+
+	let mut new_index = Vec::with_capacity(used.len());
+	let mut count = 0;
+	for &is_used in &used {
+		new_index.push(count);
+		if is_used {
+			count += 1;
+		}
+	}
+	let map = |index: i32| new_index.get(index as usize).map_or(index, |&new| new);
+	let remap = |role: Operand, value: &mut i32| match role {
+		Operand::Constant => *value = map(*value),
+		Operand::RegisterOrConstant => if let RK::K(k) = RK::decode(*value as u32) {
+			*value = RK::K(map(k as i32) as u8).encode() as i32;
+		},
+		_ => {}
+	};
+	for pc in 0..function.code.len() {
+		let mut decoded = match DecodedInstruction::decode(function.code[pc]) {
+			Some(decoded) => decoded,
+			None => continue,
+		};
+		let mode = decoded.opcode.mode();
+		remap(mode.a, &mut decoded.a);
+		remap(mode.b, &mut decoded.b);
+		remap(mode.c, &mut decoded.c);
+		function.code[pc] = decoded.encode();
+		if decoded.opcode == Opcode::LoadKX {
+			if let Some(index) = extra_arg_value(&function.code, pc) {
+				function.code[pc + 1] = encode_ax(Opcode::ExtraArg, map(index as i32) as u32);
+			}
+		}
+	}
+
+	let mut index = 0;
+	function.constants.retain(|_| {
+		index += 1;
+		used[index - 1]
+	});
+	for proto in &mut function.protos {
+		compact_constants(proto);
+	}
+}
+
+/// Remove upvalues which `Function::used_upvalues` finds unused, renumbering
+/// the rest.
+///
+/// Nested functions are handled first, so an upvalue which is only passed
+/// on to nested functions which do not use it is removed too. Upvalue names
+/// in the debug info are removed along with their upvalues. The loader sets
+/// a main chunk's first upvalue to the globals table, so removing an unused
+/// `_ENV` from a main chunk which has other upvalues changes which one it
+/// sets.
+pub fn remove_dead_upvalues(function: &mut Function) {
+	for proto in &mut function.protos {
+		remove_dead_upvalues(proto);
+	}
+	let used = function.used_upvalues();
+	if used.iter().all(|&is_used| is_used) {
+		return;
+	}
+
+	let mut new_index = Vec::with_capacity(used.len());
+	let mut count = 0;
+	for &is_used in &used {
+		new_index.push(count);
+		if is_used {
+			count += 1;
+		}
+	}
+	let map = |index: i32| new_index.get(index as usize).map_or(index, |&new| new);
+	for instr in &mut function.code {
+		let mut decoded = match DecodedInstruction::decode(*instr) {
+			Some(decoded) => decoded,
+			None => continue,
+		};
+		let mode = decoded.opcode.mode();
+		if mode.a == Operand::Upvalue {
+			decoded.a = map(decoded.a);
+		}
+		if mode.b == Operand::Upvalue {
+			decoded.b = map(decoded.b);
+		}
+		*instr = decoded.encode();
+	}
+	// Every upvalue the nested functions still have is used, so each one
+	// they capture from this function is kept.
+	for proto in &mut function.protos {
+		for upval in &mut proto.upvalues {
+			if let Upvalue::Outer(index) = *upval {
+				*upval = Upvalue::Outer(map(index as i32) as u8);
+			}
+		}
+	}
+
+	let mut index = 0;
+	function.upvalues.retain(|_| {
+		index += 1;
+		used[index - 1]
+	});
+	let mut index = 0;
+	function.debug.upvalues.retain(|_| {
+		index += 1;
+		used.get(index - 1).cloned().unwrap_or(true)
+	});
+}
+
+/// Whether an instruction's `sBx` is a jump offset which must be adjusted
+/// when instructions move.
+fn has_jump(op: Opcode) -> bool {
+	matches!(op, Opcode::Jump | Opcode::ForLoop | Opcode::ForPrep | Opcode::TForLoop)
+}
+
+/// Remove the instructions at the given pcs, adjusting jump offsets, line
+/// info, and local variable scopes to match.
+///
+/// Jumps to a removed instruction are redirected to the next remaining one.
+pub(crate) fn remove_instructions(function: &mut Function, remove: &BTreeSet<usize>) {
+	if remove.is_empty() {
+		return;
+	}
+	let len = function.code.len();
+	// new_pc[pc] is where old pc ends up, or where control arriving at a
+	// removed pc continues.
+	let mut new_pc = Vec::with_capacity(len + 1);
+	let mut removed = 0;
+	for pc in 0..len + 1 {
+		new_pc.push(pc - removed);
+		if remove.contains(&pc) {
+			removed += 1;
+		}
+	}
+	let map = |pc: i64| -> i64 {
+		if pc < 0 {
+			pc
+		} else if pc as usize > len {
+			pc - removed as i64
+		} else {
+			new_pc[pc as usize] as i64
+		}
+	};
+
+	let mut code = Vec::with_capacity(len - remove.len());
+	for (pc, &instr) in function.code.iter().enumerate() {
+		if remove.contains(&pc) {
+			continue;
+		}
+		match get_opcode(instr) {
+			Some(op) if has_jump(op) => {
+				let target = pc as i64 + 1 + get_sbx(instr) as i64;
+				let sbx = map(target) - (new_pc[pc] as i64 + 1);
+				code.push(encode_sbx(op, get_a(instr), sbx as i32));
+			}
+			_ => code.push(instr),
+		}
+	}
+	function.code = code;
+
+	if function.debug.lineinfo.len() == len {
+		let lineinfo = function.debug.lineinfo.iter().enumerate()
+			.filter(|&(pc, _)| !remove.contains(&pc))
+			.map(|(_, &line)| line)
+			.collect();
+		function.debug.lineinfo = lineinfo;
+	}
+	for var in &mut function.debug.localvars {
+		var.start_pc = map(var.start_pc as i64) as _;
+		var.end_pc = map(var.end_pc as i64) as _;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use builder::FunctionBuilder;
+	use bytecode::{Opcode, RK, encode, encode_ax, encode_bx, encode_sbx};
+	use super::super::{Constant, Upvalue};
+	use super::{compact_constants, fold_constants, inline_constants, minimize_stack, remove_dead_upvalues};
+
+	#[test]
+	fn minimize_sparse() {
+		let mut child = FunctionBuilder::new();
+		child.upvalue(Upvalue::Stack(7), "x");
+		let mut builder = FunctionBuilder::new();
+		builder.function_mut().num_params = 1;
+		builder.function_mut().max_stack_size = 10;
+		builder.constant(Constant::Int(1));
+		builder.emit(encode_bx(Opcode::LoadK, 3, 0));
+		builder.emit(encode(Opcode::Add, 3, 3, RK::K(0).encode()));
+		builder.emit(encode(Opcode::Move, 5, 3, 0));
+		builder.emit(encode(Opcode::Call, 5, 2, 2));
+		builder.emit(encode_bx(Opcode::Closure, 7, 0));
+		builder.emit(encode_sbx(Opcode::Jump, 8, 0));
+		builder.emit(encode(Opcode::Return, 5, 2, 0));
+		builder.function_mut().protos.push(child.build());
+		let mut function = builder.build();
+		minimize_stack(&mut function);
+		assert_eq!(function.code, [
+			encode_bx(Opcode::LoadK, 1, 0),
+			encode(Opcode::Add, 1, 1, RK::K(0).encode()),
+			encode(Opcode::Move, 2, 1, 0),
+			encode(Opcode::Call, 2, 2, 2),
+			encode_bx(Opcode::Closure, 4, 0),
+			encode_sbx(Opcode::Jump, 5, 0),
+			encode(Opcode::Return, 2, 2, 0),
+		]);
+		assert_eq!(function.protos[0].upvalues, [Upvalue::Stack(4)]);
+		assert_eq!(function.max_stack_size, 5);
+	}
+
+	#[test]
+	fn minimize_generic_for() {
+		// for k in pairs(t) do end, with t a parameter.
+		let mut builder = FunctionBuilder::main_chunk();
+		builder.function_mut().num_params = 1;
+		builder.function_mut().max_stack_size = 6;
+		let pairs = builder.constant(Constant::ShortString("pairs".into()));
+		builder.emit(encode(Opcode::GetTabUp, 1, 0, RK::K(pairs as u8).encode()));
+		builder.emit(encode(Opcode::Move, 2, 0, 0));
+		builder.emit(encode(Opcode::Call, 1, 2, 4));
+		builder.emit(encode_sbx(Opcode::Jump, 0, 0));
+		builder.emit(encode(Opcode::TForCall, 1, 0, 1));
+		builder.emit(encode_sbx(Opcode::TForLoop, 3, -2));
+		builder.emit(encode(Opcode::Return, 0, 1, 0));
+		let mut function = builder.build();
+		let code = function.code.clone();
+		minimize_stack(&mut function);
+		assert_eq!(function.code, code);
+		assert_eq!(function.max_stack_size, 6);
+	}
+
+	#[test]
+	fn fold_registers() {
+		// local x = 1 + 2, as compiled without folding.
+		let mut builder = FunctionBuilder::new();
+		builder.constant(Constant::Int(1));
+		builder.constant(Constant::Int(2));
+		builder.emit(encode_bx(Opcode::LoadK, 0, 0));
+		builder.emit(encode_bx(Opcode::LoadK, 1, 1));
+		builder.emit(encode(Opcode::Add, 2, 0, 1));
+		builder.emit(encode(Opcode::Return, 2, 2, 0));
+		let mut function = builder.build();
+		fold_constants(&mut function);
+		assert_eq!(function.code, [
+			encode_bx(Opcode::LoadK, 2, 2),
+			encode(Opcode::Return, 2, 2, 0),
+		]);
+		assert_eq!(function.constants[2], Constant::Int(3));
+	}
+
+	#[test]
+	fn fold_rk() {
+		let mut builder = FunctionBuilder::new();
+		builder.constant(Constant::Int(1));
+		builder.constant(Constant::Int(2));
+		builder.emit(encode(Opcode::Add, 0, RK::K(0).encode(), RK::K(1).encode()));
+		let mut function = builder.build();
+		fold_constants(&mut function);
+		assert_eq!(function.code, [encode_bx(Opcode::LoadK, 0, 2)]);
+		assert_eq!(function.constants, [Constant::Int(1), Constant::Int(2), Constant::Int(3)]);
+	}
+
+	#[test]
+	fn fold_out_of_reach() {
+		// The result would be constant 0x40000, which no `LoadK` can load.
+		let mut builder = FunctionBuilder::new();
+		builder.constant(Constant::Int(1));
+		builder.constant(Constant::Int(2));
+		builder.function_mut().constants.resize(0x40000, Constant::Nil);
+		let add = encode(Opcode::Add, 0, RK::K(0).encode(), RK::K(1).encode());
+		builder.emit(add);
+		let mut function = builder.build();
+		fold_constants(&mut function);
+		assert_eq!(function.code, [add]);
+		assert_eq!(function.constants.len(), 0x40000);
+	}
+
+	#[test]
+	fn compact_unused_string() {
+		let mut builder = FunctionBuilder::main_chunk();
+		builder.constant(Constant::ShortString("unused".into()));
+		builder.constant(Constant::Int(1));
+		builder.constant(Constant::ShortString("print".into()));
+		builder.constant(Constant::Float(0.5));
+		builder.emit(encode_bx(Opcode::LoadK, 0, 1));
+		builder.emit(encode(Opcode::GetTabUp, 1, 0, RK::K(2).encode()));
+		builder.emit(encode(Opcode::Add, 0, 0, RK::K(3).encode()));
+		builder.emit(encode_bx(Opcode::LoadKX, 2, 0));
+		builder.emit(encode_ax(Opcode::ExtraArg, 1));
+		builder.emit(encode(Opcode::Return, 0, 1, 0));
+		let mut function = builder.build();
+		compact_constants(&mut function);
+		assert_eq!(function.constants, [
+			Constant::Int(1),
+			Constant::ShortString("print".into()),
+			Constant::Float(0.5),
+		]);
+		assert_eq!(function.code, [
+			encode_bx(Opcode::LoadK, 0, 0),
+			encode(Opcode::GetTabUp, 1, 0, RK::K(1).encode()),
+			encode(Opcode::Add, 0, 0, RK::K(2).encode()),
+			encode_bx(Opcode::LoadKX, 2, 0),
+			encode_ax(Opcode::ExtraArg, 0),
+			encode(Opcode::Return, 0, 1, 0),
+		]);
+	}
+
+	/// Build a function of `p` with `k` other constants before the constant
+	/// `1` and the given code, and inline its constants.
+	fn inline(k: usize, code: &[u32]) -> (Vec<u32>, Vec<u32>) {
+		let mut builder = FunctionBuilder::new();
+		builder.function_mut().num_params = 1;
+		builder.function_mut().max_stack_size = 4;
+		for i in 0..k {
+			builder.constant(Constant::Int(i as i64 + 2));
+		}
+		builder.constant(Constant::Int(1));
+		for &instr in code {
+			builder.emit(instr);
+		}
+		let mut function = builder.build();
+		let before = function.code.clone();
+		inline_constants(&mut function);
+		(before, function.code)
+	}
+
+	#[test]
+	fn inline_rk_reads() {
+		// return p + 1
+		let (_, after) = inline(0, &[
+			encode_bx(Opcode::LoadK, 1, 0),
+			encode(Opcode::Add, 0, 0, RK::R(1).encode()),
+			encode(Opcode::Return, 0, 2, 0),
+		]);
+		assert_eq!(after, [
+			encode(Opcode::Add, 0, 0, RK::K(0).encode()),
+			encode(Opcode::Return, 0, 2, 0),
+		]);
+	}
+
+	#[test]
+	fn inline_kept() {
+		// The register is also read by a Move, which has no RK operand.
+		let (before, after) = inline(0, &[
+			encode_bx(Opcode::LoadK, 1, 0),
+			encode(Opcode::Move, 2, 1, 0),
+			encode(Opcode::Add, 0, 0, RK::R(1).encode()),
+			encode(Opcode::Return, 0, 3, 0),
+		]);
+		assert_eq!(after, before);
+
+		// The constant is beyond what an RK operand can refer to.
+		let (before, after) = inline(300, &[
+			encode_bx(Opcode::LoadK, 1, 300),
+			encode(Opcode::Add, 0, 0, RK::R(1).encode()),
+			encode(Opcode::Return, 0, 2, 0),
+		]);
+		assert_eq!(after, before);
+
+		// The LoadK is skipped when p is false.
+		let (before, after) = inline(0, &[
+			encode(Opcode::Test, 0, 0, 0),
+			encode_bx(Opcode::LoadK, 1, 0),
+			encode(Opcode::Add, 0, 0, RK::R(1).encode()),
+			encode(Opcode::Return, 0, 2, 0),
+		]);
+		assert_eq!(after, before);
+	}
+
+	#[test]
+	fn dead_upvalues_renumbered() {
+		// The grandchild uses b through the child, which uses c itself.
+		let mut grandchild = FunctionBuilder::new();
+		grandchild.upvalue(Upvalue::Outer(0), "unused");
+		grandchild.upvalue(Upvalue::Outer(2), "b");
+		grandchild.emit(encode(Opcode::GetUpval, 0, 1, 0));
+		grandchild.emit(encode(Opcode::Return, 0, 2, 0));
+		let mut child = FunctionBuilder::new();
+		child.upvalue(Upvalue::Outer(0), "a");
+		child.upvalue(Upvalue::Outer(1), "unused");
+		child.upvalue(Upvalue::Outer(2), "b");
+		child.upvalue(Upvalue::Outer(3), "c");
+		child.emit(encode(Opcode::SetTabUp, 3, RK::K(0).encode(), RK::K(0).encode()));
+		child.emit(encode(Opcode::Return, 0, 1, 0));
+		child.constant(Constant::Int(1));
+		child.function_mut().protos.push(grandchild.build());
+		let mut main = FunctionBuilder::new();
+		for name in &["a", "unused", "b", "c"] {
+			main.upvalue(Upvalue::Outer(0), name);
+		}
+		main.emit(encode(Opcode::GetUpval, 0, 3, 0));
+		main.emit(encode(Opcode::SetUpval, 0, 2, 0));
+		main.emit(encode(Opcode::Return, 0, 1, 0));
+		main.function_mut().protos.push(child.build());
+		let mut function = main.build();
+		remove_dead_upvalues(&mut function);
+
+		assert_eq!(function.debug.upvalues, ["b", "c"]);
+		assert_eq!(function.code[..2], [encode(Opcode::GetUpval, 0, 1, 0), encode(Opcode::SetUpval, 0, 0, 0)]);
+		let child = &function.protos[0];
+		assert_eq!(child.upvalues, [Upvalue::Outer(0), Upvalue::Outer(1)]);
+		assert_eq!(child.debug.upvalues, ["b", "c"]);
+		assert_eq!(child.code[0], encode(Opcode::SetTabUp, 1, RK::K(0).encode(), RK::K(0).encode()));
+		let grandchild = &child.protos[0];
+		assert_eq!(grandchild.upvalues, [Upvalue::Outer(0)]);
+		assert_eq!(grandchild.code[0], encode(Opcode::GetUpval, 0, 0, 0));
+	}
+}
@@ -0,0 +1,271 @@
+//! Structural deltas between two versions of a function.
+//!
+//! A `Patch` records only the parts of a function which changed, so it is
+//! usually much smaller than the new function when little has changed.
+
+use super::{Int, Instruction, Constant, Upvalue, Debug, Function};
+
+/// The changes which turn one `Function` into another.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Patch {
+	/// The new source name, if changed.
+	pub source: Option<String>,
+	/// The new start line, if changed.
+	pub line_start: Option<Int>,
+	/// The new end line, if changed.
+	pub line_end: Option<Int>,
+	/// The new number of fixed parameters, if changed.
+	pub num_params: Option<u8>,
+	/// The new vararg flag, if changed.
+	pub is_vararg: Option<bool>,
+	/// The new stack size, if changed.
+	pub max_stack_size: Option<u8>,
+	/// The new length of the code, if changed.
+	pub code_len: Option<usize>,
+	/// Instructions which differ from or were added after the old code, by pc.
+	pub code: Vec<(usize, Instruction)>,
+	/// The new length of the constant table, if changed.
+	pub constants_len: Option<usize>,
+	/// Constants which differ from or were added after the old ones, by index.
+	pub constants: Vec<(usize, Constant)>,
+	/// The new upvalue table, if changed.
+	pub upvalues: Option<Vec<Upvalue>>,
+	/// The new number of nested functions, if changed.
+	pub protos_len: Option<usize>,
+	/// Nested functions which changed or were added, by index.
+	pub protos: Vec<(usize, ProtoChange)>,
+	/// The new debug information, if changed.
+	pub debug: Option<Debug>,
+}
+
+/// A change to a nested function.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProtoChange {
+	/// An existing nested function was changed.
+	Patch(Box<Patch>),
+	/// A nested function was added past the end of the old ones.
+	Add(Function),
+}
+
+impl Patch {
+	/// Whether this patch makes no changes.
+	pub fn is_empty(&self) -> bool {
+		self.source.is_none() && self.line_start.is_none() && self.line_end.is_none() &&
+			self.num_params.is_none() && self.is_vararg.is_none() &&
+			self.max_stack_size.is_none() && self.code_len.is_none() &&
+			self.code.is_empty() && self.constants_len.is_none() &&
+			self.constants.is_empty() && self.upvalues.is_none() &&
+			self.protos_len.is_none() && self.protos.is_empty() && self.debug.is_none()
+	}
+}
+
+fn changed<T: Clone + PartialEq>(old: &T, new: &T) -> Option<T> {
+	if old != new { Some(new.clone()) } else { None }
+}
+
+/// Whether two constants are identical, comparing floats by their bits.
+fn same_constant(a: &Constant, b: &Constant) -> bool {
+	match (a, b) {
+		(&Constant::Float(a), &Constant::Float(b)) => a.to_bits() == b.to_bits(),
+		(a, b) => a == b,
+	}
+}
+
+/// Compute the changes which turn `old` into `new`.
+///
+/// Lengths which changed are recorded, and elements are compared by index,
+/// so removing an element from the middle records each one after it.
+pub fn diff(old: &Function, new: &Function) -> Patch {
+	let code = new.code.iter().enumerate()
+		.filter(|&(pc, instr)| old.code.get(pc) != Some(instr))
+		.map(|(pc, &instr)| (pc, instr))
+		.collect();
+	let constants = new.constants.iter().enumerate()
+		.filter(|&(idx, cons)| !old.constants.get(idx).is_some_and(|old| same_constant(old, cons)))
+		.map(|(idx, cons)| (idx, cons.clone()))
+		.collect();
+	let mut protos = Vec::new();
+	for (idx, proto) in new.protos.iter().enumerate() {
+		match old.protos.get(idx) {
+			Some(old_proto) => {
+				let patch = diff(old_proto, proto);
+				if !patch.is_empty() {
+					protos.push((idx, ProtoChange::Patch(Box::new(patch))));
+				}
+			}
+			None => protos.push((idx, ProtoChange::Add(proto.clone()))),
+		}
+	}
+
+	Patch {
+		source: changed(&old.source, &new.source),
+		line_start: changed(&old.line_start, &new.line_start),
+		line_end: changed(&old.line_end, &new.line_end),
+		num_params: changed(&old.num_params, &new.num_params),
+		is_vararg: changed(&old.is_vararg, &new.is_vararg),
+		max_stack_size: changed(&old.max_stack_size, &new.max_stack_size),
+		code_len: changed(&old.code.len(), &new.code.len()),
+		code,
+		constants_len: changed(&old.constants.len(), &new.constants.len()),
+		constants,
+		upvalues: changed(&old.upvalues, &new.upvalues),
+		protos_len: changed(&old.protos.len(), &new.protos.len()),
+		protos,
+		debug: changed(&old.debug, &new.debug),
+	}
+}
+
+/// Apply a patch to the function it was computed from.
+///
+/// # Panics
+///
+/// Panics if the patch changes a nested function which `old` lacks, which
+/// can only happen if it was computed from a different function.
+pub fn apply(old: &Function, patch: &Patch) -> Function {
+	let mut new = old.clone();
+	apply_in_place(&mut new, patch);
+	new
+}
+
+fn apply_in_place(function: &mut Function, patch: &Patch) {
+	if let Some(ref source) = patch.source {
+		function.source = source.clone();
+	}
+	if let Some(line_start) = patch.line_start {
+		function.line_start = line_start;
+	}
+	if let Some(line_end) = patch.line_end {
+		function.line_end = line_end;
+	}
+	if let Some(num_params) = patch.num_params {
+		function.num_params = num_params;
+	}
+	if let Some(is_vararg) = patch.is_vararg {
+		function.is_vararg = is_vararg;
+	}
+	if let Some(max_stack_size) = patch.max_stack_size {
+		function.max_stack_size = max_stack_size;
+	}
+
+	if let Some(len) = patch.code_len {
+		function.code.resize(len, 0);
+	}
+	for &(pc, instr) in &patch.code {
+		function.code[pc] = instr;
+	}
+	if let Some(len) = patch.constants_len {
+		function.constants.resize(len, Constant::Nil);
+	}
+	for &(idx, ref cons) in &patch.constants {
+		function.constants[idx] = cons.clone();
+	}
+	if let Some(ref upvalues) = patch.upvalues {
+		function.upvalues = upvalues.clone();
+	}
+
+	if let Some(len) = patch.protos_len {
+		function.protos.truncate(len);
+	}
+	for &(idx, ref change) in &patch.protos {
+		match *change {
+			ProtoChange::Patch(ref patch) => apply_in_place(&mut function.protos[idx], patch),
+			ProtoChange::Add(ref proto) => function.protos.push(proto.clone()),
+		}
+	}
+	if let Some(ref debug) = patch.debug {
+		function.debug = debug.clone();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use builder::FunctionBuilder;
+	use bytecode::{Opcode, encode, encode_bx};
+	use super::super::{Constant, Function};
+	use super::{ProtoChange, apply, diff};
+
+	fn function(constants: &[Constant], code: &[u32], protos: Vec<Function>) -> Function {
+		let mut builder = FunctionBuilder::main_chunk();
+		for constant in constants {
+			builder.constant(constant.clone());
+		}
+		for &instr in code {
+			builder.emit(instr);
+		}
+		let mut function = builder.build();
+		function.protos = protos;
+		function.debug.lineinfo = (1..=function.code.len() as i32).collect();
+		function
+	}
+
+	fn roundtrip(old: &Function, new: &Function) {
+		let patch = diff(old, new);
+		assert_eq!(patch.is_empty(), old == new);
+		assert_eq!(&apply(old, &patch), new);
+	}
+
+	fn nested(value: i64) -> Function {
+		function(&[Constant::Int(value)], &[encode_bx(Opcode::LoadK, 0, 0), encode(Opcode::Return, 0, 2, 0)], vec![])
+	}
+
+	fn old() -> Function {
+		function(
+			&[Constant::ShortString("a".into()), Constant::Float(0.5), Constant::Int(3)],
+			&[
+				encode_bx(Opcode::LoadK, 0, 0),
+				encode_bx(Opcode::LoadK, 1, 1),
+				encode_bx(Opcode::LoadK, 2, 2),
+				encode_bx(Opcode::Closure, 3, 0),
+				encode(Opcode::Return, 0, 1, 0),
+			],
+			vec![nested(1), nested(2)],
+		)
+	}
+
+	#[test]
+	fn roundtrip_unchanged() {
+		roundtrip(&old(), &old());
+	}
+
+	#[test]
+	fn roundtrip_shrink() {
+		// Code and constants removed from the middle, and a nested function
+		// patched and another removed.
+		let new = function(
+			&[Constant::ShortString("a".into()), Constant::Int(3)],
+			&[
+				encode_bx(Opcode::LoadK, 0, 0),
+				encode_bx(Opcode::LoadK, 2, 1),
+				encode(Opcode::Return, 0, 1, 0),
+			],
+			vec![nested(5)],
+		);
+		let patch = diff(&old(), &new);
+		assert_eq!((patch.code_len, patch.constants_len, patch.protos_len), (Some(3), Some(2), Some(1)));
+		assert!(matches!(patch.protos[..], [(0, ProtoChange::Patch(_))]));
+		roundtrip(&old(), &new);
+	}
+
+	#[test]
+	fn roundtrip_grow() {
+		// Code and constants added, floats compared by their bits, and a
+		// nested function added.
+		let mut new = old();
+		new.constants[1] = Constant::Float(-0.0);
+		new.constants.push(Constant::LongString("b".repeat(50)));
+		new.constants.push(Constant::Boolean(true));
+		new.code.insert(4, encode_bx(Opcode::Closure, 4, 2));
+		new.debug.lineinfo.push(6);
+		new.protos.push(nested(3));
+		new.max_stack_size = 5;
+		let patch = diff(&old(), &new);
+		assert!(matches!(patch.protos[..], [(2, ProtoChange::Add(_))]));
+		roundtrip(&old(), &new);
+
+		let mut zero = old();
+		zero.constants[1] = Constant::Float(0.0);
+		let mut negative = old();
+		negative.constants[1] = Constant::Float(-0.0);
+		assert_eq!(diff(&zero, &negative).constants, [(1, Constant::Float(-0.0))]);
+	}
+}
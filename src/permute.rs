@@ -0,0 +1,235 @@
+//! Register renumbering by an explicit permutation.
+
+use std::io;
+
+use super::analysis::open_top;
+use super::bytecode::{DecodedInstruction, Opcode, Operand, RK, get_opcode, get_a, get_b, get_c};
+use super::{Function, Upvalue};
+
+impl Function {
+	/// Move the value in each register `r` to register `perm[r]`, rewriting
+	/// every instruction which refers to a register to match. The
+	/// `Upvalue::Stack` entries of immediately nested functions are updated,
+	/// but nested functions are otherwise not affected.
+	///
+	/// `perm` must be a permutation of `0..max_stack_size`. Some registers
+	/// cannot be moved freely, and this fails without changing anything if
+	/// the permutation would move them:
+	///
+	/// * Parameters, which the caller places in the lowest registers, and
+	///   named local variables, which Lua assigns to registers by their
+	///   position in `debug.localvars`, must stay where they are.
+	/// * Runs of registers used together, such as a call's function and
+	///   arguments, `Concat`'s operands, and the control registers of `for`
+	///   loops, must stay together and in order. Where a run extends to the
+	///   stack top, every register above its start must.
+	/// * A `Jump` which closes upvalues from a register up must still find
+	///   the same registers above it.
+	pub fn permute_registers(&mut self, perm: &[u8]) -> io::Result<()> {
+		let size = self.max_stack_size as usize;
+		if perm.len() != size {
+			return invalid(format!(
+				"permutation has {} entries but there are {} registers",
+				perm.len(), size,
+			));
+		}
+		let mut seen = vec![false; size];
+		for &reg in perm {
+			match seen.get_mut(reg as usize) {
+				Some(seen) if !*seen => *seen = true,
+				_ => return invalid(format!("R{} appears twice or is out of range in permutation", reg)),
+			}
+		}
+
+		if let Some(reg) = (0..self.num_params as usize).find(|&reg| perm.get(reg).is_some_and(|&new| new as usize != reg)) {
+			return invalid(format!("cannot move parameter in R{}", reg));
+		}
+		let vars = &self.debug.localvars;
+		for (i, var) in vars.iter().enumerate() {
+			let reg = vars[..i].iter()
+				.filter(|outer| outer.start_pc <= var.start_pc && var.start_pc < outer.end_pc)
+				.count();
+			if reg < size && perm[reg] as usize != reg {
+				return invalid(format!("cannot move local variable {} in R{}", var.name, reg));
+			}
+		}
+
+		for pc in 0..self.code.len() {
+			let instr = self.code[pc];
+			let op = match get_opcode(instr) {
+				Some(op) => op,
+				None => continue,
+			};
+			if op == Opcode::Jump && get_a(instr) > 0 {
+				let base = get_a(instr) as usize - 1;
+				if base < size && (0..size).any(|reg| (reg >= base) != (perm[reg] >= perm[base])) {
+					return invalid(format!(
+						"Jump at pc {} would close upvalues of different registers than R{} and above",
+						pc, base,
+					));
+				}
+			}
+			if let Some((first, last)) = register_run(&self.code, pc) {
+				let last = last.map_or(size, |last| last + 1).min(size);
+				if first < size && (first..last).any(|reg| perm[reg] as usize != perm[first] as usize + (reg - first)) {
+					return invalid(format!(
+						"{:?} at pc {} uses R{} to R{} together, which must stay in order",
+						op, pc, first, last - 1,
+					));
+				}
+			}
+		}
+
+		let map = |reg: i32| perm.get(reg as usize).map_or(reg, |&new| new as i32);
+		let remap = |role: Operand, value: &mut i32| match role {
+			Operand::Register => *value = map(*value),
+			Operand::RegisterOrConstant => if let RK::R(reg) = RK::decode(*value as u32) {
+				*value = map(reg as i32);
+			},
+			_ => {}
+		};
+		for instr in &mut self.code {
+			let mut decoded = match DecodedInstruction::decode(*instr) {
+				Some(decoded) => decoded,
+				None => continue,
+			};
+			let mode = decoded.opcode.mode();
+			remap(mode.a, &mut decoded.a);
+			remap(mode.b, &mut decoded.b);
+			remap(mode.c, &mut decoded.c);
+			// A nonzero `A` of `Jump` closes upvalues from register `A - 1` up.
+			if decoded.opcode == Opcode::Jump && decoded.a > 0 {
+				decoded.a = map(decoded.a - 1) + 1;
+			}
+			*instr = decoded.encode();
+		}
+		for proto in &mut self.protos {
+			for upval in &mut proto.upvalues {
+				if let Upvalue::Stack(ref mut reg) = *upval {
+					*reg = perm.get(*reg as usize).cloned().unwrap_or(*reg);
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// The first and last registers of the run an instruction uses together,
+/// with `None` for the last if the run extends to the stack top.
+fn register_run(code: &[u32], pc: usize) -> Option<(usize, Option<usize>)> {
+	let instr = code[pc];
+	let (a, b, c) = (get_a(instr) as usize, get_b(instr) as usize, get_c(instr) as usize);
+	// The last register of a run counted as in `Call`, where zero means up
+	// to the stack top.
+	let counted = |start: usize, count: usize| if count == 0 { None } else { Some(start + count - 1) };
+	let op = get_opcode(instr)?;
+	let run = match op {
+		Opcode::LoadNil => (a, Some(a + b)),
+		Opcode::Self_ | Opcode::TForLoop => (a, Some(a + 1)),
+		Opcode::Concat => (b, Some(c)),
+		Opcode::ForLoop | Opcode::ForPrep => (a, Some(a + 3)),
+		// The iterator, state and control variable are copied above
+		// themselves for the call, whatever the number of results.
+		Opcode::TForCall => (a, Some(a + 2 + c.max(3))),
+		Opcode::Call | Opcode::TailCall => {
+			// The arguments may come from an open instruction before, and
+			// the results may be left up to the stack top.
+			let args = if b == 0 && open_top(code, pc).is_some() { None } else { counted(a, b.max(1)) };
+			let results = match (op, c) {
+				(Opcode::TailCall, _) => Some(a),
+				(_, 0) => None,
+				(_, c) => Some(a + c.max(2) - 2),
+			};
+			match (args, results) {
+				(Some(args), Some(results)) => (a, Some(args.max(results))),
+				_ => (a, None),
+			}
+		}
+		Opcode::Return | Opcode::VarArg => {
+			if b == 1 {
+				return None;
+			}
+			(a, counted(a, b).map(|last| last - 1))
+		}
+		Opcode::SetList => (a, if b == 0 { None } else { Some(a + b) }),
+		_ => return None,
+	};
+	Some(run)
+}
+
+fn invalid<T>(msg: String) -> io::Result<T> {
+	Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+}
+
+#[cfg(test)]
+mod tests {
+	use builder::FunctionBuilder;
+	use bytecode::{Opcode, encode, encode_bx, encode_sbx};
+	use super::super::{Constant, Function, LocalVar};
+
+	fn function(max_stack_size: u8, code: &[u32]) -> Function {
+		let mut builder = FunctionBuilder::new();
+		builder.function_mut().max_stack_size = max_stack_size;
+		builder.constant(Constant::ShortString("s".into()));
+		for &instr in code {
+			builder.emit(instr);
+		}
+		builder.build()
+	}
+
+	/// Check that permuting fails with a message containing `expected`,
+	/// leaving the function unchanged.
+	fn rejected(mut function: Function, perm: &[u8], expected: &str) {
+		let original = function.clone();
+		let err = function.permute_registers(perm).unwrap_err();
+		assert!(err.to_string().contains(expected), "{}", err);
+		assert_eq!(function, original);
+	}
+
+	#[test]
+	fn permute_runs() {
+		let mut function = function(6, &[
+			encode_bx(Opcode::LoadK, 0, 0),
+			encode_bx(Opcode::LoadK, 1, 0),
+			encode_bx(Opcode::LoadK, 2, 0),
+			encode(Opcode::Concat, 3, 1, 2),
+			encode(Opcode::Call, 0, 2, 2),
+			encode(Opcode::NewTable, 4, 1, 0),
+			encode(Opcode::SetList, 4, 1, 1),
+			encode(Opcode::Return, 3, 2, 0),
+		]);
+		function.permute_registers(&[3, 4, 5, 0, 1, 2]).unwrap();
+		assert_eq!(function.code, [
+			encode_bx(Opcode::LoadK, 3, 0),
+			encode_bx(Opcode::LoadK, 4, 0),
+			encode_bx(Opcode::LoadK, 5, 0),
+			encode(Opcode::Concat, 0, 4, 5),
+			encode(Opcode::Call, 3, 2, 2),
+			encode(Opcode::NewTable, 1, 1, 0),
+			encode(Opcode::SetList, 1, 1, 1),
+			encode(Opcode::Return, 0, 2, 0),
+		]);
+	}
+
+	#[test]
+	fn permute_rejected() {
+		let mut params = function(2, &[encode(Opcode::Return, 0, 1, 0)]);
+		params.num_params = 1;
+		rejected(params, &[1, 0], "parameter in R0");
+
+		let mut local = function(2, &[encode(Opcode::LoadNil, 0, 0, 0), encode(Opcode::Return, 0, 1, 0)]);
+		local.debug.localvars.push(LocalVar { name: "x".into(), start_pc: 1, end_pc: 2 });
+		rejected(local, &[1, 0], "local variable x in R0");
+
+		let call = function(2, &[encode(Opcode::Call, 0, 2, 1), encode(Opcode::Return, 0, 1, 0)]);
+		rejected(call, &[1, 0], "R0 to R1 together");
+
+		// The copies of the iterator, state and control variable are a run
+		// too, whatever the number of results.
+		let tfor = function(6, &[encode(Opcode::TForCall, 0, 0, 1), encode(Opcode::Return, 0, 1, 0)]);
+		rejected(tfor, &[0, 1, 2, 3, 5, 4], "R0 to R5 together");
+
+		let jump = function(3, &[encode_sbx(Opcode::Jump, 2, 0), encode(Opcode::Return, 0, 1, 0)]);
+		rejected(jump, &[1, 0, 2], "R1 and above");
+	}
+}
@@ -0,0 +1,16 @@
+//! The most commonly used items, for glob import.
+//!
+//! ```
+//! use lua_kit::prelude::*;
+//!
+//! let mut builder = FunctionBuilder::main_chunk();
+//! builder.emit(encode(Opcode::Return, 0, 1, 0));
+//! let function = builder.build();
+//! let bytes = to_bytes(&function).unwrap();
+//! assert_eq!(read_file(&bytes[..]).unwrap(), function);
+//! ```
+
+pub use super::builder::FunctionBuilder;
+pub use super::bytecode::{Opcode, RK, encode, encode_ax, encode_bx, encode_sbx};
+pub use super::{Constant, Debug, Function, LocalVar, Upvalue};
+pub use super::{read_file, to_bytes, write_file};
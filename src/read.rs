@@ -1,29 +1,754 @@
 //! Deserialization code.
 
-use std::io::{self, Read};
+use std::error::Error;
+use std::fmt;
+use std::iter;
+use std::io::{self, Read, Seek, SeekFrom};
 use std::mem::size_of;
-use byteorder::ReadBytesExt;
-use byteorder::NativeEndian as E;
+use std::marker::PhantomData;
+use byteorder::{ByteOrder, ReadBytesExt, BigEndian, LittleEndian, NativeEndian};
 
+use super::layout::{Layout, DEFAULT_LAYOUT};
+use super::string_len::V53;
 use super::{
 	SIGNATURE, FORMAT, VERSION, DATA, TEST_INT, TEST_NUMBER,
-	Int, Size, Instruction, Integer, Number,
+	Int, Instruction, Integer, Number,
 	Constant, Upvalue, LocalVar, Debug, Function,
 };
 
 /// Deserialize bytecode into a `Function`.
 pub fn read_file<R: Read>(read: R) -> io::Result<Function> {
-	let mut reader = Reader { out: read };
-	try!(reader.read_header());
-	try!(reader.out.read_u8()); // discard upvals header
-	reader.read_function()
+	read_file_with_offsets(read).map(|(function, _)| function)
 }
 
-struct Reader<R: Read> {
-	out: R,
+/// Deserialize bytecode into a `Function`, recording where each of its
+/// parts was found in the input.
+pub fn read_file_with_offsets<R: Read>(read: R) -> io::Result<(Function, OffsetMap)> {
+	read_chunk(read, &ReadOptions::default())
 }
 
-fn invalid<T, S: Into<Box<::std::error::Error + Send + Sync>>>(s: S) -> io::Result<T> {
+/// Deserialize bytecode into a `Function`, returning the byte range each
+/// function occupies as `(start, end)`, with `end` exclusive.
+///
+/// Ranges are listed depth-first, starting with the main function, and
+/// are positions in the stream rather than relative to where reading
+/// began, for editing the bytes of a function in place. A function's range
+/// includes its nested functions.
+pub fn read_file_with_ranges<R: Read + Seek>(mut read: R) -> io::Result<(Function, Vec<(usize, usize)>)> {
+	let base = read.stream_position()?;
+	let (function, offsets) = read_file_with_offsets(read)?;
+	let mut ranges = Vec::new();
+	collect_ranges(&offsets, base, &mut ranges);
+	Ok((function, ranges))
+}
+
+fn collect_ranges(offsets: &OffsetMap, base: u64, ranges: &mut Vec<(usize, usize)>) {
+	ranges.push(((base + offsets.start) as usize, (base + offsets.end) as usize));
+	for nested in &offsets.nested {
+		collect_ranges(nested, base, ranges);
+	}
+}
+
+/// Deserialize bytecode into a `Function`, returning everything after it
+/// in the input as a trailer.
+///
+/// This reads chunks written by `write_file_with_trailer`; for others the
+/// trailer is usually empty.
+pub fn read_file_with_trailer<R: Read>(mut read: R) -> io::Result<(Function, Vec<u8>)> {
+	let function = read_file(&mut read)?;
+	let mut trailer = Vec::new();
+	read.read_to_end(&mut trailer)?;
+	Ok((function, trailer))
+}
+
+/// Deserialize a series of complete chunks, each with its own header,
+/// until the end of the input.
+///
+/// Iteration stops after the first error.
+pub fn read_all_chunks<R: Read>(mut read: R) -> impl Iterator<Item = io::Result<Function>> {
+	let mut done = false;
+	iter::from_fn(move || {
+		if done {
+			return None;
+		}
+		// Distinguish a clean end of input from the start of another chunk.
+		let mut first = [0u8; 1];
+		let result = loop {
+			match read.read(&mut first) {
+				Ok(0) => {
+					done = true;
+					return None;
+				}
+				Ok(_) => break read_file((&first[..]).chain(&mut read)),
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+				Err(e) => break Err(e),
+			}
+		};
+		done = result.is_err();
+		Some(result)
+	})
+}
+
+/// Deserialize bytecode into a `Function` with non-default options.
+pub fn read_file_with_options<R: Read>(read: R, options: &ReadOptions) -> io::Result<Function> {
+	read_chunk(read, options).map(|(function, _)| function)
+}
+
+/// Deserialize bytecode into a `Function`, seeking past the debug
+/// information rather than reading it.
+///
+/// Every function is returned with `Debug::none()`. A chunk truncated
+/// within the debug information is detected by comparing where each seek
+/// lands with the length of the stream.
+pub fn read_file_no_debug<R: Read + Seek>(read: R) -> io::Result<Function> {
+	let mut reader = Reader::<R, NativeEndian>::new(read, &ReadOptions::default());
+	reader.skip = Some(|inner, n| {
+		let target = inner.seek(SeekFrom::Current(n as i64))?;
+		let end = inner.seek(SeekFrom::End(0))?;
+		inner.seek(SeekFrom::Start(target.min(end)))?;
+		if target > end {
+			return Err(io::ErrorKind::UnexpectedEof.into());
+		}
+		Ok(())
+	});
+	reader.read_chunk().map(|(function, _)| function).map_err(|e| reader.annotate(e))
+}
+
+/// Deserialize bytecode into a `Function`, recording problems which can be
+/// worked around rather than failing on them.
+///
+/// Strings which are not UTF-8 are read as by `ReadOptions::lossy_strings`.
+/// Debug information which does not match its function is made to: line
+/// info is padded or truncated to the length of the code, local variable
+/// scopes are clamped to the code, and upvalue names beyond the number of
+/// upvalues are dropped. Other problems, such as a bad header or truncated
+/// input, still fail.
+///
+/// So do constants of unknown types, since nothing says how long they are
+/// and so where the rest of the chunk starts. Use
+/// `read_file_lenient_with_options` to give their sizes.
+pub fn read_file_lenient<R: Read>(read: R) -> io::Result<(Function, Vec<Diagnostic>)> {
+	read_file_lenient_with_options(read, &ReadOptions::default())
+}
+
+/// Deserialize bytecode into a `Function` as `read_file_lenient` does, but
+/// with non-default options.
+///
+/// Constants of the unknown types given by `raw_constant_sizes` are read as
+/// `Constant::Raw`, with a diagnostic for each, rather than failing.
+pub fn read_file_lenient_with_options<R: Read>(read: R, options: &ReadOptions) -> io::Result<(Function, Vec<Diagnostic>)> {
+	if options.layout.big_endian {
+		read_lenient_with_order::<BigEndian, _>(read, options)
+	} else {
+		read_lenient_with_order::<LittleEndian, _>(read, options)
+	}
+}
+
+fn read_lenient_with_order<B: ByteOrder, R: Read>(read: R, options: &ReadOptions) -> io::Result<(Function, Vec<Diagnostic>)> {
+	let mut reader = Reader::<R, B>::new(read, options);
+	reader.diagnostics = Some(Vec::new());
+	let (function, _) = reader.read_chunk().map_err(|e| reader.annotate(e))?;
+	Ok((function, reader.diagnostics.unwrap_or_default()))
+}
+
+/// A problem worked around by `read_file_lenient`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+	/// The offset in the input of the part with the problem.
+	pub offset: u64,
+	/// The indices of the nested functions leading to the function with the
+	/// problem, empty for the main function.
+	pub path: Vec<u32>,
+	/// A description of the problem.
+	pub message: String,
+}
+
+/// Deserialize little-endian bytecode into a `Function`, regardless of the
+/// host's byte order.
+///
+/// The header's test integer and number are validated as little-endian, so
+/// chunks written by a big-endian Lua are rejected.
+pub fn read_file_le<R: Read>(read: R) -> io::Result<Function> {
+	let options = ReadOptions { layout: Layout { big_endian: false, ..DEFAULT_LAYOUT }, ..ReadOptions::default() };
+	read_file_with_options(read, &options)
+}
+
+/// Deserialize big-endian bytecode into a `Function`, regardless of the
+/// host's byte order.
+///
+/// The header's test integer and number are validated as big-endian, so
+/// chunks written by a little-endian Lua are rejected.
+pub fn read_file_be<R: Read>(read: R) -> io::Result<Function> {
+	let options = ReadOptions { layout: Layout { big_endian: true, ..DEFAULT_LAYOUT }, ..ReadOptions::default() };
+	read_file_with_options(read, &options)
+}
+
+/// A version of Lua, as identified by a chunk's version byte.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LuaVersion {
+	/// Lua 5.1, version byte `0x51`.
+	Lua51,
+	/// Lua 5.2, version byte `0x52`.
+	Lua52,
+	/// Lua 5.3, version byte `0x53`.
+	Lua53,
+	/// Lua 5.4, version byte `0x54`.
+	Lua54,
+}
+
+impl LuaVersion {
+	/// Identify a version byte, if it is one of the known versions.
+	pub fn from_byte(byte: u8) -> Option<LuaVersion> {
+		match byte {
+			0x51 => Some(LuaVersion::Lua51),
+			0x52 => Some(LuaVersion::Lua52),
+			0x53 => Some(LuaVersion::Lua53),
+			0x54 => Some(LuaVersion::Lua54),
+			_ => None,
+		}
+	}
+
+	/// The version byte, in the form `(MAJOR << 4) | MINOR`.
+	pub fn byte(self) -> u8 {
+		match self {
+			LuaVersion::Lua51 => 0x51,
+			LuaVersion::Lua52 => 0x52,
+			LuaVersion::Lua53 => 0x53,
+			LuaVersion::Lua54 => 0x54,
+		}
+	}
+}
+
+/// Deserialize bytecode into a `Function`, choosing how to read it by the
+/// version byte in its header.
+///
+/// Only Lua 5.3 chunks can be read so far. Chunks of the other known
+/// versions fail saying so, and anything else fails as `read_file` would.
+pub fn read_any<R: Read>(mut read: R) -> io::Result<(LuaVersion, Function)> {
+	let mut prefix = Vec::new();
+	(&mut read).take(SIGNATURE.len() as u64 + 1).read_to_end(&mut prefix)?;
+	let version = match prefix.split_last() {
+		Some((&byte, signature)) if signature == SIGNATURE => LuaVersion::from_byte(byte),
+		_ => None,
+	};
+	match version {
+		Some(LuaVersion::Lua53) | None => {
+			let function = read_file(io::Cursor::new(prefix).chain(read))?;
+			Ok((LuaVersion::Lua53, function))
+		}
+		Some(other) => invalid(format!(
+			"Lua {}.{} chunks cannot be read yet", other.byte() >> 4, other.byte() & 0xf,
+		)),
+	}
+}
+
+fn read_chunk<R: Read>(read: R, options: &ReadOptions) -> io::Result<(Function, OffsetMap)> {
+	if options.layout.big_endian {
+		read_chunk_with_order::<BigEndian, _>(read, options)
+	} else {
+		read_chunk_with_order::<LittleEndian, _>(read, options)
+	}
+}
+
+fn read_chunk_with_order<B: ByteOrder, R: Read>(read: R, options: &ReadOptions) -> io::Result<(Function, OffsetMap)> {
+	let mut reader = Reader::<R, B>::new(read, options);
+	let result = reader.read_chunk().map_err(|e| reader.annotate(e))?;
+	if options.strict_eof {
+		check_trailing(&mut reader.out.inner, options.max_trailing_padding)?;
+	}
+	Ok(result)
+}
+
+/// Check that at most `max_padding` padding bytes remain in the input.
+fn check_trailing<R: Read>(read: R, max_padding: usize) -> io::Result<()> {
+	let mut rest = Vec::new();
+	read.take(max_padding as u64 + 1).read_to_end(&mut rest)?;
+	if let Some(&byte) = rest.iter().find(|&&b| !matches!(b, 0 | b' ' | b'\t' | b'\r' | b'\n')) {
+		return invalid(format!("unexpected trailing byte {:#04x} after main function", byte));
+	}
+	if rest.len() > max_padding {
+		return invalid(format!("more than {} bytes of trailing padding after main function", max_padding));
+	}
+	Ok(())
+}
+
+/// Options controlling how bytecode is deserialized.
+#[derive(Clone, Debug)]
+pub struct ReadOptions {
+	/// Replace invalid UTF-8 in strings with U+FFFD rather than failing.
+	///
+	/// This loses data, so chunks read this way may not be written back
+	/// faithfully. Useful for inspecting chunks with latin-1 strings.
+	pub lossy_strings: bool,
+	/// Fail if anything follows the main function other than padding.
+	///
+	/// By default reading stops after the main function, as Lua does, and
+	/// the rest of the input is left unread.
+	pub strict_eof: bool,
+	/// With `strict_eof`, the number of padding bytes allowed after the
+	/// main function. Padding bytes are `\0`, space, `\t`, `\r`, and `\n`;
+	/// any other byte, or more padding than this, is an error. The default
+	/// of 2 allows the newline or CRLF left by some build tools.
+	pub max_trailing_padding: usize,
+	/// The version bytes to accept, by default only `VERSION`.
+	///
+	/// Chunks of other versions are read as Lua 5.3 chunks, so this only
+	/// suits builds which changed the version byte but not the format.
+	pub versions: Vec<u8>,
+	/// The format bytes to accept, by default only `FORMAT`, with the same
+	/// caveat as `versions`.
+	pub formats: Vec<u8>,
+	/// The sizes in bytes of constants of types this crate does not know,
+	/// as pairs of type tag and size, to read them as `Constant::Raw`.
+	///
+	/// Constants of unknown types cannot otherwise be read, since nothing
+	/// says how long they are. Tags of known types are always read as such.
+	pub raw_constant_sizes: Vec<(u8, usize)>,
+	/// The sizes and byte order chunks must have, by default the host's.
+	///
+	/// The header must declare the same sizes. Its instruction size is
+	/// accepted whatever it is, as long as the instructions fit.
+	pub layout: Layout,
+}
+
+impl Default for ReadOptions {
+	fn default() -> ReadOptions {
+		ReadOptions {
+			lossy_strings: false,
+			strict_eof: false,
+			max_trailing_padding: 2,
+			versions: vec![VERSION],
+			formats: vec![FORMAT],
+			raw_constant_sizes: vec![],
+			layout: DEFAULT_LAYOUT,
+		}
+	}
+}
+
+/// A parser which is fed bytecode as it arrives rather than reading it.
+///
+/// This suits sources which cannot be read through `Read`, such as async
+/// streams:
+///
+/// ```ignore
+/// let mut parser = ChunkParser::new();
+/// let mut buf = [0; 4096];
+/// let function = loop {
+///     let n = stream.read(&mut buf).await?;
+///     if n == 0 {
+///         return Err(io::ErrorKind::UnexpectedEof.into());
+///     }
+///     if let Some(function) = parser.feed(&buf[..n])? {
+///         break function;
+///     }
+/// };
+/// ```
+///
+/// Parsing resumes where the last call ran out, at the start of the string,
+/// instruction, constant, or other element it could not complete, so each
+/// byte is parsed about once however the input is split. Counts of arrays
+/// read so far are used to wait for enough input to get past them, so code
+/// and long strings are not retried as their bytes arrive.
+///
+/// Input is buffered until its chunk is complete. Once the lengths and
+/// counts read show the chunk must be longer than the maximum set with
+/// `set_max_len`, feeding fails rather than waiting for input which a
+/// corrupt count may have made up.
+#[derive(Clone, Debug)]
+pub struct ChunkParser {
+	buffer: Vec<u8>,
+	options: ReadOptions,
+	max_len: u64,
+	/// The buffer length below which parsing is certain to run out again.
+	needed: u64,
+	state: ParseState,
+}
+
+/// The longest chunk a `ChunkParser` accepts by default, 1 GiB.
+pub const DEFAULT_MAX_CHUNK_LEN: u64 = 1 << 30;
+
+/// How far a `ChunkParser` has got through its chunk.
+#[derive(Clone, Debug, Default)]
+struct ParseState {
+	/// The length of the buffer parsed so far.
+	pos: u64,
+	/// The upvalue count declared by the header, once it has been read.
+	upvalues: Option<u8>,
+	/// The width of an instruction in bytes, as declared by the header.
+	instruction_size: u8,
+	/// The functions begun but not finished, the main function first.
+	stack: Vec<PartialFunction>,
+}
+
+/// A function partly read by a `ChunkParser`.
+#[derive(Clone, Debug)]
+struct PartialFunction {
+	function: Function,
+	/// The array being read.
+	part: Part,
+	/// The elements of that array still to read, once its count is known.
+	remaining: Option<u32>,
+}
+
+/// The arrays of a function, in the order they are serialized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Part {
+	Code,
+	Constants,
+	Upvalues,
+	Protos,
+	LineInfo,
+	LocalVars,
+	UpvalueNames,
+}
+
+impl Default for ChunkParser {
+	fn default() -> ChunkParser {
+		ChunkParser::with_options(&ReadOptions::default())
+	}
+}
+
+impl ChunkParser {
+	/// Create a parser with the default options.
+	pub fn new() -> ChunkParser {
+		ChunkParser::default()
+	}
+
+	/// Create a parser with non-default options. `strict_eof` is ignored,
+	/// since the parser cannot know when its input has ended.
+	pub fn with_options(options: &ReadOptions) -> ChunkParser {
+		ChunkParser {
+			buffer: Vec::new(),
+			options: options.clone(),
+			max_len: DEFAULT_MAX_CHUNK_LEN,
+			needed: 0,
+			state: ParseState::default(),
+		}
+	}
+
+	/// Set the length in bytes beyond which a chunk is taken to be corrupt,
+	/// by default `DEFAULT_MAX_CHUNK_LEN`.
+	pub fn set_max_len(&mut self, max_len: u64) {
+		self.max_len = max_len;
+	}
+
+	/// Add bytes to the input and try to complete a chunk, returning `None`
+	/// if more bytes are needed.
+	///
+	/// Fails as `read_file` would if the input cannot be the start of a
+	/// valid chunk, or if it must be longer than the maximum length. Bytes
+	/// fed after a complete chunk are kept, so the parser can then be fed
+	/// the next one, like `read_all_chunks`.
+	pub fn feed(&mut self, bytes: &[u8]) -> io::Result<Option<Function>> {
+		self.buffer.extend_from_slice(bytes);
+		if (self.buffer.len() as u64) < self.needed {
+			return Ok(None);
+		}
+		if self.options.layout.big_endian {
+			self.parse::<BigEndian>()
+		} else {
+			self.parse::<LittleEndian>()
+		}
+	}
+
+	fn parse<B: ByteOrder>(&mut self) -> io::Result<Option<Function>> {
+		let pos = self.state.pos;
+		let mut reader = Reader::<&[u8], B>::new(&self.buffer[pos as usize..], &self.options);
+		reader.out.pos = pos;
+		match self.state.advance(&mut reader) {
+			Ok(_) if self.state.pos > self.max_len => self.too_long(self.state.pos),
+			Ok(function) => {
+				self.buffer.drain(..self.state.pos as usize);
+				self.state = ParseState::default();
+				self.needed = 0;
+				Ok(Some(function))
+			}
+			Err(_) if reader.out.eof => {
+				self.needed = reader.out.needed.max(self.state.expected(&self.options.layout));
+				if self.needed > self.max_len {
+					return self.too_long(self.needed);
+				}
+				Ok(None)
+			}
+			Err(e) => Err(e),
+		}
+	}
+
+	fn too_long<T>(&self, len: u64) -> io::Result<T> {
+		invalid(format!("chunk must be at least {} bytes long, more than the maximum of {}", len, self.max_len))
+	}
+
+	/// The bytes fed but not yet part of a complete chunk.
+	pub fn remainder(&self) -> &[u8] {
+		&self.buffer
+	}
+}
+
+impl ParseState {
+	/// Parse as much of the chunk as `reader` holds, keeping what has been
+	/// parsed and where it ended after each element.
+	fn advance<B: ByteOrder>(&mut self, reader: &mut Reader<&[u8], B>) -> io::Result<Function> {
+		let upvalues = match self.upvalues {
+			Some(upvalues) => upvalues,
+			None => {
+				reader.read_header()?;
+				let upvalues = reader.out.read_u8()?;
+				self.instruction_size = reader.instruction_size;
+				self.upvalues = Some(upvalues);
+				self.pos = reader.out.pos;
+				upvalues
+			}
+		};
+		reader.instruction_size = self.instruction_size;
+		if self.stack.is_empty() {
+			self.begin_function(reader)?;
+		}
+		loop {
+			let frame = self.stack.last_mut().expect("no function being parsed");
+			let remaining = match frame.remaining {
+				Some(remaining) => remaining,
+				None => {
+					let count = reader.read_count()?;
+					frame.remaining = Some(count);
+					self.pos = reader.out.pos;
+					count
+				}
+			};
+			if remaining == 0 {
+				frame.remaining = None;
+				frame.part = match frame.part {
+					Part::Code => Part::Constants,
+					Part::Constants => Part::Upvalues,
+					Part::Upvalues => Part::Protos,
+					Part::Protos => Part::LineInfo,
+					Part::LineInfo => Part::LocalVars,
+					Part::LocalVars => Part::UpvalueNames,
+					Part::UpvalueNames => {
+						let function = self.stack.pop().expect("no function being parsed").function;
+						match self.stack.last_mut() {
+							Some(parent) => {
+								parent.function.protos.push(function);
+								parent.remaining = parent.remaining.map(|n| n - 1);
+							}
+							None if upvalues as usize != function.upvalues.len() => return invalid(format!(
+								"header declares {} upvalues but the main function has {}",
+								upvalues, function.upvalues.len(),
+							)),
+							None => return Ok(function),
+						}
+						continue;
+					}
+				};
+				continue;
+			}
+			let function = &mut frame.function;
+			match frame.part {
+				Part::Code => {
+					let instr = reader.read_instruction(function.code.len())?;
+					function.code.push(instr);
+				}
+				Part::Constants => function.constants.push(reader.read_constant()?),
+				Part::Upvalues => function.upvalues.push(reader.read_upvalue()?),
+				Part::Protos => {
+					// The count is decremented once the nested function ends.
+					self.begin_function(reader)?;
+					continue;
+				}
+				Part::LineInfo => function.debug.lineinfo.push(reader.read_int()?),
+				Part::LocalVars => function.debug.localvars.push(reader.read_local_var()?),
+				Part::UpvalueNames => function.debug.upvalues.push(reader.read_string()?),
+			}
+			frame.remaining = Some(remaining - 1);
+			self.pos = reader.out.pos;
+		}
+	}
+
+	fn begin_function<B: ByteOrder>(&mut self, reader: &mut Reader<&[u8], B>) -> io::Result<()> {
+		let function = reader.read_function_head()?;
+		self.stack.push(PartialFunction { function, part: Part::Code, remaining: None });
+		self.pos = reader.out.pos;
+		Ok(())
+	}
+
+	/// The length the buffer must reach to hold the rest of the array being
+	/// read, given the least each of its elements can take.
+	fn expected(&self, layout: &Layout) -> u64 {
+		let frame = match self.stack.last() {
+			Some(frame) => frame,
+			None => return 0,
+		};
+		let min_size = match frame.part {
+			Part::Code => self.instruction_size as u64,
+			Part::Upvalues => 2,
+			Part::LineInfo => layout.int_size as u64,
+			Part::LocalVars => 1 + 2 * layout.int_size as u64,
+			Part::Constants | Part::Protos | Part::UpvalueNames => 1,
+		};
+		self.pos.saturating_add(frame.remaining.unwrap_or(0) as u64 * min_size)
+	}
+}
+
+/// Deserialize every function written by `write_framed` until the end of
+/// the input.
+pub fn read_framed<R: Read>(mut read: R) -> io::Result<Vec<Function>> {
+	let mut functions = Vec::new();
+	loop {
+		// Distinguish a clean end of input from a truncated length prefix.
+		let mut prefix = [0u8; 8];
+		let mut got = 0;
+		while got < prefix.len() {
+			match read.read(&mut prefix[got..]) {
+				Ok(0) => break,
+				Ok(n) => got += n,
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+				Err(e) => return Err(e),
+			}
+		}
+		if got == 0 {
+			return Ok(functions);
+		} else if got < prefix.len() {
+			return invalid("unexpected EOF in frame length");
+		}
+		let len = (&prefix[..]).read_u64::<LittleEndian>()?;
+
+		let mut frame = Vec::new();
+		(&mut read).take(len).read_to_end(&mut frame)?;
+		if (frame.len() as u64) < len {
+			return invalid(format!(
+				"unexpected EOF in frame {}: expected {} bytes but got {}",
+				functions.len(), len, frame.len(),
+			));
+		}
+		let (function, offsets) = read_file_with_offsets(&frame[..])?;
+		if offsets.end != len {
+			return invalid(format!(
+				"frame {} has {} bytes after its function",
+				functions.len(), len - offsets.end,
+			));
+		}
+		functions.push(function);
+	}
+}
+
+/// The byte offsets of the parts of a serialized function.
+///
+/// Offsets are measured from the start of the input. Where a part begins
+/// with a length prefix, its offset is that of the prefix.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OffsetMap {
+	/// The start of the function, which is its source name.
+	pub start: u64,
+	/// The start of the code.
+	pub code: u64,
+	/// The start of the constant pool.
+	pub constants: u64,
+	/// The start of each constant, which is its type tag.
+	pub constant_offsets: Vec<u64>,
+	/// The start of the upvalue table.
+	pub upvalues: u64,
+	/// The start of the nested function prototypes.
+	pub protos: u64,
+	/// The offsets within each nested function prototype.
+	pub nested: Vec<OffsetMap>,
+	/// The start of the debug information.
+	pub debug: u64,
+	/// The end of the function, exclusive.
+	pub end: u64,
+}
+
+/// The error for a header which is not that of Lua bytecode at all, as
+/// opposed to that of an unsupported build of Lua.
+///
+/// Reading fails with an `io::Error` of kind `InvalidInput` wrapping this,
+/// which can be recovered with `get_ref` and `downcast_ref`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorruptHeader {
+	/// The header field which did not match, `"signature"` or `"test data"`.
+	pub field: &'static str,
+	/// Whether the input starts with printable text, like Lua source.
+	pub looks_like_source: bool,
+}
+
+impl fmt::Display for CorruptHeader {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "corrupt header: invalid {}", self.field)?;
+		if self.looks_like_source {
+			write!(f, "; this looks like source, not bytecode")
+		} else {
+			write!(f, "; the input may be truncated, translated as text, or not Lua bytecode")
+		}
+	}
+}
+
+impl Error for CorruptHeader {}
+
+fn corrupt_header<T>(field: &'static str, looks_like_source: bool) -> io::Result<T> {
+	Err(io::Error::new(io::ErrorKind::InvalidInput, CorruptHeader { field, looks_like_source }))
+}
+
+/// The error for a header declaring a `sizeof(Number)` other than 8.
+///
+/// Only 8-byte numbers, C's `double`, are supported. Chunks from builds of
+/// Lua using 4-byte `float` or a 10, 12, or 16-byte `long double` are
+/// rejected with this rather than read. Reading fails with an `io::Error`
+/// of kind `InvalidInput` wrapping this.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnsupportedNumberSize(pub u8);
+
+impl fmt::Display for UnsupportedNumberSize {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "unsupported sizeof(Number) {}; only {}-byte numbers are supported", self.0, size_of::<Number>())
+	}
+}
+
+impl Error for UnsupportedNumberSize {}
+
+/// A reader which keeps track of how much input has been consumed.
+struct Tracked<R: Read> {
+	inner: R,
+	pos: u64,
+	eof: bool,
+	/// A length the input is known to need, from reads which hit its end
+	/// and from the counts of arrays still to come.
+	needed: u64,
+}
+
+impl<R: Read> Tracked<R> {
+	/// Note that the input holds at least `n` more bytes.
+	fn expect(&mut self, n: u64) {
+		self.needed = self.needed.max(self.pos.saturating_add(n));
+	}
+}
+
+impl<R: Read> Read for Tracked<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let n = self.inner.read(buf)?;
+		if n == 0 && !buf.is_empty() {
+			self.eof = true;
+			self.expect(buf.len() as u64);
+		}
+		self.pos += n as u64;
+		Ok(n)
+	}
+}
+
+struct Reader<R: Read, B: ByteOrder> {
+	out: Tracked<R>,
+	options: ReadOptions,
+	/// The part of the chunk currently being parsed, for error reporting.
+	section: &'static str,
+	/// The indices of the nested functions currently being parsed.
+	path: Vec<u32>,
+	/// The width of an instruction in bytes, as declared by the header.
+	instruction_size: u8,
+	/// If set, debug information is skipped over with this rather than read.
+	skip: Option<fn(&mut R, u64) -> io::Result<()>>,
+	/// If set, recoverable problems are recorded here rather than failing.
+	diagnostics: Option<Vec<Diagnostic>>,
+	order: PhantomData<B>,
+}
+
+fn invalid<T, S: Into<Box<dyn (::std::error::Error) + Send + Sync>>>(s: S) -> io::Result<T> {
 	Err(io::Error::new(io::ErrorKind::InvalidInput, s))
 }
 
@@ -40,12 +765,54 @@ macro_rules! check {
 	}}
 }
 
-impl<R: Read> Reader<R> {
-	fn read_all(&mut self, mut buf: &mut [u8]) -> io::Result<()> {
+impl<R: Read, B: ByteOrder> Reader<R, B> {
+	fn new(read: R, options: &ReadOptions) -> Reader<R, B> {
+		Reader {
+			out: Tracked { inner: read, pos: 0, eof: false, needed: 0 },
+			options: options.clone(),
+			section: "header",
+			path: vec![],
+			instruction_size: size_of::<Instruction>() as u8,
+			skip: None,
+			diagnostics: None,
+			order: PhantomData,
+		}
+	}
+
+	fn read_chunk(&mut self) -> io::Result<(Function, OffsetMap)> {
+		self.read_header()?;
+		self.section = "upvalue count";
+		let upvalues = self.out.read_u8()?;
+		let (function, offsets) = self.read_function()?;
+		if upvalues as usize != function.upvalues.len() {
+			return invalid(format!(
+				"header declares {} upvalues but the main function has {}",
+				upvalues, function.upvalues.len(),
+			));
+		}
+		Ok((function, offsets))
+	}
+
+	/// Describe where an unexpected EOF occurred, if that caused `err`.
+	fn annotate(&self, err: io::Error) -> io::Error {
+		if !self.out.eof {
+			return err;
+		}
+		let mut msg = format!("unexpected EOF after {} bytes while reading {}", self.out.pos, self.section);
+		if self.section != "header" && self.section != "upvalue count" {
+			msg.push_str(" of function main");
+			for idx in &self.path {
+				msg.push_str(&format!(".{}", idx));
+			}
+		}
+		io::Error::new(io::ErrorKind::UnexpectedEof, msg)
+	}
+
+	fn read_all(&mut self, buf: &mut [u8]) -> io::Result<()> {
 		let mut start = 0;
 		let len = buf.len();
 		while start < len {
-			let n = try!(self.out.read(&mut buf[start..]));
+			let n = self.out.read(&mut buf[start..])?;
 			if n == 0 {
 				return invalid("unexpected EOF");
 			}
@@ -56,86 +823,502 @@ impl<R: Read> Reader<R> {
 
 	fn read_header(&mut self) -> io::Result<()> {
 		let mut buffer = [0u8; 6];
-		try!(self.read_all(&mut buffer[..4]));
-		check!(&buffer[..4], SIGNATURE, "signature");
-		check!(try!(self.out.read_u8()), VERSION, "version");
-		check!(try!(self.out.read_u8()), FORMAT, "format");
-		try!(self.read_all(&mut buffer));
-		check!(&buffer, DATA, "test data");
-		check!(try!(self.out.read_u8()), size_of::<Int>() as u8, "sizeof(int)");
-		check!(try!(self.out.read_u8()), size_of::<Size>() as u8, "sizeof(size_t)");
-		check!(try!(self.out.read_u8()), size_of::<Instruction>() as u8, "sizeof(Instruction)");
-		check!(try!(self.out.read_u8()), size_of::<Integer>() as u8, "sizeof(Integer)");
-		check!(try!(self.out.read_u8()), size_of::<Number>() as u8, "sizeof(Number)");
-		check!(try!(self.out.read_i64::<E>()), TEST_INT, "test integer");
-		check!(try!(self.out.read_f64::<E>()), TEST_NUMBER, "test number");
+		self.read_all(&mut buffer[..4])?;
+		if &buffer[..4] != SIGNATURE {
+			let text = buffer[..4].iter().all(|&b| b.is_ascii_graphic() || b.is_ascii_whitespace());
+			return corrupt_header("signature", text);
+		}
+		let version = self.out.read_u8()?;
+		if !self.options.versions.contains(&version) {
+			return invalid(format!("invalid version, expected one of {:?} but got {:?}", self.options.versions, version));
+		}
+		let format = self.out.read_u8()?;
+		if !self.options.formats.contains(&format) {
+			return invalid(format!("invalid format, expected one of {:?} but got {:?}", self.options.formats, format));
+		}
+		self.read_all(&mut buffer)?;
+		if &buffer[..] != DATA {
+			return corrupt_header("test data", false);
+		}
+		self.options.layout.check()?;
+		check!(self.out.read_u8()?, self.options.layout.int_size, "sizeof(int)");
+		check!(self.out.read_u8()?, self.options.layout.size_t_size, "sizeof(size_t)");
+		// Instructions of other widths are read if their values fit.
+		self.instruction_size = match self.out.read_u8()? {
+			n @ 1..=8 => n,
+			n => return invalid(format!("unsupported sizeof(Instruction) {}", n)),
+		};
+		check!(self.out.read_u8()?, size_of::<Integer>() as u8, "sizeof(Integer)");
+		let number_size = self.out.read_u8()?;
+		if number_size != size_of::<Number>() as u8 {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, UnsupportedNumberSize(number_size)));
+		}
+		check!(self.out.read_i64::<B>()?, TEST_INT, "test integer");
+		check!(self.out.read_f64::<B>()?, TEST_NUMBER, "test number");
 		Ok(())
 	}
 
-	fn read_function(&mut self) -> io::Result<Function> {
+	fn read_function(&mut self) -> io::Result<(Function, OffsetMap)> {
+		self.section = "function header";
+		let mut offsets = OffsetMap { start: self.out.pos, ..OffsetMap::default() };
+		let mut function = self.read_function_head()?;
+		self.section = "code";
+		offsets.code = self.out.pos;
+		let mut pc = 0;
+		function.code = self.read_vec(self.instruction_size as u64, |this| {
+			let instr = this.read_instruction(pc)?;
+			pc += 1;
+			Ok(instr)
+		})?;
+		self.section = "constants";
+		offsets.constants = self.out.pos;
+		let constant_offsets = &mut offsets.constant_offsets;
+		function.constants = self.read_vec(1, |this| {
+			constant_offsets.push(this.out.pos);
+			this.read_constant()
+		})?;
+		self.section = "upvalues";
+		offsets.upvalues = self.out.pos;
+		function.upvalues = self.read_vec(2, |this| this.read_upvalue())?;
+		self.section = "protos";
+		offsets.protos = self.out.pos;
+		let len = self.read_count()?;
+		for i in 0..len {
+			self.path.push(i);
+			let (proto, nested) = self.read_function()?;
+			function.protos.push(proto);
+			offsets.nested.push(nested);
+			self.path.pop();
+		}
+		self.section = "debug";
+		offsets.debug = self.out.pos;
+		match self.skip {
+			Some(skip) => self.skip_debug(skip)?,
+			None => {
+				let int_size = self.options.layout.int_size as u64;
+				function.debug.lineinfo = self.read_vec(int_size, |this| this.read_int())?;
+				function.debug.localvars = self.read_vec(1 + 2 * int_size, |this| this.read_local_var())?;
+				function.debug.upvalues = self.read_vec(1, |this| this.read_string())?;
+			}
+		}
+		if self.diagnostics.is_some() {
+			self.fix_debug(&mut function, offsets.debug);
+		}
+		offsets.end = self.out.pos;
+		Ok((function, offsets))
+	}
+
+	/// Read the fields of a function before its code, returning it with
+	/// everything after them empty.
+	fn read_function_head(&mut self) -> io::Result<Function> {
 		Ok(Function {
-			source: try!(self.read_string()),
-			line_start: try!(self.out.read_i32::<E>()),
-			line_end: try!(self.out.read_i32::<E>()),
-			num_params: try!(self.out.read_u8()),
-			is_vararg: try!(self.out.read_u8()) != 0,
-			max_stack_size: try!(self.out.read_u8()),
-			code: try!(self.read_vec(|this| Ok(try!(this.out.read_u32::<E>())))),
-			constants: try!(self.read_vec(|this| Ok(match try!(this.out.read_u8()) {
-				0x00 => Constant::Nil,
-				0x01 => Constant::Boolean(try!(this.out.read_u8()) != 0),
-				0x03 => Constant::Float(try!(this.out.read_f64::<E>())),
-				0x13 => Constant::Int(try!(this.out.read_i64::<E>())),
-				0x04 => Constant::ShortString(try!(this.read_string())),
-				0x14 => Constant::LongString(try!(this.read_string())),
-				o => return invalid(format!("unknown constant type {}", o)),
-			}))),
-			upvalues: try!(self.read_vec(|this| {
-				let stack = try!(this.out.read_u8());
-				let idx = try!(this.out.read_u8());
-				Ok(match stack {
-					0 => Upvalue::Outer(idx),
-					_ => Upvalue::Stack(idx),
-				})
-			})),
-			protos: try!(self.read_vec(|this| this.read_function())),
-			debug: Debug {
-				lineinfo: try!(self.read_vec(|this| Ok(try!(this.out.read_i32::<E>())))),
-				localvars: try!(self.read_vec(|this| Ok(LocalVar {
-					name: try!(this.read_string()),
-					start_pc: try!(this.out.read_i32::<E>()),
-					end_pc: try!(this.out.read_i32::<E>()),
-				}))),
-				upvalues: try!(self.read_vec(|this| this.read_string())),
+			source: self.read_string()?,
+			line_start: self.read_int()?,
+			line_end: self.read_int()?,
+			num_params: self.out.read_u8()?,
+			is_vararg: self.out.read_u8()? != 0,
+			max_stack_size: self.out.read_u8()?,
+			code: vec![],
+			constants: vec![],
+			upvalues: vec![],
+			protos: vec![],
+			debug: Debug::none(),
+		})
+	}
+
+	fn read_constant(&mut self) -> io::Result<Constant> {
+		Ok(match self.out.read_u8()? {
+			0x00 => Constant::Nil,
+			0x01 => Constant::Boolean(self.out.read_u8()? != 0),
+			0x03 => Constant::Float(self.out.read_f64::<B>()?),
+			0x13 => Constant::Int(self.out.read_i64::<B>()?),
+			0x04 => Constant::ShortString(self.read_string()?),
+			0x14 => Constant::LongString(self.read_string()?),
+			o => match self.options.raw_constant_sizes.iter().find(|&&(tag, _)| tag == o) {
+				Some(&(tag, size)) => {
+					let offset = self.out.pos - 1;
+					let mut bytes = vec![0; size];
+					self.read_all(&mut bytes)?;
+					self.diagnose(offset, format!(
+						"constant of unknown type {:#04x} kept as {} raw bytes", tag, size,
+					));
+					Constant::Raw { tag, bytes }
+				}
+				None => return invalid(format!("unknown constant type {}", o)),
 			},
 		})
 	}
 
+	fn read_upvalue(&mut self) -> io::Result<Upvalue> {
+		let stack = self.out.read_u8()?;
+		let idx = self.out.read_u8()?;
+		Ok(Upvalue::from_raw(stack, idx))
+	}
+
+	fn read_local_var(&mut self) -> io::Result<LocalVar> {
+		Ok(LocalVar {
+			name: self.read_string()?,
+			start_pc: self.read_int()?,
+			end_pc: self.read_int()?,
+		})
+	}
+
+	/// Record a recoverable problem at `offset` in the current function.
+	fn diagnose(&mut self, offset: u64, message: String) {
+		let path = self.path.clone();
+		if let Some(ref mut diagnostics) = self.diagnostics {
+			diagnostics.push(Diagnostic { offset, path, message });
+		}
+	}
+
+	/// Make debug information which does not match the function consistent,
+	/// recording what was wrong.
+	fn fix_debug(&mut self, function: &mut Function, offset: u64) {
+		let (code, lines) = (function.code.len(), function.debug.lineinfo.len());
+		if lines != 0 && lines != code {
+			self.diagnose(offset, format!("lineinfo has {} entries but code has {} instructions", lines, code));
+			let last = function.debug.lineinfo.last().cloned().unwrap_or(0);
+			function.debug.lineinfo.resize(code, last);
+		}
+		for var in &mut function.debug.localvars {
+			let range = 0..=code as Int;
+			if range.contains(&var.start_pc) && range.contains(&var.end_pc) && var.start_pc <= var.end_pc {
+				continue;
+			}
+			let message = format!(
+				"local variable {} has scope {} to {} outside code of {} instructions",
+				var.name, var.start_pc, var.end_pc, code,
+			);
+			var.start_pc = var.start_pc.clamp(0, code as Int);
+			var.end_pc = var.end_pc.clamp(var.start_pc, code as Int);
+			self.diagnose(offset, message);
+		}
+		let (upvalues, names) = (function.upvalues.len(), function.debug.upvalues.len());
+		if names > upvalues {
+			self.diagnose(offset, format!("{} upvalue names but only {} upvalues", names, upvalues));
+			function.debug.upvalues.truncate(upvalues);
+		}
+	}
+
+	fn skip_debug(&mut self, skip: fn(&mut R, u64) -> io::Result<()>) -> io::Result<()> {
+		let lines = self.read_count()?;
+		let int_size = self.options.layout.int_size as u64;
+		self.skip_bytes(skip, lines as u64 * int_size)?;
+		for _ in 0..self.read_count()? {
+			let len = self.skip_string_len()?;
+			self.skip_bytes(skip, len + 2 * int_size)?;
+		}
+		for _ in 0..self.read_count()? {
+			let len = self.skip_string_len()?;
+			self.skip_bytes(skip, len)?;
+		}
+		Ok(())
+	}
+
+	fn skip_bytes(&mut self, skip: fn(&mut R, u64) -> io::Result<()>, n: u64) -> io::Result<()> {
+		if let Err(e) = skip(&mut self.out.inner, n) {
+			self.out.eof |= e.kind() == io::ErrorKind::UnexpectedEof;
+			return Err(e);
+		}
+		self.out.pos += n;
+		Ok(())
+	}
+
+	fn skip_string_len(&mut self) -> io::Result<u64> {
+		Ok(V53::read_len_sized::<B, _>(&mut self.out, self.options.layout.size_t_size)?.unwrap_or(0) as u64)
+	}
+
+	fn read_instruction(&mut self, pc: usize) -> io::Result<Instruction> {
+		if self.instruction_size == 4 {
+			return Ok(self.out.read_u32::<B>()?);
+		}
+		let value = self.out.read_uint::<B>(self.instruction_size as usize)?;
+		if value > Instruction::MAX as u64 {
+			return invalid(format!(
+				"instruction {:#x} at pc {} does not fit in {} bytes",
+				value, pc, size_of::<Instruction>(),
+			));
+		}
+		Ok(value as Instruction)
+	}
+
+	/// Read a length and that many elements, each at least `min_size`
+	/// bytes long.
 	#[inline]
-	fn read_vec<F, T>(&mut self, f: F) -> io::Result<Vec<T>>
-		where F: Fn(&mut Self) -> io::Result<T>
+	fn read_vec<F, T>(&mut self, min_size: u64, mut f: F) -> io::Result<Vec<T>>
+		where F: FnMut(&mut Self) -> io::Result<T>
 	{
-		let len = try!(self.out.read_u32::<E>());
-		(0..len).map(|_| f(self)).collect()
+		let len = self.read_count()?;
+		self.out.expect(len as u64 * min_size);
+		// Reserve up front, but not so much that a corrupt length can
+		// exhaust memory before the input runs out.
+		let mut vec = Vec::with_capacity(len.min(0x1000) as usize);
+		for _ in 0..len {
+			vec.push(f(self)?);
+		}
+		Ok(vec)
+	}
+
+	/// Read the count before an array, a C `int` of the layout's size.
+	fn read_count(&mut self) -> io::Result<u32> {
+		let count = match self.options.layout.int_size {
+			4 => self.out.read_u32::<B>()? as u64,
+			_ => self.out.read_u64::<B>()?,
+		};
+		// A count is a C int, so one past its maximum is really negative.
+		if count > Int::MAX as u64 {
+			let offset = self.out.pos - self.options.layout.int_size as u64;
+			return invalid(format!("count {} at offset {} is too large", count, offset));
+		}
+		Ok(count as u32)
+	}
+
+	/// Read a C `int` of the layout's size.
+	fn read_int(&mut self) -> io::Result<Int> {
+		if self.options.layout.int_size == 4 {
+			return Ok(self.out.read_i32::<B>()?);
+		}
+		let value = self.out.read_i64::<B>()?;
+		if value < Int::MIN as i64 || value > Int::MAX as i64 {
+			return invalid(format!("int {} at offset {} does not fit in 4 bytes", value, self.out.pos - 8));
+		}
+		Ok(value as Int)
 	}
 
 	fn read_string(&mut self) -> io::Result<String> {
-		let first = try!(self.out.read_u8());
-		if first == 0 {
-			Ok(String::new())
-		} else {
-			let len = if first < 0xff {
-				first as usize
-			} else {
-				try!(self.out.read_u32::<E>()) as usize
-			} - 1;
-			let mut buffer = vec![0u8; len];
-			try!(self.read_all(&mut buffer));
-			// TODO: May need to return a Vec<u8> rather than String
-			match String::from_utf8(buffer) {
-				Ok(s) => Ok(s),
-				Err(_) => invalid("not utf8"),
+		let offset = self.out.pos;
+		// A null string is read as an empty one.
+		let len = match V53::read_len_sized::<B, _>(&mut self.out, self.options.layout.size_t_size)? {
+			None => return Ok(String::new()),
+			Some(len) => len,
+		};
+		let mut buffer = vec![0u8; len];
+		self.read_all(&mut buffer)?;
+		// TODO: May need to return a Vec<u8> rather than String
+		match String::from_utf8(buffer) {
+			Ok(s) => Ok(s),
+			Err(e) if self.options.lossy_strings => Ok(String::from_utf8_lossy(e.as_bytes()).into_owned()),
+			Err(e) if self.diagnostics.is_some() => {
+				self.diagnose(offset, format!("string in {} is not utf8", self.section));
+				Ok(String::from_utf8_lossy(e.as_bytes()).into_owned())
+			}
+			Err(_) => invalid("not utf8"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::{self, Cursor, Read};
+	use builder::FunctionBuilder;
+	use bytecode::{Opcode, encode, encode_bx};
+	use super::super::{Constant, Debug, Function, LocalVar, to_bytes, write_framed};
+	use super::{
+		ChunkParser, ReadOptions, read_file, read_file_lenient, read_file_lenient_with_options,
+		read_file_no_debug, read_file_with_offsets, read_framed,
+	};
+
+	fn sample() -> Function {
+		let mut nested = FunctionBuilder::new();
+		nested.constant(Constant::LongString("x".repeat(1000)));
+		nested.emit(encode_bx(Opcode::LoadK, 0, 0));
+		nested.emit(encode(Opcode::Return, 0, 2, 0));
+		let mut main = FunctionBuilder::main_chunk();
+		main.constant(Constant::Float(0.5));
+		for _ in 0..200 {
+			main.emit(encode_bx(Opcode::LoadK, 0, 0));
+		}
+		main.emit(encode_bx(Opcode::Closure, 1, 0));
+		main.emit(encode(Opcode::Return, 0, 1, 0));
+		let mut main = main.build();
+		main.protos.push(nested.build());
+		main.debug.lineinfo = (1..=main.code.len() as i32).collect();
+		main.debug.localvars.push(LocalVar { name: "f".into(), start_pc: 201, end_pc: 202 });
+		main
+	}
+
+	#[test]
+	fn chunk_parser_bytewise() {
+		let bytes = to_bytes(&sample()).unwrap();
+		let expected = read_file(&bytes[..]).unwrap();
+
+		let mut parser = ChunkParser::new();
+		let mut attempts = 0;
+		for (i, byte) in bytes.iter().enumerate() {
+			if parser.buffer.len() as u64 + 1 >= parser.needed {
+				attempts += 1;
+			}
+			match parser.feed(&[*byte]).unwrap() {
+				Some(function) => {
+					assert_eq!(i, bytes.len() - 1);
+					assert_eq!(function, expected);
+				}
+				None => assert!(i < bytes.len() - 1),
+			}
+		}
+		assert!(parser.remainder().is_empty());
+		// The code and the long string are waited for rather than parsed
+		// a byte at a time.
+		assert!(attempts < bytes.len() / 8, "{} attempts for {} bytes", attempts, bytes.len());
+	}
+
+	#[test]
+	fn chunk_parser_consecutive() {
+		let bytes = to_bytes(&sample()).unwrap();
+		let mut parser = ChunkParser::new();
+		let mut input = bytes.clone();
+		input.extend_from_slice(&bytes[..10]);
+		assert!(parser.feed(&input).unwrap().is_some());
+		assert_eq!(parser.remainder(), &bytes[..10]);
+		assert!(parser.feed(&bytes[10..20]).unwrap().is_none());
+		assert!(parser.feed(&bytes[20..]).unwrap().is_some());
+		assert!(parser.remainder().is_empty());
+	}
+
+	#[test]
+	fn chunk_parser_resumes() {
+		let bytes = to_bytes(&sample()).unwrap();
+		let mut parser = ChunkParser::new();
+		// Stop partway through the eleventh instruction of the main function.
+		let code = read_file_with_offsets(&bytes[..]).unwrap().1.code;
+		let half = code as usize + 4 + 10 * 4 + 2;
+		assert!(parser.feed(&bytes[..half]).unwrap().is_none());
+		// Everything before the instruction the input ends in stays parsed.
+		let pos = parser.state.pos;
+		assert_eq!(pos, half as u64 - 2);
+		assert_eq!(parser.state.stack[0].function.code.len(), 10);
+		assert!(parser.feed(&bytes[half..bytes.len() - 1]).unwrap().is_none());
+		assert!(parser.state.pos > pos);
+		assert_eq!(parser.feed(&bytes[bytes.len() - 1..]).unwrap(), Some(read_file(&bytes[..]).unwrap()));
+	}
+
+	#[test]
+	fn corrupt_counts() {
+		let bytes = to_bytes(&sample()).unwrap();
+		// The count of the main function's code.
+		let at = read_file_with_offsets(&bytes[..]).unwrap().1.code as usize;
+
+		let mut corrupt = bytes.clone();
+		corrupt[at + 3] = 0x80;
+		let err = read_file(&corrupt[..]).unwrap_err();
+		assert!(err.to_string().contains("too large"), "{}", err);
+		assert!(ChunkParser::new().feed(&corrupt[..at + 4]).is_err());
+
+		corrupt[at + 3] = 0x7f;
+		let mut parser = ChunkParser::new();
+		let err = parser.feed(&corrupt[..at + 8]).unwrap_err();
+		assert!(err.to_string().contains("maximum"), "{}", err);
+
+		let mut parser = ChunkParser::new();
+		parser.set_max_len(bytes.len() as u64 - 1);
+		assert!(parser.feed(&bytes[..]).is_err());
+		let mut parser = ChunkParser::new();
+		parser.set_max_len(bytes.len() as u64);
+		assert!(parser.feed(&bytes[..]).unwrap().is_some());
+	}
+
+	fn find(haystack: &[u8], needle: &[u8]) -> usize {
+		haystack.windows(needle.len()).position(|window| window == needle).unwrap()
+	}
+
+	#[test]
+	fn lenient_strings() {
+		let mut nested = FunctionBuilder::new();
+		nested.constant(Constant::ShortString("NOTUTF8".into()));
+		let mut main = FunctionBuilder::main_chunk().build();
+		main.protos.push(nested.build());
+		let mut bytes = to_bytes(&main).unwrap();
+		let at = find(&bytes, b"NOTUTF8");
+		bytes[at] = 0xff;
+		assert!(read_file(&bytes[..]).is_err());
+
+		let (function, diagnostics) = read_file_lenient(&bytes[..]).unwrap();
+		assert_eq!(function.protos[0].constants, [Constant::ShortString("\u{fffd}OTUTF8".into())]);
+		assert_eq!(diagnostics.len(), 1);
+		// The offset is that of the length before the string.
+		assert_eq!(diagnostics[0].offset, at as u64 - 1);
+		assert_eq!(diagnostics[0].path, [0]);
+		assert!(diagnostics[0].message.contains("not utf8"));
+	}
+
+	#[test]
+	fn lenient_debug() {
+		let mut main = FunctionBuilder::main_chunk();
+		main.emit(encode(Opcode::Return, 0, 1, 0));
+		let mut main = main.build();
+		main.debug.lineinfo = vec![1, 2, 3];
+		main.debug.localvars.push(LocalVar { name: "x".into(), start_pc: 0, end_pc: 5 });
+		main.debug.upvalues.push("extra".into());
+		let bytes = to_bytes(&main).unwrap();
+
+		let (function, diagnostics) = read_file_lenient(&bytes[..]).unwrap();
+		assert_eq!(function.debug.lineinfo, [1]);
+		assert_eq!((function.debug.localvars[0].start_pc, function.debug.localvars[0].end_pc), (0, 1));
+		assert_eq!(function.debug.upvalues, ["_ENV"]);
+		assert_eq!(diagnostics.len(), 3);
+		assert!(diagnostics.iter().all(|d| d.path.is_empty()));
+		// A consistent chunk has nothing to report.
+		assert!(read_file_lenient(&to_bytes(&function).unwrap()[..]).unwrap().1.is_empty());
+	}
+
+	#[test]
+	fn lenient_unknown_constant() {
+		let mut main = FunctionBuilder::main_chunk();
+		main.constant(Constant::Raw { tag: 0x25, bytes: vec![1, 2, 3] });
+		main.constant(Constant::Int(7));
+		let main = main.build();
+		let bytes = to_bytes(&main).unwrap();
+		assert!(read_file_lenient(&bytes[..]).is_err());
+
+		let options = ReadOptions { raw_constant_sizes: vec![(0x25, 3)], ..ReadOptions::default() };
+		let (function, diagnostics) = read_file_lenient_with_options(&bytes[..], &options).unwrap();
+		assert_eq!(function, main);
+		assert_eq!(diagnostics.len(), 1);
+		assert_eq!(diagnostics[0].offset, find(&bytes, &[0x25, 1, 2, 3]) as u64);
+	}
+
+	#[test]
+	fn no_debug_truncated() {
+		let bytes = to_bytes(&sample()).unwrap();
+		let mut expected = read_file(&bytes[..]).unwrap();
+		expected.debug = Debug::none();
+		for proto in &mut expected.protos {
+			proto.debug = Debug::none();
+		}
+		assert_eq!(read_file_no_debug(Cursor::new(&bytes[..])).unwrap(), expected);
+
+		// Cut within the line info, the scope of the local variable, and the
+		// name of the last upvalue.
+		let debug = read_file_with_offsets(&bytes[..]).unwrap().1.debug as usize;
+		for &len in &[debug + 8, bytes.len() - 12, bytes.len() - 2] {
+			let err = read_file_no_debug(Cursor::new(&bytes[..len])).unwrap_err();
+			assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{}", err);
+			assert!(err.to_string().contains("debug of function main"), "{}", err);
+		}
+	}
+
+	/// A reader which is interrupted before every read which succeeds.
+	struct Interrupting<R> {
+		inner: R,
+		interrupt: bool,
+	}
+
+	impl<R: Read> Read for Interrupting<R> {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			self.interrupt = !self.interrupt;
+			if self.interrupt {
+				return Err(io::ErrorKind::Interrupted.into());
 			}
+			self.inner.read(buf)
 		}
 	}
+
+	#[test]
+	fn framed_interrupted() {
+		let mut bytes = Vec::new();
+		write_framed(&mut bytes, &sample()).unwrap();
+		write_framed(&mut bytes, &sample()).unwrap();
+		let functions = read_framed(Interrupting { inner: &bytes[..], interrupt: false }).unwrap();
+		assert_eq!(functions, [sample(), sample()]);
+	}
 }
@@ -0,0 +1,164 @@
+//! Encodings of the length prefix of serialized strings.
+
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt};
+
+use super::Size;
+
+/// A way of encoding the length of a serialized string.
+///
+/// Lengths are stored plus one, so that zero can represent a null string,
+/// which Lua uses for absent source names. Null strings are read and
+/// written as `None`. Multi-byte lengths are in the byte order `B`.
+pub trait StringLenCodec {
+	/// Read a string length, or `None` for a null string.
+	fn read_len<B: ByteOrder, R: Read>(&self, read: &mut R) -> io::Result<Option<usize>>;
+	/// Write a string length, or `None` for a null string.
+	fn write_len<B: ByteOrder, W: Write>(&self, write: &mut W, len: Option<usize>) -> io::Result<()>;
+}
+
+fn read_size<B: ByteOrder, R: Read>(read: &mut R, size_t_size: u8) -> io::Result<u64> {
+	Ok(match size_t_size {
+		4 => read.read_u32::<B>()? as u64,
+		_ => read.read_u64::<B>()?,
+	})
+}
+
+fn write_size<B: ByteOrder, W: Write>(write: &mut W, size: u64, size_t_size: u8) -> io::Result<()> {
+	match size_t_size {
+		4 => {
+			if size > u32::MAX as u64 {
+				return Err(io::Error::new(io::ErrorKind::InvalidInput, "string length too large for a 4-byte size_t"));
+			}
+			write.write_u32::<B>(size as u32)?
+		}
+		_ => write.write_u64::<B>(size)?,
+	}
+	Ok(())
+}
+
+fn from_stored(stored: u64) -> io::Result<Option<usize>> {
+	if stored == 0 {
+		Ok(None)
+	} else if stored - 1 > usize::MAX as u64 {
+		Err(io::Error::new(io::ErrorKind::InvalidInput, "string length too large"))
+	} else {
+		Ok(Some((stored - 1) as usize))
+	}
+}
+
+/// The Lua 5.3 encoding: a single byte if the stored length is below
+/// `0xff`, or `0xff` followed by a `size_t`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct V53;
+
+impl V53 {
+	/// Read a string length whose `size_t` is `size_t_size` bytes, 4 or 8,
+	/// rather than the host's.
+	pub(crate) fn read_len_sized<B: ByteOrder, R: Read>(read: &mut R, size_t_size: u8) -> io::Result<Option<usize>> {
+		let first = read.read_u8()?;
+		if first < 0xff {
+			from_stored(first as u64)
+		} else {
+			from_stored(read_size::<B, _>(read, size_t_size)?)
+		}
+	}
+
+	/// Write a string length whose `size_t` is `size_t_size` bytes, 4 or
+	/// 8, rather than the host's.
+	pub(crate) fn write_len_sized<B: ByteOrder, W: Write>(write: &mut W, len: Option<usize>, size_t_size: u8) -> io::Result<()> {
+		let stored = len.map_or(0, |len| len as u64 + 1);
+		if stored < 0xff {
+			write.write_u8(stored as u8)?;
+			Ok(())
+		} else {
+			write.write_u8(0xff)?;
+			write_size::<B, _>(write, stored, size_t_size)
+		}
+	}
+}
+
+impl StringLenCodec for V53 {
+	fn read_len<B: ByteOrder, R: Read>(&self, read: &mut R) -> io::Result<Option<usize>> {
+		V53::read_len_sized::<B, _>(read, size_of::<Size>() as u8)
+	}
+
+	fn write_len<B: ByteOrder, W: Write>(&self, write: &mut W, len: Option<usize>) -> io::Result<()> {
+		V53::write_len_sized::<B, _>(write, len, size_of::<Size>() as u8)
+	}
+}
+
+/// The Lua 5.4 encoding: a variable-length integer of 7-bit groups, most
+/// significant first, with the high bit set on the last byte.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct V54;
+
+impl StringLenCodec for V54 {
+	fn read_len<B: ByteOrder, R: Read>(&self, read: &mut R) -> io::Result<Option<usize>> {
+		let mut stored: u64 = 0;
+		loop {
+			let byte = read.read_u8()?;
+			if stored > u64::MAX >> 7 {
+				return Err(io::Error::new(io::ErrorKind::InvalidInput, "string length overflow"));
+			}
+			stored = (stored << 7) | (byte & 0x7f) as u64;
+			if byte & 0x80 != 0 {
+				return from_stored(stored);
+			}
+		}
+	}
+
+	fn write_len<B: ByteOrder, W: Write>(&self, write: &mut W, len: Option<usize>) -> io::Result<()> {
+		let mut stored = len.map_or(0, |len| len as u64 + 1);
+		let mut buffer = [0u8; 10];
+		let mut n = 0;
+		loop {
+			n += 1;
+			buffer[buffer.len() - n] = (stored & 0x7f) as u8;
+			stored >>= 7;
+			if stored == 0 {
+				break;
+			}
+		}
+		buffer[buffer.len() - 1] |= 0x80;
+		write.write_all(&buffer[buffer.len() - n..])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::mem::size_of;
+	use byteorder::LittleEndian;
+
+	use super::{StringLenCodec, V53, V54};
+	use super::super::Size;
+
+	fn roundtrip<C: StringLenCodec>(codec: C, len: Option<usize>, bytes: &[u8]) {
+		let mut written = Vec::new();
+		codec.write_len::<LittleEndian, _>(&mut written, len).unwrap();
+		assert_eq!(written, bytes, "writing {:?}", len);
+		let mut read = bytes;
+		assert_eq!(codec.read_len::<LittleEndian, _>(&mut read).unwrap(), len);
+		assert!(read.is_empty());
+	}
+
+	#[test]
+	fn v54_boundary() {
+		roundtrip(V54, None, &[0x80]);
+		// Stored lengths are one more than the string length, so the
+		// second byte is needed from a stored 128.
+		roundtrip(V54, Some(126), &[0xff]);
+		roundtrip(V54, Some(127), &[0x01, 0x80]);
+		roundtrip(V54, Some(128), &[0x01, 0x81]);
+	}
+
+	#[test]
+	fn v53_sentinel() {
+		roundtrip(V53, None, &[0x00]);
+		roundtrip(V53, Some(253), &[0xfe]);
+		let mut long = vec![0xff, 0xff];
+		long.resize(1 + size_of::<Size>(), 0);
+		roundtrip(V53, Some(254), &long);
+	}
+}
@@ -0,0 +1,84 @@
+//! A record of debug information stripping, carried in a chunk's trailer.
+
+use std::io::{self, Read, Write};
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+
+use super::{read_file_with_trailer, write_file_with_trailer, Function};
+
+/// Marks a trailer holding a `StripInfo`.
+const MAGIC: &[u8] = b"LKstrip";
+
+/// Whether a chunk was stripped of its debug information, and how much it
+/// had.
+///
+/// Written after the main function by `write_file_with_strip_info`, where
+/// Lua ignores it, and recovered by `read_file_with_strip_info`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StripInfo {
+	/// Whether the debug information was stripped.
+	pub stripped: bool,
+	/// The number of `debug.lineinfo` entries in the function and its nested
+	/// functions before stripping. Zero if it never had line info.
+	pub original_line_count: u32,
+}
+
+impl StripInfo {
+	/// Describe `function`, as it was before it was or was not stripped.
+	pub fn new(function: &Function, stripped: bool) -> StripInfo {
+		fn count(function: &Function) -> usize {
+			function.debug.lineinfo.len() + function.protos.iter().map(count).sum::<usize>()
+		}
+		StripInfo {
+			stripped,
+			original_line_count: count(function).min(u32::MAX as usize) as u32,
+		}
+	}
+
+	fn to_bytes(self) -> Vec<u8> {
+		let mut bytes = MAGIC.to_vec();
+		bytes.push(self.stripped as u8);
+		bytes.write_u32::<LittleEndian>(self.original_line_count).unwrap();
+		bytes
+	}
+
+	fn from_bytes(bytes: &[u8]) -> io::Result<StripInfo> {
+		let mut body = &bytes[MAGIC.len()..];
+		if body.len() != 5 {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+				"strip info should be 5 bytes but is {}", body.len(),
+			)));
+		}
+		let stripped = match body.read_u8()? {
+			0 => false,
+			1 => true,
+			other => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+				"invalid stripped flag {} in strip info", other,
+			))),
+		};
+		let original_line_count = body.read_u32::<LittleEndian>()?;
+		Ok(StripInfo { stripped, original_line_count })
+	}
+}
+
+/// Serialize a `Function` to bytecode, followed by a `StripInfo` as its
+/// trailer.
+///
+/// The function is written as given, so strip it first if `info` says so.
+pub fn write_file_with_strip_info<W: Write>(write: W, function: &Function, info: StripInfo) -> io::Result<()> {
+	write_file_with_trailer(write, function, &info.to_bytes())
+}
+
+/// Deserialize bytecode into a `Function`, along with the `StripInfo`
+/// written after it by `write_file_with_strip_info`.
+///
+/// Returns `None` for the info if the chunk has no trailer or some other
+/// trailer, and fails if the trailer is marked as strip info but malformed.
+pub fn read_file_with_strip_info<R: Read>(read: R) -> io::Result<(Function, Option<StripInfo>)> {
+	let (function, trailer) = read_file_with_trailer(read)?;
+	let info = if trailer.starts_with(MAGIC) {
+		Some(StripInfo::from_bytes(&trailer)?)
+	} else {
+		None
+	};
+	Ok((function, info))
+}
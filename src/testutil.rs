@@ -0,0 +1,16 @@
+//! Utilities for testing code which produces `Function`s.
+//!
+//! Enabled by the `testutil` feature.
+
+use std::io;
+
+use super::{Function, read_file, to_bytes};
+
+/// Serialize a `Function` to bytecode and deserialize it again.
+///
+/// Any `Function` which can be loaded by Lua should survive the roundtrip
+/// unchanged, so comparing the result to the original is a quick check
+/// that nothing in it is unrepresentable.
+pub fn roundtrip(function: &Function) -> io::Result<Function> {
+	read_file(&to_bytes(function)?[..])
+}
@@ -0,0 +1,291 @@
+//! Textual representations of Lua values.
+
+use std::io;
+
+fn invalid<T, S: Into<Box<dyn (::std::error::Error) + Send + Sync>>>(s: S) -> io::Result<T> {
+	Err(io::Error::new(io::ErrorKind::InvalidInput, s))
+}
+
+/// Parse a quoted Lua string literal into the bytes it represents.
+///
+/// The literal must be delimited by matching `"` or `'` quotes. All of Lua
+/// 5.3's escape sequences are understood, including `\ddd` (which is
+/// *decimal*, not octal), `\xHH`, `\u{XXX}`, and `\z`.
+pub fn parse_lua_string_literal(literal: &str) -> io::Result<Vec<u8>> {
+	let bytes = literal.as_bytes();
+	let quote = match bytes.first() {
+		Some(&q) if q == b'"' || q == b'\'' => q,
+		_ => return invalid("string literal must begin with a quote"),
+	};
+	if bytes.len() < 2 || bytes[bytes.len() - 1] != quote {
+		return invalid("unfinished string literal");
+	}
+	let body = &bytes[1..bytes.len() - 1];
+
+	let mut out = Vec::with_capacity(body.len());
+	let mut i = 0;
+	while i < body.len() {
+		let ch = body[i];
+		i += 1;
+		if ch == quote {
+			return invalid("unescaped quote inside string literal");
+		} else if ch == b'\n' || ch == b'\r' {
+			return invalid("unescaped newline inside string literal");
+		} else if ch != b'\\' {
+			out.push(ch);
+			continue;
+		}
+
+		let esc = match body.get(i) {
+			Some(&esc) => esc,
+			None => return invalid("unfinished escape sequence"),
+		};
+		i += 1;
+		match esc {
+			b'a' => out.push(b'\x07'),
+			b'b' => out.push(b'\x08'),
+			b'f' => out.push(b'\x0c'),
+			b'n' => out.push(b'\n'),
+			b'r' => out.push(b'\r'),
+			b't' => out.push(b'\t'),
+			b'v' => out.push(b'\x0b'),
+			b'\\' => out.push(b'\\'),
+			b'"' => out.push(b'"'),
+			b'\'' => out.push(b'\''),
+			b'\n' | b'\r' => {
+				// An escaped line break, where "\r\n" and "\n\r" count as one.
+				out.push(b'\n');
+				if let Some(&next) = body.get(i) {
+					if (next == b'\n' || next == b'\r') && next != esc {
+						i += 1;
+					}
+				}
+			}
+			b'x' => {
+				let mut value = 0;
+				for _ in 0..2 {
+					let digit = match body.get(i).and_then(|&d| (d as char).to_digit(16)) {
+						Some(digit) => digit,
+						None => return invalid("hexadecimal digit expected in \\x escape"),
+					};
+					value = value * 16 + digit;
+					i += 1;
+				}
+				out.push(value as u8);
+			}
+			b'z' => {
+				while body.get(i).is_some_and(|c| c.is_ascii_whitespace()) {
+					i += 1;
+				}
+			}
+			b'u' => {
+				if body.get(i) != Some(&b'{') {
+					return invalid("missing '{' in \\u{xxxx} escape");
+				}
+				i += 1;
+				let mut value: u32 = 0;
+				let mut digits = 0;
+				while let Some(digit) = body.get(i).and_then(|&d| (d as char).to_digit(16)) {
+					value = match value.checked_mul(16) {
+						Some(v) if v + digit <= 0x7fff_ffff => v + digit,
+						_ => return invalid("UTF-8 value too large in \\u escape"),
+					};
+					digits += 1;
+					i += 1;
+				}
+				if digits == 0 {
+					return invalid("hexadecimal digit expected in \\u escape");
+				}
+				if body.get(i) != Some(&b'}') {
+					return invalid("missing '}' in \\u{xxxx} escape");
+				}
+				i += 1;
+				push_utf8(&mut out, value);
+			}
+			b'0'..=b'9' => {
+				let mut value = (esc - b'0') as u32;
+				let mut digits = 1;
+				while digits < 3 {
+					match body.get(i) {
+						Some(&d) if d.is_ascii_digit() => value = value * 10 + (d - b'0') as u32,
+						_ => break,
+					}
+					digits += 1;
+					i += 1;
+				}
+				if value > 0xff {
+					return invalid("decimal escape too large");
+				}
+				out.push(value as u8);
+			}
+			_ => return invalid(format!("invalid escape sequence '\\{}'", esc as char)),
+		}
+	}
+	Ok(out)
+}
+
+/// Encode a code point as (extended) UTF-8, as Lua's `\u{XXX}` escape does.
+fn push_utf8(out: &mut Vec<u8>, mut value: u32) {
+	if value < 0x80 {
+		out.push(value as u8);
+		return;
+	}
+	let mut buffer = [0u8; 6];
+	let mut n = 0;
+	let mut max_first = 0x3f;
+	while value > max_first {
+		buffer[5 - n] = 0x80 | (value & 0x3f) as u8;
+		value >>= 6;
+		max_first >>= 1;
+		n += 1;
+	}
+	buffer[5 - n] = ((!max_first << 1) | value) as u8;
+	out.extend_from_slice(&buffer[5 - n..]);
+}
+
+/// Render bytes as a double-quoted Lua string literal.
+///
+/// The result is printable ASCII and is accepted by
+/// `parse_lua_string_literal`, which yields the original bytes.
+pub fn escape_lua_string(bytes: &[u8]) -> String {
+	let mut out = String::with_capacity(bytes.len() + 2);
+	out.push('"');
+	for (i, &byte) in bytes.iter().enumerate() {
+		match byte {
+			b'\x07' => out.push_str("\\a"),
+			b'\x08' => out.push_str("\\b"),
+			b'\x0c' => out.push_str("\\f"),
+			b'\n' => out.push_str("\\n"),
+			b'\r' => out.push_str("\\r"),
+			b'\t' => out.push_str("\\t"),
+			b'\x0b' => out.push_str("\\v"),
+			b'\\' => out.push_str("\\\\"),
+			b'"' => out.push_str("\\\""),
+			0x20..=0x7e => out.push(byte as char),
+			_ => {
+				// Decimal escapes stop after three digits or at the first
+				// non-digit, so pad only when a digit follows.
+				if bytes.get(i + 1).is_some_and(|c| c.is_ascii_digit()) {
+					out.push_str(&format!("\\{:03}", byte));
+				} else {
+					out.push_str(&format!("\\{}", byte));
+				}
+			}
+		}
+	}
+	out.push('"');
+	out
+}
+
+/// Format a float as Lua's `tostring` does.
+///
+/// This is C's `%.14g`, with `.0` appended if the result would otherwise
+/// look like an integer. Infinities are `inf` and `-inf` and NaNs are `nan`
+/// or `-nan`, as printed by glibc.
+pub fn format_lua_number(n: f64) -> String {
+	let mut out = format_g(n, 14);
+	if out.bytes().all(|b| b == b'-' || b.is_ascii_digit()) {
+		out.push_str(".0");
+	}
+	out
+}
+
+/// Format a float as C's `%.{precision}g`.
+fn format_g(n: f64, precision: usize) -> String {
+	let sign = if n.is_sign_negative() { "-" } else { "" };
+	if n.is_nan() {
+		return format!("{}nan", sign);
+	} else if n.is_infinite() {
+		return format!("{}inf", sign);
+	} else if n == 0.0 {
+		return format!("{}0", sign);
+	}
+
+	// The exponent is that of the number as rounded to `precision` digits.
+	let sci = format!("{:.*e}", precision - 1, n);
+	let e = sci.find('e').unwrap();
+	let exponent: i32 = sci[e + 1..].parse().unwrap();
+	if exponent < -4 || exponent >= precision as i32 {
+		let mantissa = strip_zeros(&sci[..e]);
+		let exp_sign = if exponent < 0 { '-' } else { '+' };
+		format!("{}e{}{:02}", mantissa, exp_sign, exponent.abs())
+	} else {
+		let decimals = (precision as i32 - 1 - exponent) as usize;
+		strip_zeros(&format!("{:.*}", decimals, n)).to_owned()
+	}
+}
+
+/// Remove trailing zeros after a decimal point, and the point if bare.
+fn strip_zeros(s: &str) -> &str {
+	if s.contains('.') {
+		s.trim_end_matches('0').trim_end_matches('.')
+	} else {
+		s
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{escape_lua_string, format_lua_number, parse_lua_string_literal};
+
+	fn parse(literal: &str) -> Vec<u8> {
+		parse_lua_string_literal(literal).unwrap()
+	}
+
+	#[test]
+	fn decimal_escapes() {
+		assert_eq!(parse(r#""\65""#), b"A");
+		assert_eq!(parse(r#""\0659""#), b"A9");
+		assert_eq!(parse(r#""\255""#), b"\xff");
+		assert!(parse_lua_string_literal(r#""\256""#).is_err());
+	}
+
+	#[test]
+	fn other_escapes() {
+		assert_eq!(parse("'a\\z  \n\t b'"), b"ab");
+		assert_eq!(parse(r#""\x41\u{48}\u{7FFFFFFF}""#), b"AH\xfd\xbf\xbf\xbf\xbf\xbf");
+		assert!(parse_lua_string_literal(r#""\u{80000000}""#).is_err());
+		// "\r\n" and "\n\r" after a backslash are one line break each.
+		assert_eq!(parse("\"a\\\r\nb\\\n\rc\""), b"a\nb\nc");
+		assert!(parse_lua_string_literal("\"a\\\n\nb\"").is_err());
+	}
+
+	#[test]
+	fn escape_roundtrip() {
+		let cases: &[&[u8]] = &[
+			b"",
+			b"plain text",
+			b"\"quoted\" \\ 'single'\n\r\t\x07\x08\x0c\x0b",
+			b"\x001\x002",
+			b"\x7f9\xff0\x01",
+			b"\x00\x01\x02\x7f\x80\xfe\xff",
+		];
+		for &bytes in cases {
+			let escaped = escape_lua_string(bytes);
+			assert!(escaped.bytes().all(|b| (0x20..0x7f).contains(&b)), "{}", escaped);
+			assert_eq!(parse(&escaped), bytes, "{}", escaped);
+		}
+		assert_eq!(escape_lua_string(b"\x001\x01"), r#""\0001\1""#);
+	}
+
+	#[test]
+	fn lua_numbers() {
+		assert_eq!(format_lua_number(0.1), "0.1");
+		assert_eq!(format_lua_number(1.0), "1.0");
+		assert_eq!(format_lua_number(-2.5), "-2.5");
+		assert_eq!(format_lua_number(1e300), "1e+300");
+		assert_eq!(format_lua_number(1e15), "1e+15");
+		assert_eq!(format_lua_number(1e14), "1e+14");
+		assert_eq!(format_lua_number(12345678901234.0), "12345678901234.0");
+		assert_eq!(format_lua_number(123456789012345.0), "1.2345678901234e+14");
+		assert_eq!(format_lua_number(1.0 / 3.0), "0.33333333333333");
+		assert_eq!(format_lua_number(0.0), "0.0");
+		assert_eq!(format_lua_number(-0.0), "-0.0");
+		assert_eq!(format_lua_number(1e-4), "0.0001");
+		assert_eq!(format_lua_number(1e-5), "1e-05");
+		assert_eq!(format_lua_number(f64::INFINITY), "inf");
+		assert_eq!(format_lua_number(f64::NEG_INFINITY), "-inf");
+		assert_eq!(format_lua_number(f64::NAN), "nan");
+		assert_eq!(format_lua_number(-f64::NAN), "-nan");
+	}
+}
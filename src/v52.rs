@@ -0,0 +1,264 @@
+//! Serialization to the Lua 5.2 bytecode format.
+//!
+//! Lua 5.2 has no integer subtype, so integer constants are written as
+//! floats, and it lacks the integer division and bitwise opcodes, which
+//! cannot be written at all.
+
+use std::io::{self, Write};
+use std::mem::size_of;
+use byteorder::WriteBytesExt;
+use byteorder::NativeEndian as E;
+
+use super::bytecode::{Opcode, get_opcode};
+use super::{SIGNATURE, Int, Size, Instruction, Number, Constant, Function};
+
+/// The Lua 5.2 version, in the form `(MAJOR << 4) | MINOR`.
+pub const VERSION: u8 = 0x52;
+/// The Lua 5.2 bytecode format.
+pub const FORMAT: u8 = 0;
+/// Test text to catch translation errors, found at the end of the header.
+pub const TAIL: &[u8] = b"\x19\x93\r\n\x1a\n";
+
+/// Find the Lua 5.2 number of an opcode, if it exists in Lua 5.2.
+pub fn opcode_number(op: Opcode) -> Option<u8> {
+	Some(match op {
+		Opcode::Move => 0,
+		Opcode::LoadK => 1,
+		Opcode::LoadKX => 2,
+		Opcode::LoadBool => 3,
+		Opcode::LoadNil => 4,
+		Opcode::GetUpval => 5,
+		Opcode::GetTabUp => 6,
+		Opcode::GetTable => 7,
+		Opcode::SetTabUp => 8,
+		Opcode::SetUpval => 9,
+		Opcode::SetTable => 10,
+		Opcode::NewTable => 11,
+		Opcode::Self_ => 12,
+		Opcode::Add => 13,
+		Opcode::Sub => 14,
+		Opcode::Mul => 15,
+		Opcode::Div => 16,
+		Opcode::Mod => 17,
+		Opcode::Pow => 18,
+		Opcode::UnMinus => 19,
+		Opcode::Not => 20,
+		Opcode::Len => 21,
+		Opcode::Concat => 22,
+		Opcode::Jump => 23,
+		Opcode::Eq => 24,
+		Opcode::Less => 25,
+		Opcode::LessEq => 26,
+		Opcode::Test => 27,
+		Opcode::TestSet => 28,
+		Opcode::Call => 29,
+		Opcode::TailCall => 30,
+		Opcode::Return => 31,
+		Opcode::ForLoop => 32,
+		Opcode::ForPrep => 33,
+		Opcode::TForCall => 34,
+		Opcode::TForLoop => 35,
+		Opcode::SetList => 36,
+		Opcode::Closure => 37,
+		Opcode::VarArg => 38,
+		Opcode::ExtraArg => 39,
+		Opcode::IntDiv | Opcode::BinAnd | Opcode::BinOr | Opcode::BinXor |
+		Opcode::ShLeft | Opcode::ShRight | Opcode::BinNot => return None,
+	})
+}
+
+/// Serialize a `Function` to Lua 5.2 bytecode.
+pub fn write_file<W: Write>(write: W, function: &Function) -> io::Result<()> {
+	let mut writer = Writer { out: write };
+	writer.write_header()?;
+	writer.write_function(function, "")
+}
+
+struct Writer<W: Write> {
+	out: W,
+}
+
+impl<W: Write> Writer<W> {
+	fn write_header(&mut self) -> io::Result<()> {
+		self.out.write_all(SIGNATURE)?;
+		self.out.write_u8(VERSION)?;
+		self.out.write_u8(FORMAT)?;
+		self.out.write_u8(if cfg!(target_endian = "little") { 1 } else { 0 })?;
+		self.out.write_u8(size_of::<Int>() as u8)?;
+		self.out.write_u8(size_of::<Size>() as u8)?;
+		self.out.write_u8(size_of::<Instruction>() as u8)?;
+		self.out.write_u8(size_of::<Number>() as u8)?;
+		self.out.write_u8(0)?; // lua_Number is floating-point
+		self.out.write_all(TAIL)?;
+		Ok(())
+	}
+
+	fn write_function(&mut self, function: &Function, parent_source: &str) -> io::Result<()> {
+		self.out.write_i32::<E>(function.line_start)?;
+		self.out.write_i32::<E>(function.line_end)?;
+		self.out.write_u8(function.num_params)?;
+		self.out.write_u8(if function.is_vararg { 1 } else { 0 })?;
+		self.out.write_u8(function.max_stack_size)?;
+
+		self.out.write_i32::<E>(function.code.len() as i32)?;
+		for (pc, &ins) in function.code.iter().enumerate() {
+			let op = match get_opcode(ins).and_then(opcode_number) {
+				Some(op) => op,
+				None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+					"instruction {:#010x} at pc {} has no Lua 5.2 equivalent", ins, pc,
+				))),
+			};
+			self.out.write_u32::<E>((ins & !0x3f) | op as u32)?;
+		}
+		self.out.write_i32::<E>(function.constants.len() as i32)?;
+		for (i, cons) in function.constants.iter().enumerate() {
+			match *cons {
+				Constant::Nil => self.out.write_u8(0)?,
+				Constant::Boolean(b) => self.out.write_all(&[1, if b { 1 } else { 0 }])?,
+				Constant::Float(n) => {
+					self.out.write_u8(3)?;
+					self.out.write_f64::<E>(n)?;
+				}
+				Constant::Int(n) => {
+					self.out.write_u8(3)?;
+					self.out.write_f64::<E>(n as Number)?;
+				}
+				Constant::ShortString(ref s) | Constant::LongString(ref s) => {
+					self.out.write_u8(4)?;
+					self.write_string(s)?;
+				}
+				Constant::Raw { tag, .. } => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+					"constant {} of unknown type {:#04x} has no Lua 5.2 equivalent", i, tag,
+				))),
+			}
+		}
+		// Lua 5.3 omits the source of nested functions which share their
+		// parent's, but Lua 5.2 expects it to be repeated.
+		let source = if function.source.is_empty() { parent_source } else { &function.source };
+		self.out.write_i32::<E>(function.protos.len() as i32)?;
+		for proto in &function.protos {
+			self.write_function(proto, source)?;
+		}
+		self.out.write_i32::<E>(function.upvalues.len() as i32)?;
+		for upval in &function.upvalues {
+			let (stack, idx) = upval.to_raw();
+			self.out.write_all(&[stack, idx])?;
+		}
+		// debug; an empty source is written as a null string
+		if source.is_empty() {
+			self.write_size(0)?;
+		} else {
+			self.write_string(source)?;
+		}
+		self.out.write_i32::<E>(function.debug.lineinfo.len() as i32)?;
+		for &line in &function.debug.lineinfo {
+			self.out.write_i32::<E>(line)?;
+		}
+		self.out.write_i32::<E>(function.debug.localvars.len() as i32)?;
+		for var in &function.debug.localvars {
+			self.write_string(&var.name)?;
+			self.out.write_i32::<E>(var.start_pc)?;
+			self.out.write_i32::<E>(var.end_pc)?;
+		}
+		self.out.write_i32::<E>(function.debug.upvalues.len() as i32)?;
+		for upval in &function.debug.upvalues {
+			self.write_string(upval)?;
+		}
+		Ok(())
+	}
+
+	fn write_size(&mut self, size: usize) -> io::Result<()> {
+		match size_of::<Size>() {
+			4 => self.out.write_u32::<E>(size as u32)?,
+			_ => self.out.write_u64::<E>(size as u64)?,
+		}
+		Ok(())
+	}
+
+	fn write_string(&mut self, string: &str) -> io::Result<()> {
+		// Strings are written with their terminating NUL.
+		self.write_size(string.len() + 1)?;
+		self.out.write_all(string.as_bytes())?;
+		self.out.write_u8(0)?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::mem::size_of;
+
+	use builder::FunctionBuilder;
+	use bytecode::{Opcode, encode, encode_bx};
+	use super::super::{Constant, Size};
+	use super::write_file;
+
+	fn push_int(out: &mut Vec<u8>, n: i32) {
+		out.extend_from_slice(&n.to_ne_bytes());
+	}
+
+	fn push_string(out: &mut Vec<u8>, s: &str) {
+		let size = s.len() + 1;
+		out.extend_from_slice(&size.to_ne_bytes()[..size_of::<Size>()]);
+		out.extend_from_slice(s.as_bytes());
+		out.push(0);
+	}
+
+	// There is no luac 5.2 to produce a reference chunk, so the expected
+	// bytes are assembled by hand following ldump.c from Lua 5.2.4.
+	#[test]
+	fn header_and_function() {
+		let mut builder = FunctionBuilder::main_chunk();
+		builder.constant(Constant::Int(1));
+		builder.emit(encode_bx(Opcode::LoadK, 0, 0));
+		builder.emit(encode(Opcode::Return, 0, 2, 0));
+		builder.emit(encode(Opcode::Return, 0, 1, 0));
+		let mut function = builder.build();
+		function.source = "@x.lua".into();
+		function.debug.lineinfo = vec![1, 1, 1];
+		let mut written = Vec::new();
+		write_file(&mut written, &function).unwrap();
+
+		let mut expected = b"\x1bLua\x52\x00".to_vec();
+		expected.push(cfg!(target_endian = "little") as u8);
+		expected.extend_from_slice(&[4, size_of::<Size>() as u8, 4, 8, 0]);
+		expected.extend_from_slice(b"\x19\x93\r\n\x1a\n");
+		assert_eq!(written[..expected.len()], expected[..]);
+
+		// linedefined, lastlinedefined, numparams, is_vararg, maxstacksize
+		push_int(&mut expected, 0);
+		push_int(&mut expected, 0);
+		expected.extend_from_slice(&[0, 1, 2]);
+		// code: LOADK 0 0, RETURN 0 2, RETURN 0 1, with 5.2's numbering
+		push_int(&mut expected, 3);
+		push_int(&mut expected, 0x0000_0001);
+		push_int(&mut expected, 0x0100_001f);
+		push_int(&mut expected, 0x0080_001f);
+		// constants: the integer 1 as a number, then no protos
+		push_int(&mut expected, 1);
+		expected.push(3);
+		expected.extend_from_slice(&1f64.to_ne_bytes());
+		push_int(&mut expected, 0);
+		// upvalues: _ENV in the caller's register 0
+		push_int(&mut expected, 1);
+		expected.extend_from_slice(&[1, 0]);
+		// debug: source, lineinfo, localvars, upvalue names
+		push_string(&mut expected, "@x.lua");
+		push_int(&mut expected, 3);
+		for _ in 0..3 {
+			push_int(&mut expected, 1);
+		}
+		push_int(&mut expected, 0);
+		push_int(&mut expected, 1);
+		push_string(&mut expected, "_ENV");
+		assert_eq!(written, expected);
+	}
+
+	#[test]
+	fn reject_int_div() {
+		let mut builder = FunctionBuilder::new();
+		builder.emit(encode(Opcode::IntDiv, 0, 0, 1));
+		let err = write_file(Vec::new(), &builder.build()).unwrap_err();
+		assert!(err.to_string().contains("no Lua 5.2 equivalent"), "{}", err);
+	}
+}
@@ -0,0 +1,116 @@
+//! Pieces of the Lua 5.4 bytecode format.
+//!
+//! Lua 5.4 chunks cannot be read yet. These convert its representations
+//! to and from this crate's, so that a reader for it can produce the same
+//! `Function` shape as for Lua 5.3.
+
+use std::io;
+
+use super::Int;
+
+/// The marker in 5.4's relative line info for an instruction whose line is
+/// found in the absolute line info instead.
+pub const ABSLINEINFO: i8 = -0x80;
+/// The most instructions 5.4 writes between absolute line info entries.
+pub const MAXIWTHABS: usize = 128;
+
+/// An entry of 5.4's absolute line info, giving the line of one
+/// instruction exactly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AbsLineInfo {
+	/// The pc of the instruction.
+	pub pc: Int,
+	/// Its line number.
+	pub line: Int,
+}
+
+/// Reconstruct the line of every instruction from 5.4's compressed form,
+/// as `Debug::lineinfo` holds them.
+///
+/// Each entry of `lineinfo` is the difference from the previous
+/// instruction's line, starting from `line_defined`, or `ABSLINEINFO` if
+/// the line is given by the next entry of `abslineinfo`, which must then
+/// have that instruction's pc.
+pub fn decode_lineinfo(line_defined: Int, lineinfo: &[i8], abslineinfo: &[AbsLineInfo]) -> io::Result<Vec<Int>> {
+	let mut abs = abslineinfo.iter();
+	let mut line = line_defined;
+	let mut lines = Vec::with_capacity(lineinfo.len());
+	for (pc, &delta) in lineinfo.iter().enumerate() {
+		if delta == ABSLINEINFO {
+			match abs.next() {
+				Some(entry) if entry.pc as usize == pc => line = entry.line,
+				_ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+					"no absolute line info for pc {}", pc,
+				))),
+			}
+		} else {
+			line = line.wrapping_add(delta as Int);
+		}
+		lines.push(line);
+	}
+	Ok(lines)
+}
+
+/// Compress the line of every instruction into 5.4's form, returning the
+/// relative and absolute line info.
+///
+/// As in Lua, an absolute entry is used where the line changes by 128 or
+/// more, or after `MAXIWTHABS` instructions without one.
+pub fn encode_lineinfo(line_defined: Int, lines: &[Int]) -> (Vec<i8>, Vec<AbsLineInfo>) {
+	let mut lineinfo = Vec::with_capacity(lines.len());
+	let mut abslineinfo = Vec::new();
+	let mut previous = line_defined;
+	let mut since_abs = 0;
+	for (pc, &line) in lines.iter().enumerate() {
+		let delta = line.wrapping_sub(previous);
+		if delta.unsigned_abs() >= 0x80 || since_abs >= MAXIWTHABS {
+			lineinfo.push(ABSLINEINFO);
+			abslineinfo.push(AbsLineInfo { pc: pc as Int, line });
+			// Lua counts the absolute entry itself.
+			since_abs = 1;
+		} else {
+			lineinfo.push(delta as i8);
+			since_abs += 1;
+		}
+		previous = line;
+	}
+	(lineinfo, abslineinfo)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{ABSLINEINFO, AbsLineInfo, decode_lineinfo, encode_lineinfo};
+
+	#[test]
+	fn large_delta() {
+		let lines = [10, 11, 11 + 0x7f, 11 + 0x7f + 0x80, 12, 10];
+		let (lineinfo, abslineinfo) = encode_lineinfo(10, &lines);
+		assert_eq!(lineinfo, [0, 1, 0x7f, ABSLINEINFO, ABSLINEINFO, -2]);
+		assert_eq!(abslineinfo, [
+			AbsLineInfo { pc: 3, line: 11 + 0x7f + 0x80 },
+			AbsLineInfo { pc: 4, line: 12 },
+		]);
+		assert_eq!(decode_lineinfo(10, &lineinfo, &abslineinfo).unwrap(), lines);
+	}
+
+	#[test]
+	fn long_run() {
+		let lines = vec![5; 300];
+		let (lineinfo, abslineinfo) = encode_lineinfo(5, &lines);
+		let pcs: Vec<_> = abslineinfo.iter().map(|entry| entry.pc).collect();
+		assert_eq!(pcs, [128, 256]);
+		assert!(abslineinfo.iter().all(|entry| entry.line == 5));
+		assert_eq!(lineinfo.iter().filter(|&&delta| delta == ABSLINEINFO).count(), 2);
+		assert_eq!(decode_lineinfo(5, &lineinfo, &abslineinfo).unwrap(), lines);
+	}
+
+	#[test]
+	fn missing_abs_entry() {
+		let lineinfo = [1, ABSLINEINFO, ABSLINEINFO];
+		assert!(decode_lineinfo(1, &lineinfo, &[]).is_err());
+		// An entry for the wrong pc is also rejected.
+		assert!(decode_lineinfo(1, &lineinfo, &[AbsLineInfo { pc: 2, line: 4 }]).is_err());
+		let entries = [AbsLineInfo { pc: 1, line: 4 }];
+		assert!(decode_lineinfo(1, &lineinfo, &entries).is_err());
+	}
+}
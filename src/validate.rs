@@ -0,0 +1,207 @@
+//! Structural validation.
+
+use std::io;
+
+use super::{Constant, Function, Upvalue};
+use super::bytecode::{Opcode, extra_arg_value, get_opcode, get_c, get_bx};
+
+impl Function {
+	/// Check that this function and its nested functions are well-formed.
+	///
+	/// Verifies invariants which the bytecode format requires but which the
+	/// structs do not enforce, such as `debug.lineinfo` being either empty
+	/// or exactly as long as `code`, and `LoadKX` and `SetList` with `C == 0`
+	/// being followed by an `ExtraArg`.
+	///
+	/// The constants loaded by `LoadK` and `LoadKX` must exist and be of a
+	/// type Lua 5.3 can load, not `Constant::Raw`. The functions
+	/// instantiated by `Closure` must exist, and their upvalues must refer
+	/// to registers within the enclosing function's `max_stack_size` or to
+	/// its upvalues.
+	///
+	/// Also checks that `line_start <= line_end`.
+	pub fn validate(&self) -> io::Result<()> {
+		self.validate_with_options(&ValidateOptions::default())
+	}
+
+	/// Check that this function and its nested functions are well-formed,
+	/// as by `validate`, with optional stricter checks.
+	pub fn validate_with_options(&self, options: &ValidateOptions) -> io::Result<()> {
+		Validator { path: vec![], options: options.clone() }.function(self)
+	}
+}
+
+/// Options enabling stricter checks in `Function::validate_with_options`.
+///
+/// These find mistakes in line number tracking during code generation, but
+/// code compiled by Lua does not always pass them.
+#[derive(Clone, Debug, Default)]
+pub struct ValidateOptions {
+	/// Check that every line in `debug.lineinfo` is within the function's
+	/// `line_start` and `line_end`.
+	///
+	/// Functions with both set to zero, as Lua does for main chunks, are
+	/// not checked, since they may span the whole source.
+	pub line_ranges: bool,
+	/// Check that the lines in `debug.lineinfo` never decrease.
+	///
+	/// Lua attributes the backward jump of a loop to the line it started
+	/// on, and optimized code may be reordered, so such code fails this.
+	pub monotonic_lines: bool,
+}
+
+struct Validator {
+	path: Vec<usize>,
+	options: ValidateOptions,
+}
+
+impl Validator {
+	fn fail<T, S: AsRef<str>>(&self, msg: S) -> io::Result<T> {
+		let mut location = String::from("main");
+		for idx in &self.path {
+			location.push_str(&format!(".{}", idx));
+		}
+		Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+			"in function {}: {}", location, msg.as_ref(),
+		)))
+	}
+
+	fn function(&mut self, function: &Function) -> io::Result<()> {
+		if function.line_start > function.line_end {
+			return self.fail(format!(
+				"line_start {} is after line_end {}",
+				function.line_start, function.line_end,
+			));
+		}
+		let lineinfo = &function.debug.lineinfo;
+		let whole_source = function.line_start == 0 && function.line_end == 0;
+		if self.options.line_ranges && !whole_source {
+			let range = function.line_start..=function.line_end;
+			if let Some((pc, line)) = lineinfo.iter().enumerate().find(|&(_, line)| !range.contains(line)) {
+				return self.fail(format!(
+					"line {} at pc {} is outside lines {} to {}",
+					line, pc, function.line_start, function.line_end,
+				));
+			}
+		}
+		if self.options.monotonic_lines {
+			if let Some(pc) = (1..lineinfo.len()).find(|&pc| lineinfo[pc] < lineinfo[pc - 1]) {
+				return self.fail(format!(
+					"line {} at pc {} is before line {} at pc {}",
+					lineinfo[pc], pc, lineinfo[pc - 1], pc - 1,
+				));
+			}
+		}
+
+		let lines = lineinfo.len();
+		if lines != 0 && lines != function.code.len() {
+			return self.fail(format!(
+				"lineinfo has {} entries but code has {} instructions",
+				lines, function.code.len(),
+			));
+		}
+
+		for (pc, &instr) in function.code.iter().enumerate() {
+			let op = match get_opcode(instr) {
+				Some(op) => op,
+				None => continue,
+			};
+			let needs_extra_arg = op == Opcode::LoadKX || (op == Opcode::SetList && get_c(instr) == 0);
+			if needs_extra_arg && extra_arg_value(&function.code, pc).is_none() {
+				return self.fail(format!("{:?} at pc {} is not followed by ExtraArg", op, pc));
+			}
+
+			let constant = match op {
+				Opcode::LoadK => Some(get_bx(instr)),
+				Opcode::LoadKX => extra_arg_value(&function.code, pc),
+				_ => None,
+			};
+			if let Some(index) = constant {
+				match function.constants.get(index as usize) {
+					None => return self.fail(format!(
+						"{:?} at pc {} loads constant {} but there are only {}",
+						op, pc, index, function.constants.len(),
+					)),
+					Some(&Constant::Raw { tag, .. }) => return self.fail(format!(
+						"{:?} at pc {} loads constant {} of unknown type {:#04x}",
+						op, pc, index, tag,
+					)),
+					Some(_) => {}
+				}
+			}
+			if op == Opcode::Closure && get_bx(instr) as usize >= function.protos.len() {
+				return self.fail(format!(
+					"Closure at pc {} instantiates function {} but there are only {}",
+					pc, get_bx(instr), function.protos.len(),
+				));
+			}
+		}
+
+		for (i, proto) in function.protos.iter().enumerate() {
+			self.path.push(i);
+			for (idx, upval) in proto.upvalues.iter().enumerate() {
+				match *upval {
+					Upvalue::Outer(outer) if outer as usize >= function.upvalues.len() => return self.fail(format!(
+						"upvalue {} refers to upvalue {} of the enclosing function, which has only {}",
+						idx, outer, function.upvalues.len(),
+					)),
+					Upvalue::Stack(reg) if reg >= function.max_stack_size => return self.fail(format!(
+						"upvalue {} refers to R{} of the enclosing function, which has only {} registers",
+						idx, reg, function.max_stack_size,
+					)),
+					_ => {}
+				}
+			}
+			self.function(proto)?;
+			self.path.pop();
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use builder::FunctionBuilder;
+	use bytecode::{Opcode, encode, encode_bx};
+	use super::super::{Constant, Function, Upvalue};
+
+	fn check(function: &Function, expected: &str) {
+		let err = function.validate().unwrap_err();
+		assert!(err.to_string().contains(expected), "{}", err);
+	}
+
+	#[test]
+	fn loaded_constants() {
+		let mut builder = FunctionBuilder::new();
+		builder.constant(Constant::Int(1));
+		builder.constant(Constant::Raw { tag: 0x25, bytes: vec![] });
+		builder.emit(encode_bx(Opcode::LoadK, 0, 0));
+		builder.emit(encode(Opcode::Return, 0, 1, 0));
+		let mut function = builder.build();
+		function.validate().unwrap();
+
+		function.code[0] = encode_bx(Opcode::LoadK, 0, 1);
+		check(&function, "LoadK at pc 0 loads constant 1 of unknown type 0x25");
+		function.code[0] = encode_bx(Opcode::LoadK, 0, 2);
+		check(&function, "LoadK at pc 0 loads constant 2 but there are only 2");
+	}
+
+	#[test]
+	fn closures() {
+		let mut child = FunctionBuilder::new();
+		child.upvalue(Upvalue::Stack(1), "x");
+		let mut builder = FunctionBuilder::new();
+		builder.emit(encode_bx(Opcode::Closure, 0, 0));
+		builder.emit(encode(Opcode::Return, 0, 1, 0));
+		builder.function_mut().protos.push(child.build());
+		let mut function = builder.build();
+		function.validate().unwrap();
+
+		function.protos[0].upvalues[0] = Upvalue::Stack(2);
+		check(&function, "upvalue 0 refers to R2 of the enclosing function, which has only 2 registers");
+		function.protos[0].upvalues[0] = Upvalue::Outer(0);
+		check(&function, "upvalue 0 refers to upvalue 0 of the enclosing function, which has only 0");
+		function.protos.clear();
+		check(&function, "Closure at pc 0 instantiates function 0 but there are only 0");
+	}
+}
@@ -1,115 +1,332 @@
 //! Serialization code.
 
+use std::error::Error;
+use std::fmt;
 use std::io::{self, Write};
-use std::mem::size_of;
-use byteorder::WriteBytesExt;
-use byteorder::NativeEndian as E;
+use std::marker::PhantomData;
+use byteorder::{ByteOrder, WriteBytesExt, BigEndian, LittleEndian};
 
+use super::layout::{Layout, DEFAULT_LAYOUT};
+use super::string_len::V53;
 use super::{
 	SIGNATURE, FORMAT, VERSION, DATA, TEST_INT, TEST_NUMBER,
-	Int, Size, Instruction, Integer, Number,
-	Constant, Upvalue, Function,
+	Int, Constant, Function,
 };
 
 /// Serialize a `Function` to bytecode.
 pub fn write_file<W: Write>(write: W, function: &Function) -> io::Result<()> {
-	let mut writer = Writer { out: write };
-	try!(writer.write_header());
-	try!(writer.out.write_u8(function.upvalues.len() as u8));
+	write_file_with_options(write, function, &WriteOptions::default())
+}
+
+/// Serialize a `Function` to bytecode with non-default options.
+pub fn write_file_with_options<W: Write>(write: W, function: &Function, options: &WriteOptions) -> io::Result<()> {
+	if options.layout.big_endian {
+		write_with_order::<BigEndian, _>(write, function, options)
+	} else {
+		write_with_order::<LittleEndian, _>(write, function, options)
+	}
+}
+
+/// Serialize a `Function` to little-endian bytecode, regardless of the
+/// host's byte order.
+///
+/// The header's test integer and number are written in little-endian order,
+/// so the result loads only on a little-endian Lua.
+pub fn write_file_le<W: Write>(write: W, function: &Function) -> io::Result<()> {
+	let options = WriteOptions { layout: Layout { big_endian: false, ..DEFAULT_LAYOUT }, ..WriteOptions::default() };
+	write_file_with_options(write, function, &options)
+}
+
+/// Serialize a `Function` to big-endian bytecode, regardless of the host's
+/// byte order.
+///
+/// The header's test integer and number are written in big-endian order,
+/// so the result loads only on a big-endian Lua.
+pub fn write_file_be<W: Write>(write: W, function: &Function) -> io::Result<()> {
+	let options = WriteOptions { layout: Layout { big_endian: true, ..DEFAULT_LAYOUT }, ..WriteOptions::default() };
+	write_file_with_options(write, function, &options)
+}
+
+fn write_with_order<B: ByteOrder, W: Write>(write: W, function: &Function, options: &WriteOptions) -> io::Result<()> {
+	options.layout.check()?;
+	// The header stores the main function's upvalue count in one byte.
+	if function.upvalues.len() > 0xff {
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+			"main function has {} upvalues, but at most 255 can be written",
+			function.upvalues.len(),
+		)));
+	}
+	let mut writer = Writer::<W, B> { out: write, options: options.clone(), order: PhantomData };
+	writer.write_header()?;
+	writer.out.write_u8(function.upvalues.len() as u8)?;
 	writer.write_function(function)
 }
 
-struct Writer<W: Write> {
+/// Options controlling how bytecode is serialized.
+#[derive(Clone, Debug, Default)]
+pub struct WriteOptions {
+	/// Make debug information consistent with the code as it is written.
+	///
+	/// Non-empty line info is padded with its last line, or zero, or
+	/// truncated to the length of the code, and non-empty upvalue names are
+	/// padded with empty names or truncated to the number of upvalues.
+	/// Empty debug information is valid and left empty.
+	pub fix_debug: bool,
+	/// The sizes and byte order to write with, by default the host's.
+	///
+	/// `Layout::lua53_64` gives the same output on every host.
+	pub layout: Layout,
+}
+
+/// Serialize a `Function` to bytecode in memory.
+pub fn to_bytes(function: &Function) -> io::Result<Vec<u8>> {
+	let mut buffer = Vec::new();
+	write_file(&mut buffer, function)?;
+	Ok(buffer)
+}
+
+/// Serialize a `Function` to bytecode, preceded by its length in bytes as a
+/// little-endian `u64`.
+///
+/// Several framed functions may be written back to back and read with
+/// `read_framed`.
+pub fn write_framed<W: Write>(mut write: W, function: &Function) -> io::Result<()> {
+	let buffer = to_bytes(function)?;
+	write.write_u64::<LittleEndian>(buffer.len() as u64)?;
+	write.write_all(&buffer)
+}
+
+/// Serialize a `Function` to bytecode, followed by arbitrary trailing data.
+///
+/// Lua stops reading after the main function, so the trailer is ignored
+/// when the chunk is loaded. It can be recovered with
+/// `read_file_with_trailer`.
+pub fn write_file_with_trailer<W: Write>(mut write: W, function: &Function, trailer: &[u8]) -> io::Result<()> {
+	write_file(&mut write, function)?;
+	write.write_all(trailer)
+}
+
+/// Serialize a `Function` into a fixed-size buffer, returning the number of
+/// bytes used.
+///
+/// Fails with `ErrorKind::WriteZero` if the buffer is too small, in which
+/// case its contents are unspecified.
+pub fn write_to_slice(buf: &mut [u8], function: &Function) -> io::Result<usize> {
+	let capacity = buf.len();
+	let mut rest = buf;
+	write_file(&mut rest, function)?;
+	Ok(capacity - rest.len())
+}
+
+/// Serialize a `Function` to bytecode, failing if it would take more than
+/// `max_bytes`.
+///
+/// Useful as a safety valve when writing functions produced by automated
+/// transformations. Fails with an `io::Error` of kind `Other` wrapping
+/// `OutputTooLarge` without writing the output past the limit, though
+/// what came before it has already been written.
+pub fn limited_write_file<W: Write>(write: W, function: &Function, max_bytes: u64) -> io::Result<()> {
+	write_file(Limited { inner: write, written: 0, limit: max_bytes }, function)
+}
+
+/// The error for output which would exceed the limit passed to
+/// `limited_write_file`, which it holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputTooLarge(pub u64);
+
+impl fmt::Display for OutputTooLarge {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "output too large: exceeds the limit of {} bytes", self.0)
+	}
+}
+
+impl Error for OutputTooLarge {}
+
+/// The error for a function with more of something than the bytecode
+/// format can count.
+///
+/// Writing fails with an `io::Error` of kind `InvalidInput` wrapping this,
+/// rather than writing a truncated count.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TooManyItems {
+	/// What there are too many of, such as `"instructions"`.
+	pub kind: &'static str,
+	/// How many there are.
+	pub len: usize,
+}
+
+impl fmt::Display for TooManyItems {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "too many {}: {} exceeds the limit of {}", self.kind, self.len, u32::MAX)
+	}
+}
+
+impl Error for TooManyItems {}
+
+/// A writer which counts its output and refuses to exceed a limit.
+struct Limited<W: Write> {
+	inner: W,
+	written: u64,
+	limit: u64,
+}
+
+impl<W: Write> Write for Limited<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if self.written + buf.len() as u64 > self.limit {
+			return Err(io::Error::other(OutputTooLarge(self.limit)));
+		}
+		let n = self.inner.write(buf)?;
+		self.written += n as u64;
+		Ok(n)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+struct Writer<W: Write, B: ByteOrder> {
 	out: W,
+	options: WriteOptions,
+	order: PhantomData<B>,
 }
 
-impl<W: Write> Writer<W> {
+impl<W: Write, B: ByteOrder> Writer<W, B> {
 	fn write_header(&mut self) -> io::Result<()> {
-		try!(self.out.write_all(SIGNATURE));
-		try!(self.out.write_u8(VERSION));
-		try!(self.out.write_u8(FORMAT));
-		try!(self.out.write_all(DATA));
-		try!(self.out.write_u8(size_of::<Int>() as u8));
-		try!(self.out.write_u8(size_of::<Size>() as u8));
-		try!(self.out.write_u8(size_of::<Instruction>() as u8));
-		try!(self.out.write_u8(size_of::<Integer>() as u8));
-		try!(self.out.write_u8(size_of::<Number>() as u8));
-		try!(self.out.write_i64::<E>(TEST_INT));
-		try!(self.out.write_f64::<E>(TEST_NUMBER));
+		self.out.write_all(SIGNATURE)?;
+		self.out.write_u8(VERSION)?;
+		self.out.write_u8(FORMAT)?;
+		self.out.write_all(DATA)?;
+		let layout = self.options.layout;
+		self.out.write_u8(layout.int_size)?;
+		self.out.write_u8(layout.size_t_size)?;
+		self.out.write_u8(layout.instruction_size)?;
+		self.out.write_u8(layout.integer_size)?;
+		self.out.write_u8(layout.number_size)?;
+		self.out.write_i64::<B>(TEST_INT)?;
+		self.out.write_f64::<B>(TEST_NUMBER)?;
 		Ok(())
 	}
 
 	fn write_function(&mut self, function: &Function) -> io::Result<()> {
-		try!(self.write_string(&function.source));
-		try!(self.out.write_i32::<E>(function.line_start));
-		try!(self.out.write_i32::<E>(function.line_end));
-		try!(self.out.write_u8(function.num_params));
-		try!(self.out.write_u8(if function.is_vararg { 1 } else { 0 }));
-		try!(self.out.write_u8(function.max_stack_size));
-
-		try!(self.out.write_u32::<E>(function.code.len() as u32));
+		// Like luac, write an empty source as a null string, which is how
+		// nested functions inherit their parent's.
+		if function.source.is_empty() {
+			V53::write_len_sized::<B, _>(&mut self.out, None, self.options.layout.size_t_size)?;
+		} else {
+			self.write_string(&function.source)?;
+		}
+		self.write_int(function.line_start)?;
+		self.write_int(function.line_end)?;
+		self.out.write_u8(function.num_params)?;
+		self.out.write_u8(if function.is_vararg { 1 } else { 0 })?;
+		self.out.write_u8(function.max_stack_size)?;
+
+		self.write_count("instructions", function.code.len())?;
 		for &ins in &function.code {
-			try!(self.out.write_u32::<E>(ins));
+			self.out.write_u32::<B>(ins)?;
 		}
-		try!(self.out.write_u32::<E>(function.constants.len() as u32));
+		self.write_count("constants", function.constants.len())?;
 		for cons in &function.constants {
-			match cons {
-				&Constant::Nil => try!(self.out.write_u8(0x00)),
-				&Constant::Boolean(b) => try!(self.out.write_all(&[0x01, if b { 1 } else { 0 }])),
-				&Constant::Float(n) => {
-					try!(self.out.write_u8(0x03));
-					try!(self.out.write_f64::<E>(n));
+			match *cons {
+				Constant::Nil => self.out.write_u8(0x00)?,
+				Constant::Boolean(b) => self.out.write_all(&[0x01, if b { 1 } else { 0 }])?,
+				Constant::Float(n) => {
+					self.out.write_u8(0x03)?;
+					self.out.write_f64::<B>(n)?;
+				}
+				Constant::Int(n) => {
+					self.out.write_u8(0x13)?;
+					self.out.write_i64::<B>(n)?;
 				}
-				&Constant::Int(n) => {
-					try!(self.out.write_u8(0x13));
-					try!(self.out.write_i64::<E>(n));
+				Constant::ShortString(ref s) => {
+					self.out.write_u8(0x04)?;
+					self.write_string(s)?;
 				}
-				&Constant::ShortString(ref s) => {
-					try!(self.out.write_u8(0x04));
-					try!(self.write_string(s));
+				Constant::LongString(ref s) => {
+					self.out.write_u8(0x14)?;
+					self.write_string(s)?;
 				}
-				&Constant::LongString(ref s) => {
-					try!(self.out.write_u8(0x14));
-					try!(self.write_string(s));
+				Constant::Raw { tag, ref bytes } => {
+					self.out.write_u8(tag)?;
+					self.out.write_all(bytes)?;
 				}
 			}
 		}
-		try!(self.out.write_u32::<E>(function.upvalues.len() as u32));
+		self.write_count("upvalues", function.upvalues.len())?;
 		for upval in &function.upvalues {
-			try!(match upval {
-				&Upvalue::Outer(idx) => self.out.write_all(&[0, idx]),
-				&Upvalue::Stack(idx) => self.out.write_all(&[1, idx]),
-			});
+			let (stack, idx) = upval.to_raw();
+			self.out.write_all(&[stack, idx])?;
 		}
-		try!(self.out.write_u32::<E>(function.protos.len() as u32));
+		self.write_count("nested functions", function.protos.len())?;
 		for proto in &function.protos {
-			try!(self.write_function(proto));
+			self.write_function(proto)?;
 		}
 		// debug
-		try!(self.out.write_u32::<E>(function.debug.lineinfo.len() as u32));
-		for &line in &function.debug.lineinfo {
-			try!(self.out.write_i32::<E>(line));
+		let lineinfo = &function.debug.lineinfo;
+		let lines = if self.options.fix_debug && !lineinfo.is_empty() { function.code.len() } else { lineinfo.len() };
+		let last = lineinfo.last().cloned().unwrap_or(0);
+		self.write_count("line info entries", lines)?;
+		for pc in 0..lines {
+			self.write_int(lineinfo.get(pc).cloned().unwrap_or(last))?;
 		}
-		try!(self.out.write_u32::<E>(function.debug.localvars.len() as u32));
+		self.write_count("local variables", function.debug.localvars.len())?;
 		for var in &function.debug.localvars {
-			try!(self.write_string(&var.name));
-			try!(self.out.write_i32::<E>(var.start_pc));
-			try!(self.out.write_i32::<E>(var.end_pc));
+			self.write_string(&var.name)?;
+			self.write_int(var.start_pc)?;
+			self.write_int(var.end_pc)?;
 		}
-		try!(self.out.write_u32::<E>(function.debug.upvalues.len() as u32));
-		for upval in &function.debug.upvalues {
-			try!(self.write_string(upval));
+		let names = &function.debug.upvalues;
+		let upvalues = if self.options.fix_debug && !names.is_empty() { function.upvalues.len() } else { names.len() };
+		self.write_count("upvalue names", upvalues)?;
+		for idx in 0..upvalues {
+			self.write_string(names.get(idx).map_or("", |name| &name[..]))?;
 		}
 		Ok(())
 	}
 
-	fn write_string(&mut self, string: &str) -> io::Result<()> {
-		if string.len() >= 0xff {
-			try!(self.out.write_u8(0xff));
-			try!(self.out.write_u32::<E>(string.len() as u32 + 1));
-		} else {
-			try!(self.out.write_u8(string.len() as u8 + 1));
+	fn write_count(&mut self, kind: &'static str, len: usize) -> io::Result<()> {
+		if len > u32::MAX as usize {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, TooManyItems { kind, len }));
+		}
+		match self.options.layout.int_size {
+			4 => self.out.write_u32::<B>(len as u32)?,
+			_ => self.out.write_u64::<B>(len as u64)?,
+		}
+		Ok(())
+	}
+
+	/// Write a C `int` of the layout's size.
+	fn write_int(&mut self, value: Int) -> io::Result<()> {
+		match self.options.layout.int_size {
+			4 => self.out.write_i32::<B>(value)?,
+			_ => self.out.write_i64::<B>(value as i64)?,
 		}
+		Ok(())
+	}
+
+	fn write_string(&mut self, string: &str) -> io::Result<()> {
+		V53::write_len_sized::<B, _>(&mut self.out, Some(string.len()), self.options.layout.size_t_size)?;
 		self.out.write_all(string.as_bytes())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use builder::FunctionBuilder;
+	use bytecode::{Opcode, encode};
+	use super::super::read_file;
+	use super::{to_bytes, write_file};
+
+	#[test]
+	fn to_bytes_matches_write_file() {
+		let mut builder = FunctionBuilder::main_chunk();
+		builder.emit(encode(Opcode::Return, 0, 1, 0));
+		let function = builder.build();
+		let bytes = to_bytes(&function).unwrap();
+		let mut written = Vec::new();
+		write_file(&mut written, &function).unwrap();
+		assert_eq!(bytes, written);
+		assert_eq!(read_file(&bytes[..]).unwrap(), function);
+	}
+}